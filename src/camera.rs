@@ -1,8 +1,28 @@
-use rand;
-
 use crate::linear;
 use crate::scene;
 use crate::ray;
+use crate::spectrum;
+
+// How rays leave the camera. Orthographic cameras look straight along look
+// from a view of the given width in scene units, for elevations and plans,
+// and ignore the lens.
+pub enum Projection {
+    Perspective,
+    Orthographic(f64),
+}
+
+// Image plane of a shot, relative to the camera: the top left corner, the
+// steps between pixels, and the width of the plane.
+struct Screen {
+    lookv: linear::Vec3<f64>,
+    handle: linear::Vec3<f64>,
+    corner: linear::Vec3<f64>,
+    colinc: linear::Vec3<f64>,
+    rowinc: linear::Vec3<f64>,
+    plane: f64,
+    cone: f64,
+    spread: f64,
+}
 
 pub struct Camera<'a> {
     pub pos: linear::Vec3<f64>,
@@ -12,6 +32,9 @@ pub struct Camera<'a> {
     pub fov: f64,
     pub scene: scene::Scene<'a>,
     pub lens_rad: f64,
+    pub projection: Projection,
+    pub spectral: bool,
+    pub bg: linear::Vec3<f64>,
     pub film: Vec<linear::Vec3<f64>>
 }
 
@@ -19,7 +42,7 @@ impl<'a> Camera<'a> {
     pub fn look_at(&mut self, vec: &linear::Vec3<f64>) {
         let lookv = &self.look - &self.pos;
         let vecv = vec - &self.pos;
-        let mut theta = (&lookv.normalize() * &vecv.normalize()).acos();
+        let theta = (&lookv.normalize() * &vecv.normalize()).acos();
         let axis = lookv ^ vecv;
         self.up = self.up.rotate(&axis, theta).normalize();
         self.look = vec.copy();
@@ -27,18 +50,25 @@ impl<'a> Camera<'a> {
 
     pub fn translate(&mut self, vec: &linear::Vec3<f64>) {
         let lookv = &self.pos - &self.look;
-        let vecv = &(&self.pos + &vec) - &self.look;
-        let mut theta = (&lookv.normalize() * &vecv.normalize()).acos();
+        let vecv = &(&self.pos + vec) - &self.look;
+        let theta = (&lookv.normalize() * &vecv.normalize()).acos();
         let axis = lookv ^ vecv;
         self.up = self.up.rotate(&axis, theta).normalize();
-        self.pos = &self.pos + &vec;
+        self.pos = &self.pos + vec;
     }
 
-    pub fn shoot(&mut self, width: u32, height: u32, samples: u16) {
-        self.ratio = (width as f64) / (height as f64);
+    // Angle between the rays of neighbouring pixels across an image of the
+    // given width.
+    pub fn spread(&self, width: u32) -> f64 {
+        2.0 * (self.fov / 4.0).tan() / width as f64
+    }
+
+    // Image plane at the look point for an image of the given size.
+    fn screen(&self, width: u32, height: u32) -> Screen {
+        let ratio = (width as f64) / (height as f64);
         let lookv = &self.look - &self.pos;
         let focal_len = lookv.norm();
-        let vfov = self.fov / self.ratio;
+        let vfov = self.fov / ratio;
         let handle = (&self.up ^ &lookv).normalize();
         let right = lookv.rotate(&self.up, self.fov / 4.0).normalize() * focal_len * (self.fov / 4.0).cos().recip();
         let left = lookv.rotate(&self.up, -self.fov / 4.0).normalize() * focal_len * (self.fov / 4.0).cos().recip();
@@ -46,39 +76,66 @@ impl<'a> Camera<'a> {
         let top = lookv.rotate(&handle, -vfov / 4.0).normalize() * focal_len * (vfov / 4.0).cos().recip();
         let colinc = &(&right - &left) / width as f64;
         let rowinc = &(&bot - &top) / height as f64;
+        // Rays stand for a pixel's worth of angle each, or of view when
+        // parallel.
+        let (cone, spread) = match self.projection {
+            Projection::Perspective => (0.0, self.spread(width)),
+            Projection::Orthographic(view) => (view / width as f64, 0.0),
+        };
+        let plane = (&right - &left).norm();
+        let corner = &left - &(&rowinc * (height / 2) as f64);
+        Screen {lookv, handle, corner, colinc, rowinc, plane, cone, spread}
+    }
+
+    // Ray through the point of the screen at fractional column x and row y.
+    fn primary(&self, screen: &Screen, x: f64, y: f64) -> ray::Ray {
+        let mut aim = &screen.corner + &(&screen.rowinc * y + &screen.colinc * x);
+        let mut origin = self.pos.copy();
+        if let Projection::Orthographic(view) = self.projection {
+            // The point on the image plane across the look direction, scaled
+            // from the plane's width to the view's.
+            let across = &aim - &(&screen.lookv * ((&aim * &screen.lookv) / (&screen.lookv * &screen.lookv)));
+            origin = &origin + &(across * (view / screen.plane));
+            aim = screen.lookv.copy();
+        } else if self.lens_rad > 0.0 {
+            let mut rannum1: f64 = rand::random();
+            let mut rannum2: f64 = rand::random();
+            rannum1 = (rannum1 - 0.5) * 2.0 * self.lens_rad;
+            rannum2 = (rannum2 - 0.5) * 2.0 * self.lens_rad;
+            origin = origin + (&self.up * rannum1) + (&screen.handle * rannum2);
+            aim = &(&aim + &self.pos) - &origin;
+        }
+        ray::Ray{origin, traj: aim.normalize(), lambda: None, pdf: 0.0, media: Vec::new(), width: screen.cone, spread: screen.spread}
+    }
+
+    pub fn shoot(&mut self, width: u32, height: u32, samples: u16) {
+        self.scene.prepare();
+        self.ratio = (width as f64) / (height as f64);
+        let screen = self.screen(width, height);
         let scale = (samples as f64).recip();
-        let mut cur: linear::Vec3<f64>;
         let mut last_per = 0.0;
-        let mut new_per = 0.0;
 
         for i in 0..height {
-            cur = &left + &(&rowinc * ((i as i32) - (height / 2) as i32) as f64);
-            let bal = i as f64 / height as f64;
-            for _j in 0..width {
+            for j in 0..width {
                 let mut fcolor = linear::Vec3::new();
                 for _k in 0..samples {
                     let rannum1: f64 = rand::random();
                     let rannum2: f64 = rand::random();
-                    let mut aim = &cur + &(&rowinc * rannum1 + &colinc * rannum2);
-                    let mut origin = self.pos.copy();
-                    if self.lens_rad > 0.0 {
-                        let mut rannum1: f64 = rand::random();
-                        let mut rannum2: f64 = rand::random();
-                        rannum1 = (rannum1 - 0.5) * 2.0 * self.lens_rad;
-                        rannum2 = (rannum2 - 0.5) * 2.0 * self.lens_rad;
-                        origin = origin + (&self.up * rannum1) + (&handle * rannum2);
-                        aim = &(&aim + &self.pos) - &origin;
+                    let mut ray = self.primary(&screen, j as f64 + rannum2, i as f64 + rannum1);
+                    if self.spectral {
+                        ray.lambda = Some(spectrum::Wavelengths::sample(rand::random()));
                     }
-                    let mut ray = ray::Ray{origin: origin.copy(), traj: aim.normalize()};
 
-                    fcolor = &fcolor + &ray.trace(&self.scene, &scene::SKY_DARK, 15);
+                    let radiance = ray.trace(&self.scene, &self.bg, 15);
+                    fcolor = &fcolor + &match &ray.lambda {
+                        Some(wl) => spectrum::to_rgb(&radiance, wl),
+                        None => radiance,
+                    };
                 }
 
                 self.film.push(&fcolor * scale);
-                cur = &cur + &colinc;
             }
-            print!("\n");
-            new_per = i as f64 / height as f64 * 100.0;
+            let new_per = i as f64 / height as f64 * 100.0;
             if new_per - last_per >= 5.0 {
                 eprintln!("Thread #{} is {}% done.", std::thread::current().name().unwrap(), new_per.round());
                 last_per = new_per;
@@ -87,3 +144,30 @@ impl<'a> Camera<'a> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera<'a>(look: linear::Vec3<f64>, lens_rad: f64) -> Camera<'a> {
+        Camera {pos: linear::Vec3 {x: 1.0, y: 2.0, z: 3.0}, look, up: linear::Y, ratio: 0.0, fov: std::f64::consts::PI * 0.5,
+                scene: scene::Scene::new(), lens_rad, projection: Projection::Orthographic(4.0), spectral: false, bg: scene::BLACK, film: Vec::new()}
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel_from_the_image_plane() {
+        let near = camera(linear::Vec3 {x: 1.0, y: 2.0, z: 1.0}, 0.0);
+        let far = camera(linear::Vec3 {x: 1.0, y: 2.0, z: -7.0}, 0.5);
+        let (a, b) = (near.screen(40, 20), far.screen(40, 20));
+        for (x, y) in [(0.0, 0.0), (40.0, 20.0), (13.5, 2.25)] {
+            let (r, s) = (near.primary(&a, x, y), far.primary(&b, x, y));
+            assert!((&r.traj - &linear::Vec3 {x: 0.0, y: 0.0, z: -1.0}).norm() < 1e-12);
+            assert!((&s.traj - &r.traj).norm() < 1e-12);
+            // Off the camera position only across the view, and the same
+            // whatever the aperture and focus distance.
+            assert!(((&r.origin - &near.pos).z).abs() < 1e-12);
+            assert!((&s.origin - &r.origin).norm() < 1e-12);
+        }
+        let corner = near.primary(&a, 0.0, 10.0).origin;
+        assert!(((&corner - &near.pos).norm() - 2.0).abs() < 1e-9);
+    }
+}
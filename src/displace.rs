@@ -0,0 +1,350 @@
+use std::cell::OnceCell;
+
+use crate::linear;
+use crate::material;
+use crate::ray;
+use crate::scene;
+use crate::scene::SceneObject;
+use crate::texture;
+
+// Deepest bisection of a mesh triangle, bounding the triangles made from it.
+const MAX_DEPTH: u32 = 20;
+// Most triangles in a leaf of the tree over the displaced triangles.
+const LEAF_SIZE: usize = 4;
+
+// Triangle mesh over shared vertices, counter-clockwise seen from the front,
+// so that displaced neighbours stay joined.
+pub struct Mesh {
+    pub positions: Vec<linear::Vec3<f64>>,
+    pub uvs: Vec<(f64, f64)>,
+    pub triangles: Vec<[usize; 3]>,
+}
+
+// Edge length tessellation stops at: in scene units, or in pixels as seen
+// from a camera at eye with rays spread radians apart.
+pub enum EdgeLength {
+    World(f64),
+    Screen {eye: linear::Vec3<f64>, spread: f64, pixels: f64},
+}
+
+// A mesh tessellated until its edges are short enough and moved along its
+// normals by a height texture times scale, for terrain and sculpted detail.
+// Heights are clamped between 0 and 1, so the bounds of the mesh padded by
+// the scale hold the displaced surface before it is tessellated. That
+// happens at the first ray reaching the bounds.
+pub struct Displaced<'a> {
+    mesh: Mesh,
+    height: &'a dyn texture::Texture,
+    scale: f64,
+    edge: EdgeLength,
+    mat: &'a dyn material::Material,
+    min: linear::Vec3<f64>,
+    max: linear::Vec3<f64>,
+    tessellation: OnceCell<Tessellation<'a>>,
+}
+
+struct Vertex {
+    pos: linear::Vec3<f64>,
+    norm: linear::Vec3<f64>,
+    uv: (f64, f64),
+}
+
+struct Node {
+    min: linear::Vec3<f64>,
+    max: linear::Vec3<f64>,
+    children: Option<(usize, usize)>,
+    // Range of triangles under the node.
+    first: usize,
+    count: usize,
+}
+
+// Displaced triangles and a bounding volume tree over them, root first.
+struct Tessellation<'a> {
+    triangles: Vec<scene::Triangle<'a>>,
+    nodes: Vec<Node>,
+}
+
+fn axis(v: &linear::Vec3<f64>, i: usize) -> f64 {
+    match i {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+fn lower(a: &linear::Vec3<f64>, b: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+    linear::Vec3 {x: a.x.min(b.x), y: a.y.min(b.y), z: a.z.min(b.z)}
+}
+
+fn upper(a: &linear::Vec3<f64>, b: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+    linear::Vec3 {x: a.x.max(b.x), y: a.y.max(b.y), z: a.z.max(b.z)}
+}
+
+// Distances along the ray to where it enters and leaves the box, if it does
+// before far.
+fn slab(r: &ray::Ray, min: &linear::Vec3<f64>, max: &linear::Vec3<f64>, far: f64) -> Option<f64> {
+    let (mut near, mut far) = (0.0_f64, far);
+    for i in 0..3 {
+        let o = axis(&r.origin, i);
+        // Parallel to the slab, where its planes would give 0 * inf.
+        if axis(&r.traj, i) == 0.0 {
+            if o < axis(min, i) || o > axis(max, i) {
+                return None;
+            }
+            continue;
+        }
+        let inv = axis(&r.traj, i).recip();
+        let t0 = (axis(min, i) - o) * inv;
+        let t1 = (axis(max, i) - o) * inv;
+        near = near.max(t0.min(t1));
+        far = far.min(t0.max(t1));
+    }
+    if near <= far { Some(near) } else { None }
+}
+
+impl Mesh {
+    // Grid of cells by cells squares spanning the two edges from a corner,
+    // facing along u ^ v, with texture coordinates running over it once.
+    pub fn grid(corner: &linear::Vec3<f64>, u: &linear::Vec3<f64>, v: &linear::Vec3<f64>, cells: usize) -> Self {
+        let n = cells.max(1);
+        let mut mesh = Mesh {positions: Vec::new(), uvs: Vec::new(), triangles: Vec::new()};
+        for j in 0..=n {
+            for i in 0..=n {
+                let (s, t) = (i as f64 / n as f64, j as f64 / n as f64);
+                mesh.positions.push(corner + &(&(u * s) + &(v * t)));
+                mesh.uvs.push((s, t));
+            }
+        }
+        for j in 0..n {
+            for i in 0..n {
+                let a = j * (n + 1) + i;
+                mesh.triangles.push([a, a + 1, a + n + 2]);
+                mesh.triangles.push([a, a + n + 2, a + n + 1]);
+            }
+        }
+        mesh
+    }
+
+    // Vertex normals averaged over the faces around each vertex, weighted by
+    // area.
+    fn normals(&self) -> Vec<linear::Vec3<f64>> {
+        let mut normals = vec![linear::Vec3::new(); self.positions.len()];
+        for [a, b, c] in &self.triangles {
+            let p = &self.positions;
+            let face = (&p[*b] - &p[*a]) ^ (&p[*c] - &p[*a]);
+            for i in [a, b, c] {
+                normals[*i] = &normals[*i] + &face;
+            }
+        }
+        normals.iter().map(|n| if n.norm() > 0.0 { n.normalize() } else { n.copy() }).collect()
+    }
+}
+
+impl Vertex {
+    fn midpoint(&self, b: &Vertex) -> Vertex {
+        let norm = &self.norm + &b.norm;
+        Vertex {
+            pos: (&self.pos + &b.pos) * 0.5,
+            norm: if norm.norm() > 0.0 { norm.normalize() } else { norm },
+            uv: ((self.uv.0 + b.uv.0) * 0.5, (self.uv.1 + b.uv.1) * 0.5),
+        }
+    }
+}
+
+impl<'a> Displaced<'a> {
+    pub fn new(mesh: Mesh, height: &'a dyn texture::Texture, scale: f64, edge: EdgeLength, mat: &'a dyn material::Material) -> Self {
+        let pad = linear::Vec3 {x: scale.abs(), y: scale.abs(), z: scale.abs()};
+        let mut min = linear::Vec3 {x: f64::INFINITY, y: f64::INFINITY, z: f64::INFINITY};
+        let mut max = -&min;
+        for p in &mesh.positions {
+            min = lower(&min, p);
+            max = upper(&max, p);
+        }
+        Displaced {mesh, height, scale, edge, mat, min: &min - &pad, max: &max + &pad, tessellation: OnceCell::new()}
+    }
+
+    // Length of the edge over the length tessellation stops at.
+    fn measure(&self, a: &linear::Vec3<f64>, b: &linear::Vec3<f64>) -> f64 {
+        let len = (a - b).norm();
+        match &self.edge {
+            EdgeLength::World(target) => len / target,
+            EdgeLength::Screen {eye, spread, pixels} => {
+                let dist = (&(&(a + b) * 0.5) - eye).norm().max(1e-6);
+                len / (dist * spread * pixels)
+            },
+        }
+    }
+
+    // Bisects the longest edge over the target length until none is left.
+    // The middle of an edge is the same from either side and only depends on
+    // that edge, so neighbouring triangles split shared edges alike and no
+    // cracks open between them.
+    fn split(&self, tri: [Vertex; 3], depth: u32, out: &mut Vec<[Vertex; 3]>) {
+        let lengths: Vec<f64> = (0..3).map(|i| self.measure(&tri[i].pos, &tri[(i + 1) % 3].pos)).collect();
+        let i = (0..3).max_by(|a, b| lengths[*a].total_cmp(&lengths[*b])).unwrap_or(0);
+        if lengths[i] <= 1.0 || depth >= MAX_DEPTH {
+            out.push(tri);
+            return;
+        }
+        let [a, b, c] = tri;
+        let (a, b, c) = match i {
+            0 => (a, b, c),
+            1 => (b, c, a),
+            _ => (c, a, b),
+        };
+        let m = a.midpoint(&b);
+        let m2 = Vertex {pos: m.pos.copy(), norm: m.norm.copy(), uv: m.uv};
+        let c2 = Vertex {pos: c.pos.copy(), norm: c.norm.copy(), uv: c.uv};
+        self.split([a, m, c], depth + 1, out);
+        self.split([m2, b, c2], depth + 1, out);
+    }
+
+    fn displace(&self, v: &Vertex) -> linear::Vec3<f64> {
+        let inter = scene::Intersection {t: 0.0, pos: v.pos.copy(), norm: v.norm.copy(), geom: v.norm.copy(), mat: self.mat, uv: v.uv,
+                                         footprint: 0.0, tangent: linear::Vec3::new(), bitangent: linear::Vec3::new()};
+        &v.pos + &(&v.norm * (self.height.scalar(&inter).clamp(0.0, 1.0) * self.scale))
+    }
+
+    fn tessellate(&self) -> Tessellation<'a> {
+        let normals = self.mesh.normals();
+        let vertex = |i: usize| Vertex {pos: self.mesh.positions[i].copy(), norm: normals[i].copy(), uv: self.mesh.uvs[i]};
+        let mut pieces = Vec::new();
+        for [a, b, c] in &self.mesh.triangles {
+            self.split([vertex(*a), vertex(*b), vertex(*c)], 0, &mut pieces);
+        }
+        let triangles = pieces.iter().map(|[a, b, c]| scene::Triangle {
+            a: self.displace(a), b: self.displace(b), c: self.displace(c), uv: [a.uv, b.uv, c.uv], mat: self.mat,
+        }).collect();
+        let mut tess = Tessellation {triangles, nodes: Vec::new()};
+        let count = tess.triangles.len();
+        tess.build(0, count);
+        tess
+    }
+}
+
+impl<'a> Tessellation<'a> {
+    fn bounds(&self, first: usize, count: usize) -> (linear::Vec3<f64>, linear::Vec3<f64>) {
+        let mut min = linear::Vec3 {x: f64::INFINITY, y: f64::INFINITY, z: f64::INFINITY};
+        let mut max = -&min;
+        for t in &self.triangles[first..first + count] {
+            for p in [&t.a, &t.b, &t.c] {
+                min = lower(&min, p);
+                max = upper(&max, p);
+            }
+        }
+        (min, max)
+    }
+
+    // Builds the node over a range of triangles, splitting at the median
+    // along the longest axis, and returns its index.
+    fn build(&mut self, first: usize, count: usize) -> usize {
+        let (min, max) = self.bounds(first, count);
+        let index = self.nodes.len();
+        self.nodes.push(Node {min: min.copy(), max: max.copy(), children: None, first, count});
+        if count <= LEAF_SIZE {
+            return index;
+        }
+        let extent = &max - &min;
+        let longest = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let centroid = |t: &scene::Triangle| axis(&t.a, longest) + axis(&t.b, longest) + axis(&t.c, longest);
+        self.triangles[first..first + count].sort_by(|a, b| centroid(a).total_cmp(&centroid(b)));
+        let half = count / 2;
+        let left = self.build(first, half);
+        let right = self.build(first + half, count - half);
+        self.nodes[index].children = Some((left, right));
+        index
+    }
+}
+
+impl<'a> SceneObject for Displaced<'a> {
+    // Closest displaced triangle facing the ray, or any if the material is
+    // two sided, as the scene culls separate triangles.
+    fn intersect(&self, r: &ray::Ray) -> scene::Intersection<'_> {
+        let miss = scene::Intersection {t: -1.0, pos: linear::Vec3::new(), norm: linear::Vec3::new(), geom: linear::Vec3::new(), mat: self.mat,
+                                        uv: (0.0, 0.0), footprint: 0.0, tangent: linear::Vec3::new(), bitangent: linear::Vec3::new()};
+        if slab(r, &self.min, &self.max, scene::T_MAX).is_none() {
+            return miss;
+        }
+        let tess = self.tessellation.get_or_init(|| self.tessellate());
+        let mut best = miss;
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            let node = &tess.nodes[i];
+            let far = if best.t >= scene::T_MIN { best.t } else { scene::T_MAX };
+            if slab(r, &node.min, &node.max, far).is_none() {
+                continue;
+            }
+            match node.children {
+                Some((left, right)) => stack.extend([left, right]),
+                None => {
+                    for tri in &tess.triangles[node.first..node.first + node.count] {
+                        let inter = tri.intersect(r);
+                        if inter.t >= scene::T_MIN && (best.t < scene::T_MIN || inter.t < best.t)
+                            && (&r.traj * &inter.geom <= 0.0 || self.mat.two_sided()) {
+                            best = inter;
+                        }
+                    }
+                },
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Height rising from 0 to 1 across u.
+    struct Ramp;
+
+    impl texture::Texture for Ramp {
+        fn value(&self, inter: &scene::Intersection) -> linear::Vec3<f64> {
+            linear::Vec3 {x: inter.uv.0, y: inter.uv.0, z: inter.uv.0}
+        }
+    }
+
+    fn square(edge: EdgeLength) -> Displaced<'static> {
+        let mesh = Mesh::grid(&linear::Vec3::new(), &linear::Vec3 {x: 4.0, y: 0.0, z: 0.0}, &linear::Vec3 {x: 0.0, y: 4.0, z: 0.0}, 2);
+        Displaced::new(mesh, &Ramp, 0.5, edge, &material::WHITE_TESTING)
+    }
+
+    #[test]
+    fn bisection_stops_at_the_edge_length() {
+        let displaced = square(EdgeLength::World(0.3));
+        let tess = displaced.tessellate();
+        let mut area = 0.0;
+        for t in &tess.triangles {
+            // Flattened back onto the grid, edges are within the target and
+            // the pieces cover it exactly.
+            let flat: Vec<linear::Vec3<f64>> = [&t.a, &t.b, &t.c].iter().map(|p| linear::Vec3 {x: p.x, y: p.y, z: 0.0}).collect();
+            for i in 0..3 {
+                assert!((&flat[i] - &flat[(i + 1) % 3]).norm() <= 0.3 + 1e-9);
+            }
+            area += ((&flat[1] - &flat[0]) ^ (&flat[2] - &flat[0])).norm() * 0.5;
+            // Each corner is raised by the height at its u.
+            for (p, uv) in [&t.a, &t.b, &t.c].iter().zip(t.uv) {
+                assert!((p.z - 0.5 * uv.0).abs() < 1e-9);
+                assert!((p.x - 4.0 * uv.0).abs() < 1e-9);
+            }
+        }
+        assert!((area - 16.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn distant_triangles_split_less_on_screen() {
+        let near = square(EdgeLength::Screen {eye: linear::Vec3 {x: 2.0, y: 2.0, z: 2.0}, spread: 0.01, pixels: 8.0});
+        let far = square(EdgeLength::Screen {eye: linear::Vec3 {x: 2.0, y: 2.0, z: 20.0}, spread: 0.01, pixels: 8.0});
+        assert!(near.tessellate().triangles.len() > far.tessellate().triangles.len() * 4);
+    }
+
+    // Straight down onto a vertex, along the planes between tree nodes.
+    #[test]
+    fn rays_hit_the_displaced_surface() {
+        let displaced = square(EdgeLength::World(0.5));
+        let r = ray::Ray {origin: linear::Vec3 {x: 3.0, y: 1.0, z: 5.0}, traj: linear::Vec3 {x: 0.0, y: 0.0, z: -1.0}, lambda: None, pdf: 0.0,
+                          media: Vec::new(), width: 0.0, spread: 0.0};
+        let inter = displaced.intersect(&r);
+        assert!((inter.pos.z - 0.5 * 0.75).abs() < 1e-9);
+    }
+}
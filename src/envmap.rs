@@ -0,0 +1,155 @@
+use std::f64::consts::PI;
+use std::io;
+
+use crate::image;
+use crate::linear;
+use crate::ray;
+use crate::sampling;
+use crate::scene;
+use crate::spectrum;
+
+// Image based lighting from an equirectangular (lat-long) map surrounding
+// the scene. The top row of the image is straight up (+y), and rotation
+// turns the map about the y axis (radians). Cube maps are resampled to
+// lat-long on load.
+pub struct Environment {
+    pub image: image::Image,
+    pub rotation: f64,
+    pub intensity: f64,
+    distribution: sampling::Distribution2D,
+}
+
+impl Environment {
+    // Errors on an image with no pixels, which has nothing to sample.
+    pub fn new(image: image::Image, rotation: f64, intensity: f64) -> io::Result<Self> {
+        if image.width == 0 || image.height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty image"));
+        }
+        let (w, h) = (image.width, image.height);
+        let mut values = Vec::with_capacity(w * h);
+        for y in 0..h {
+            let sin = (PI * (y as f64 + 0.5) / h as f64).sin();
+            for x in 0..w {
+                values.push(spectrum::luminance(image.get(x, y)) * sin);
+            }
+        }
+        let distribution = sampling::Distribution2D::new(&values, w, h);
+        Ok(Environment {image, rotation, intensity, distribution})
+    }
+
+    pub fn load(path: &str, rotation: f64, intensity: f64) -> io::Result<Self> {
+        Environment::new(image::Image::load(path)?, rotation, intensity)
+    }
+
+    // Six faces in the order +x, -x, +y, -y, +z, -z, using the usual cube map
+    // orientation, resampled to a lat-long map of four faces' width. The faces
+    // must all be the same size.
+    pub fn from_cube(faces: &[image::Image; 6], rotation: f64, intensity: f64) -> io::Result<Self> {
+        if faces[0].width == 0 || faces[0].height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty image"));
+        }
+        if faces.iter().any(|f| f.width != faces[0].width || f.height != faces[0].height) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "cube faces differ in size"));
+        }
+        let size = faces[0].width;
+        let (w, h) = (4 * size, 2 * size);
+        let mut pixels = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                let dir = Environment::direction((x as f64 + 0.5) / w as f64, (y as f64 + 0.5) / h as f64);
+                let (ax, ay, az) = (dir.x.abs(), dir.y.abs(), dir.z.abs());
+                let (face, sc, tc, ma) = if ax >= ay && ax >= az {
+                    if dir.x > 0.0 { (0, -dir.z, -dir.y, ax) } else { (1, dir.z, -dir.y, ax) }
+                } else if ay >= az {
+                    if dir.y > 0.0 { (2, dir.x, dir.z, ay) } else { (3, dir.x, -dir.z, ay) }
+                } else if dir.z > 0.0 {
+                    (4, dir.x, -dir.y, az)
+                } else {
+                    (5, -dir.x, -dir.y, az)
+                };
+                let img = &faces[face];
+                let s = ((sc / ma + 1.0) * 0.5 * img.width as f64) as usize;
+                let t = ((tc / ma + 1.0) * 0.5 * img.height as f64) as usize;
+                pixels.push(img.get(s.min(img.width - 1), t.min(img.height - 1)).copy());
+            }
+        }
+        Environment::new(image::Image {width: w, height: h, pixels}, rotation, intensity)
+    }
+
+    pub fn load_cube(paths: [&str; 6], rotation: f64, intensity: f64) -> io::Result<Self> {
+        let faces = [
+            image::Image::load(paths[0])?, image::Image::load(paths[1])?, image::Image::load(paths[2])?,
+            image::Image::load(paths[3])?, image::Image::load(paths[4])?, image::Image::load(paths[5])?,
+        ];
+        Environment::from_cube(&faces, rotation, intensity)
+    }
+
+    // Map direction for lat-long coordinates, before rotation.
+    fn direction(u: f64, v: f64) -> linear::Vec3<f64> {
+        let theta = v * PI;
+        let phi = u * 2.0 * PI;
+        linear::Vec3 {x: theta.sin() * phi.cos(), y: theta.cos(), z: theta.sin() * phi.sin()}
+    }
+
+    fn coords(&self, dir: &linear::Vec3<f64>) -> (f64, f64) {
+        let d = dir.rotate(&linear::Y, -self.rotation * 0.5);
+        let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+        let u = d.z.atan2(d.x) / (2.0 * PI);
+        (u.rem_euclid(1.0), v)
+    }
+
+    pub fn lookup(&self, dir: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let (u, v) = self.coords(dir);
+        self.image.bilinear(u, v) * self.intensity
+    }
+}
+
+impl scene::LightSource for Environment {
+    fn sample(&self, from: &linear::Vec3<f64>, lambda: &Option<spectrum::Wavelengths>) -> Option<scene::LightSample> {
+        let ((u, v), pdf_uv) = self.distribution.sample(rand::random(), rand::random());
+        let sin = (v * PI).sin();
+        if pdf_uv <= 0.0 || sin <= 0.0 {
+            return None;
+        }
+        let dir = Environment::direction(u, v).rotate(&linear::Y, self.rotation * 0.5);
+        let ray = ray::Ray {origin: from.copy(), traj: dir, lambda: lambda.as_ref().map(|wl| wl.copy()), pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0};
+        let radiance = self.background(&ray);
+        Some(scene::LightSample {ray, dist: scene::T_MAX, pdf: pdf_uv / (2.0 * PI * PI * sin), radiance, delta: false})
+    }
+
+    fn pdf(&self, _from: &linear::Vec3<f64>, dir: &linear::Vec3<f64>) -> f64 {
+        let (u, v) = self.coords(dir);
+        let sin = (v * PI).sin();
+        if sin <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin)
+    }
+
+    fn background(&self, ray: &ray::Ray) -> linear::Vec3<f64> {
+        ray.illuminant(&self.lookup(&ray.traj))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat(width: usize, height: usize) -> image::Image {
+        image::Image {width, height, pixels: vec![linear::Vec3 {x: 1.0, y: 1.0, z: 1.0}; width * height]}
+    }
+
+    #[test]
+    fn empty_and_mismatched_images_are_rejected() {
+        assert!(Environment::new(flat(0, 0), 0.0, 1.0).is_err());
+        assert!(Environment::new(flat(4, 0), 0.0, 1.0).is_err());
+        assert!(Environment::new(flat(4, 2), 0.0, 1.0).is_ok());
+
+        let cube = |last: usize| [flat(2, 2), flat(2, 2), flat(2, 2), flat(2, 2), flat(2, 2), flat(last, last)];
+        let env = Environment::from_cube(&cube(2), 0.0, 1.0).unwrap();
+        assert_eq!((env.image.width, env.image.height), (8, 4));
+        assert!(Environment::from_cube(&cube(3), 0.0, 1.0).is_err());
+        let empty = [flat(0, 0), flat(0, 0), flat(0, 0), flat(0, 0), flat(0, 0), flat(0, 0)];
+        assert!(Environment::from_cube(&empty, 0.0, 1.0).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+
+use crate::linear;
+
+// Goniometric intensity distribution of a luminaire, read from an IES LM-63
+// photometric file. Only type C photometry (the usual for building fixtures)
+// is supported. Candela values are kept relative to their peak, so a light
+// using the profile keeps its own intensity as the peak intensity.
+pub struct Profile {
+    // Vertical angles from the photometric axis and horizontal angles around
+    // it, in degrees and increasing.
+    vertical: Vec<f64>,
+    horizontal: Vec<f64>,
+    // Relative intensity for each horizontal angle, then each vertical one.
+    candela: Vec<Vec<f64>>,
+    // Intensity averaged over all directions, relative to the peak.
+    pub average: f64,
+}
+
+// A profile hung on a light. Vertical angle 0 points along the light's axis,
+// the downward nadir for a ceiling fixture, and horizontal angle 0 is the
+// direction of zero projected perpendicular to that axis.
+pub struct Fixture<'a> {
+    pub profile: &'a Profile,
+    pub zero: linear::Vec3<f64>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Index of the interval holding x in a sorted list, and the fraction along it.
+fn locate(angles: &[f64], x: f64) -> (usize, f64) {
+    if angles.len() == 1 || x <= angles[0] {
+        return (0, 0.0);
+    }
+    let i = angles.partition_point(|a| *a <= x).min(angles.len() - 1) - 1;
+    let span = angles[i + 1] - angles[i];
+    let f = if span > 0.0 { (x - angles[i]) / span } else { 0.0 };
+    (i, f.clamp(0.0, 1.0))
+}
+
+impl Profile {
+    pub fn load(path: &str) -> io::Result<Self> {
+        Profile::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut lines = text.lines();
+        // Keywords and comments run up to the TILT line.
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => break line.trim_start()[5..].trim().to_string(),
+                Some(_) => continue,
+                None => return Err(invalid("missing TILT line")),
+            }
+        };
+        let rest: Vec<&str> = lines.collect();
+        let mut values = rest.iter()
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<f64>().map_err(|_| invalid("bad number")));
+        let mut next = || values.next().unwrap_or_else(|| Err(invalid("unexpected end of file")));
+
+        // Lamp tilt only matters for lamps mounted off their design position.
+        if tilt == "INCLUDE" {
+            next()?;
+            let pairs = next()? as usize;
+            for _ in 0..2 * pairs {
+                next()?;
+            }
+        }
+
+        let _lamps = next()?;
+        let _lumens = next()?;
+        let multiplier = next()?;
+        let nv = next()? as usize;
+        let nh = next()? as usize;
+        let kind = next()? as i32;
+        for _ in 0..4 {
+            next()?; // units, width, length, height
+        }
+        let ballast = next()?;
+        let _future = next()?;
+        let _watts = next()?;
+        if kind != 1 {
+            return Err(invalid("only type C photometry is supported"));
+        }
+        if nv == 0 || nh == 0 {
+            return Err(invalid("empty candela table"));
+        }
+
+        let vertical = (0..nv).map(|_| next()).collect::<io::Result<Vec<f64>>>()?;
+        let horizontal = (0..nh).map(|_| next()).collect::<io::Result<Vec<f64>>>()?;
+        let mut candela = Vec::with_capacity(nh);
+        for _ in 0..nh {
+            candela.push((0..nv).map(|_| next().map(|c| c * multiplier * ballast)).collect::<io::Result<Vec<f64>>>()?);
+        }
+
+        let peak = candela.iter().flatten().fold(0.0_f64, |m, c| m.max(*c));
+        if peak <= 0.0 {
+            return Err(invalid("profile emits no light"));
+        }
+        for row in candela.iter_mut() {
+            for c in row.iter_mut() {
+                *c /= peak;
+            }
+        }
+
+        let mut profile = Profile {vertical, horizontal, candela, average: 0.0};
+        // Average over the sphere by midpoint rule in cos(theta) and phi.
+        let (n_theta, n_phi) = (90, 180);
+        let mut sum = 0.0;
+        for i in 0..n_theta {
+            let theta = (1.0 - 2.0 * (i as f64 + 0.5) / n_theta as f64).acos().to_degrees();
+            for j in 0..n_phi {
+                sum += profile.eval(theta, 360.0 * (j as f64 + 0.5) / n_phi as f64);
+            }
+        }
+        profile.average = sum / (n_theta * n_phi) as f64;
+        Ok(profile)
+    }
+
+    // Folds a horizontal angle into the range the file covers, using the
+    // symmetry its horizontal angles imply.
+    fn fold(&self, phi: f64) -> f64 {
+        let first = self.horizontal[0];
+        let last = self.horizontal[self.horizontal.len() - 1];
+        let mut phi = phi.rem_euclid(360.0);
+        if last - first <= 90.0 {
+            // Same in every quadrant.
+            if phi > 180.0 { phi = 360.0 - phi; }
+            if phi > 90.0 { phi = 180.0 - phi; }
+        } else if last - first <= 180.0 {
+            // Mirrored about the plane through first and last.
+            if first >= 90.0 {
+                if phi < first || phi > last { phi = (180.0 - phi).rem_euclid(360.0); }
+            } else if phi > 180.0 {
+                phi = 360.0 - phi;
+            }
+        }
+        phi
+    }
+
+    // Relative intensity at angles in degrees, zero outside the vertical range.
+    pub fn eval(&self, theta: f64, phi: f64) -> f64 {
+        let (v0, v1) = (self.vertical[0], self.vertical[self.vertical.len() - 1]);
+        if theta < v0 - 1e-6 || theta > v1 + 1e-6 {
+            return 0.0;
+        }
+        let (vi, vf) = locate(&self.vertical, theta);
+        let row = |h: usize| {
+            let c = &self.candela[h];
+            if vi + 1 < c.len() { c[vi] * (1.0 - vf) + c[vi + 1] * vf } else { c[vi] }
+        };
+        let nh = self.horizontal.len();
+        if nh == 1 {
+            return row(0);
+        }
+        let mut phi = self.fold(phi);
+        let last = self.horizontal[nh - 1];
+        if phi < self.horizontal[0] {
+            phi += 360.0;
+        }
+        if phi > last {
+            // Full circle data not repeating 360: wrap back to the first angle.
+            let span = 360.0 + self.horizontal[0] - last;
+            let f = if span > 0.0 { (phi - last) / span } else { 0.0 };
+            return row(nh - 1) * (1.0 - f) + row(0) * f;
+        }
+        let (hi, hf) = locate(&self.horizontal, phi);
+        if hi + 1 < nh { row(hi) * (1.0 - hf) + row(hi + 1) * hf } else { row(hi) }
+    }
+}
+
+impl<'a> Fixture<'a> {
+    // Frame about a light's axis, with any other s when zero lies along it.
+    fn frame(&self, axis: &linear::Vec3<f64>) -> linear::Frame {
+        let n = axis.normalize();
+        let s = &self.zero - &(&n * (&self.zero * &n));
+        if s.norm() < 1e-9 {
+            return linear::Frame::new(&n);
+        }
+        let s = s.normalize();
+        let t = &n ^ &s;
+        linear::Frame {s, t, n}
+    }
+
+    // Relative intensity leaving a light with this axis in a world direction.
+    pub fn intensity(&self, axis: &linear::Vec3<f64>, dir: &linear::Vec3<f64>) -> f64 {
+        let local = self.frame(axis).to_local(dir);
+        let theta = local.z.clamp(-1.0, 1.0).acos().to_degrees();
+        let phi = local.y.atan2(local.x).to_degrees();
+        self.profile.eval(theta, phi)
+    }
+
+    // Power of a light of the given peak intensity, in the same units.
+    pub fn power(&self, peak: f64) -> f64 {
+        4.0 * PI * peak * self.profile.average
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Type C file with vertical angles 0, 45 and 90 and a row of candela for
+    // each horizontal angle, the row for angle h being 100 + h at each.
+    fn file(tilt: &str, horizontal: &[f64]) -> String {
+        let mut text = format!("IESNA:LM-63-2002\n[TEST] synthetic\nTILT={}\n", tilt);
+        if tilt == "INCLUDE" {
+            text += "1\n3\n0 45 90\n1 0.9 0.8\n";
+        }
+        text += &format!("1 -1 1 3 {} 1 1 0 0 0\n1 1 40\n0 45 90\n", horizontal.len());
+        text += &horizontal.iter().map(|h| h.to_string()).collect::<Vec<_>>().join(" ");
+        text += "\n";
+        for h in horizontal {
+            text += &format!("{} {} {}\n", 100.0 + h, 100.0 + h, 50.0 + h);
+        }
+        text
+    }
+
+    #[test]
+    fn tilt_data_is_skipped() {
+        let none = Profile::parse(&file("NONE", &[0.0, 90.0])).unwrap();
+        let include = Profile::parse(&file("INCLUDE", &[0.0, 90.0])).unwrap();
+        assert_eq!(none.average, include.average);
+        for (theta, phi) in [(0.0, 0.0), (30.0, 45.0), (90.0, 80.0)] {
+            assert_eq!(none.eval(theta, phi), include.eval(theta, phi));
+        }
+    }
+
+    #[test]
+    fn horizontal_symmetry_follows_the_angles_given() {
+        let axial = Profile::parse(&file("NONE", &[0.0])).unwrap();
+        for phi in [0.0, 70.0, 200.0, 350.0] {
+            assert_eq!(axial.eval(60.0, phi), axial.eval(60.0, 0.0));
+        }
+
+        let quadrant = Profile::parse(&file("NONE", &[0.0, 90.0])).unwrap();
+        let q = quadrant.eval(60.0, 30.0);
+        for phi in [150.0, 210.0, 330.0] {
+            assert!((quadrant.eval(60.0, phi) - q).abs() < 1e-12, "{}", phi);
+        }
+        assert!(quadrant.eval(60.0, 60.0) > q);
+
+        let half = Profile::parse(&file("NONE", &[0.0, 45.0, 90.0, 135.0, 180.0])).unwrap();
+        assert!((half.eval(60.0, 330.0) - half.eval(60.0, 30.0)).abs() < 1e-12);
+        assert!((half.eval(60.0, 210.0) - half.eval(60.0, 150.0)).abs() < 1e-12);
+        assert!(half.eval(60.0, 150.0) > half.eval(60.0, 30.0));
+        assert_eq!(half.eval(0.0, 180.0), 1.0);
+    }
+
+    #[test]
+    fn fixtures_turn_with_the_light() {
+        let half = Profile::parse(&file("NONE", &[0.0, 45.0, 90.0, 135.0, 180.0])).unwrap();
+        let fixture = Fixture {profile: &half, zero: linear::Z};
+        let down = -&linear::Y;
+        // 60 degrees from nadir, toward zero and away from it.
+        let toward = linear::Vec3 {x: 0.0, y: -0.5, z: 0.75f64.sqrt()};
+        let away = linear::Vec3 {x: 0.0, y: -0.5, z: -(0.75f64.sqrt())};
+        assert!((fixture.intensity(&down, &toward) - half.eval(60.0, 0.0)).abs() < 1e-9);
+        assert!((fixture.intensity(&down, &away) - half.eval(60.0, 180.0)).abs() < 1e-9);
+        // Pointed along x, the nadir follows.
+        let tilted = linear::Vec3 {x: 0.5, y: 0.0, z: 0.75f64.sqrt()};
+        assert!((fixture.intensity(&linear::X, &tilted) - half.eval(60.0, 0.0)).abs() < 1e-9);
+        assert!((fixture.intensity(&linear::X, &down) - half.eval(90.0, 90.0)).abs() < 1e-9);
+        // With zero along the axis any azimuth will do, but vertical angles hold.
+        let axial = Profile::parse(&file("NONE", &[0.0])).unwrap();
+        let fixture = Fixture {profile: &axial, zero: linear::Z};
+        assert!((fixture.intensity(&linear::Z, &tilted) - axial.eval(30.0, 0.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let text = file("INCLUDE", &[0.0, 45.0, 90.0, 135.0, 180.0]);
+        for end in [0, 20, text.len() / 2, text.len() - 6] {
+            assert!(Profile::parse(&text[..end]).is_err(), "cut at {}", end);
+        }
+    }
+}
@@ -0,0 +1,572 @@
+use std::{fs, io};
+
+use crate::linear;
+
+// Floating point RGB image, stored row by row from the top left.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<linear::Vec3<f64>>,
+}
+
+fn invalid(path: &str, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, msg))
+}
+
+impl Image {
+    // Loads an image, choosing the format from the file extension. Integer
+    // formats (PNG and PPM) are taken to be sRGB encoded, as for colors.
+    pub fn load(path: &str) -> io::Result<Image> {
+        Image::read(path, true)
+    }
+
+    // Loads an image holding data rather than colors, like a roughness map,
+    // keeping integer formats as they are.
+    pub fn load_linear(path: &str) -> io::Result<Image> {
+        Image::read(path, false)
+    }
+
+    fn read(path: &str, srgb: bool) -> io::Result<Image> {
+        let data = fs::read(path)?;
+        let ext = path.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+        let mut image = match ext.as_str() {
+            "hdr" | "pic" => return Image::decode_hdr(path, &data),
+            "exr" => return Image::decode_exr(path, &data),
+            "png" => Image::decode_png(path, &data)?,
+            "ppm" | "pnm" => Image::decode_ppm(path, &data)?,
+            _ => return Err(invalid(path, "unsupported image format")),
+        };
+        if srgb {
+            let decode = |c: f64| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+            for px in image.pixels.iter_mut() {
+                *px = linear::Vec3 {x: decode(px.x), y: decode(px.y), z: decode(px.z)};
+            }
+        }
+        Ok(image)
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> &linear::Vec3<f64> {
+        &self.pixels[y * self.width + x]
+    }
+
+    // Bilinear lookup with u wrapping around and v clamped, as for a lat-long map.
+    pub fn bilinear(&self, u: f64, v: f64) -> linear::Vec3<f64> {
+        let x = u * self.width as f64 - 0.5;
+        let y = (v * self.height as f64 - 0.5).clamp(0.0, (self.height - 1) as f64);
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let (tx, ty) = (x - x0, y - y0);
+        let wrap = |x: f64| (x as i64).rem_euclid(self.width as i64) as usize;
+        let (x0, x1) = (wrap(x0), wrap(x0 + 1.0));
+        let (y0, y1) = (y0 as usize, (y0 as usize + 1).min(self.height - 1));
+        let top = self.get(x0, y0) * (1.0 - tx) + self.get(x1, y0) * tx;
+        let bot = self.get(x0, y1) * (1.0 - tx) + self.get(x1, y1) * tx;
+        top * (1.0 - ty) + bot * ty
+    }
+
+    // PNG of any color type, alpha dropped, values scaled to [0, 1].
+    fn decode_png(path: &str, data: &[u8]) -> io::Result<Image> {
+        let mut decoder = png::Decoder::new(io::Cursor::new(data));
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().map_err(|e| invalid(path, &e.to_string()))?;
+        let mut buf = vec![0; reader.output_buffer_size().ok_or_else(|| invalid(path, "image too large"))?];
+        let info = reader.next_frame(&mut buf).map_err(|e| invalid(path, &e.to_string()))?;
+        let channels = info.color_type.samples();
+        let wide = info.bit_depth == png::BitDepth::Sixteen;
+        let sample = |i: usize| if wide {
+            u16::from_be_bytes([buf[2 * i], buf[2 * i + 1]]) as f64 / 65535.0
+        } else {
+            buf[i] as f64 / 255.0
+        };
+        let (width, height) = (info.width as usize, info.height as usize);
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = y * info.line_size / if wide { 2 } else { 1 };
+            for x in 0..width {
+                let i = row + x * channels;
+                pixels.push(if channels < 3 {
+                    let v = sample(i);
+                    linear::Vec3 {x: v, y: v, z: v}
+                } else {
+                    linear::Vec3 {x: sample(i), y: sample(i + 1), z: sample(i + 2)}
+                });
+            }
+        }
+        Ok(Image {width, height, pixels})
+    }
+
+    // Portable pixmap, plain (P3) or binary (P6), values scaled to [0, 1].
+    fn decode_ppm(path: &str, data: &[u8]) -> io::Result<Image> {
+        let mut pos = 0;
+        // Header fields are separated by whitespace, with # comments.
+        let mut field = || {
+            loop {
+                while pos < data.len() && data[pos].is_ascii_whitespace() {
+                    pos += 1;
+                }
+                if pos < data.len() && data[pos] == b'#' {
+                    while pos < data.len() && data[pos] != b'\n' {
+                        pos += 1;
+                    }
+                } else {
+                    break;
+                }
+            }
+            let start = pos;
+            while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            String::from_utf8_lossy(&data[start..pos]).into_owned()
+        };
+        let magic = field();
+        if magic != "P3" && magic != "P6" {
+            return Err(invalid(path, "only P3 and P6 pixmaps are supported"));
+        }
+        let mut number = || field().parse::<usize>().map_err(|_| invalid(path, "bad header"));
+        let (width, height, max) = (number()?, number()?, number()?);
+        if max == 0 || max > 65535 {
+            return Err(invalid(path, "bad maximum value"));
+        }
+        let count = width.checked_mul(height).and_then(|n| n.checked_mul(3)).ok_or_else(|| invalid(path, "image too large"))?;
+        let samples: Vec<f64> = if magic == "P3" {
+            (0..count).map(|_| number().map(|v| v as f64 / max as f64)).collect::<io::Result<Vec<f64>>>()?
+        } else {
+            // A single whitespace byte separates the header from the data.
+            let start = pos + 1;
+            let size = if max > 255 { 2 } else { 1 };
+            let end = count.checked_mul(size).and_then(|n| n.checked_add(start)).ok_or_else(|| invalid(path, "image too large"))?;
+            let body = data.get(start..end).ok_or_else(|| invalid(path, "unexpected end of file"))?;
+            (0..count).map(|i| if size == 2 {
+                u16::from_be_bytes([body[2 * i], body[2 * i + 1]]) as f64 / max as f64
+            } else {
+                body[i] as f64 / max as f64
+            }).collect()
+        };
+        let pixels = samples.chunks(3).map(|c| linear::Vec3 {x: c[0], y: c[1], z: c[2]}).collect();
+        Ok(Image {width, height, pixels})
+    }
+
+    // Radiance RGBE, flat or with per-scanline run length encoding.
+    fn decode_hdr(path: &str, data: &[u8]) -> io::Result<Image> {
+        let mut pos = 0;
+        let mut line = || {
+            let start = pos;
+            while pos < data.len() && data[pos] != b'\n' {
+                pos += 1;
+            }
+            pos += 1;
+            String::from_utf8_lossy(&data[start..(pos - 1).min(data.len())]).into_owned()
+        };
+        if !line().starts_with("#?") {
+            return Err(invalid(path, "missing radiance header"));
+        }
+        loop {
+            let l = line();
+            if l.is_empty() {
+                break;
+            }
+            if l.starts_with("FORMAT=") && l != "FORMAT=32-bit_rle_rgbe" {
+                return Err(invalid(path, "only RGBE data is supported"));
+            }
+        }
+        let res: Vec<String> = line().split_whitespace().map(String::from).collect();
+        if res.len() != 4 || res[0] != "-Y" || res[2] != "+X" {
+            return Err(invalid(path, "unsupported image orientation"));
+        }
+        let height: usize = res[1].parse().map_err(|_| invalid(path, "bad height"))?;
+        let width: usize = res[3].parse().map_err(|_| invalid(path, "bad width"))?;
+
+        let count = width.checked_mul(height).ok_or_else(|| invalid(path, "image too large"))?;
+        let mut pixels = Vec::with_capacity(count);
+        let mut scan = vec![[0u8; 4]; width];
+        let eof = || invalid(path, "unexpected end of file");
+        for _y in 0..height {
+            let rle = (8..32768).contains(&width) && data.len() >= pos + 4
+                && data[pos] == 2 && data[pos + 1] == 2 && data[pos + 2] & 0x80 == 0;
+            if rle {
+                if ((data[pos + 2] as usize) << 8 | data[pos + 3] as usize) != width {
+                    return Err(invalid(path, "scanline width mismatch"));
+                }
+                pos += 4;
+                for c in 0..4 {
+                    let mut x = 0;
+                    while x < width {
+                        let count = *data.get(pos).ok_or_else(eof)? as usize;
+                        pos += 1;
+                        if count > 128 {
+                            let value = *data.get(pos).ok_or_else(eof)?;
+                            pos += 1;
+                            for px in scan.iter_mut().skip(x).take(count - 128) {
+                                px[c] = value;
+                            }
+                            x += count - 128;
+                        } else {
+                            if count == 0 || x + count > width || pos + count > data.len() {
+                                return Err(invalid(path, "corrupt scanline"));
+                            }
+                            for i in 0..count {
+                                scan[x + i][c] = data[pos + i];
+                            }
+                            pos += count;
+                            x += count;
+                        }
+                    }
+                }
+            } else {
+                for px in scan.iter_mut() {
+                    px.copy_from_slice(data.get(pos..pos + 4).ok_or_else(eof)?);
+                    pos += 4;
+                }
+            }
+            for px in &scan {
+                if px[3] == 0 {
+                    pixels.push(linear::Vec3::new());
+                } else {
+                    let f = 2.0_f64.powi(px[3] as i32 - 136);
+                    pixels.push(linear::Vec3 {x: (px[0] as f64 + 0.5) * f, y: (px[1] as f64 + 0.5) * f, z: (px[2] as f64 + 0.5) * f});
+                }
+            }
+        }
+        Ok(Image {width, height, pixels})
+    }
+
+    // OpenEXR, single part scanline images with half or float channels,
+    // uncompressed or with RLE, ZIPS or ZIP compression.
+    fn decode_exr(path: &str, data: &[u8]) -> io::Result<Image> {
+        let eof = || invalid(path, "unexpected end of file");
+        let u32_at = |p: usize| data.get(p..p + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).ok_or_else(eof);
+        if u32_at(0)? != 20000630 {
+            return Err(invalid(path, "not an OpenEXR file"));
+        }
+        if u32_at(4)? & 0x1e00 != 0 {
+            return Err(invalid(path, "tiled, deep and multi-part files are not supported"));
+        }
+        let mut pos = 8;
+        let cstr = |pos: &mut usize| -> io::Result<String> {
+            let start = *pos;
+            while *data.get(*pos).ok_or_else(eof)? != 0 {
+                *pos += 1;
+            }
+            *pos += 1;
+            Ok(String::from_utf8_lossy(&data[start..*pos - 1]).into_owned())
+        };
+
+        // (name, pixel type) of each channel, in file order.
+        let mut channels: Vec<(String, u32)> = Vec::new();
+        let mut compression = 0;
+        let mut window = [0i32; 4];
+        loop {
+            let name = cstr(&mut pos)?;
+            if name.is_empty() {
+                break;
+            }
+            let _kind = cstr(&mut pos)?;
+            let size = u32_at(pos)? as usize;
+            pos += 4;
+            let value = data.get(pos..pos + size).ok_or_else(eof)?;
+            match name.as_str() {
+                "channels" => {
+                    let mut p = 0;
+                    while value.get(p).is_some_and(|&c| c != 0) {
+                        let start = p;
+                        p += value[p..].iter().position(|&c| c == 0).ok_or_else(|| invalid(path, "unterminated channel list"))?;
+                        let cname = String::from_utf8_lossy(&value[start..p]).into_owned();
+                        let ptype = u32_at(pos + p + 1)?;
+                        channels.push((cname, ptype));
+                        p += 17;
+                    }
+                }
+                "compression" => compression = *value.first().ok_or_else(|| invalid(path, "empty compression attribute"))?,
+                "dataWindow" => {
+                    for (i, w) in window.iter_mut().enumerate() {
+                        *w = u32_at(pos + 4 * i)? as i32;
+                    }
+                }
+                _ => {}
+            }
+            pos += size;
+        }
+        let lines = match compression {
+            0..=2 => 1,
+            3 => 16,
+            _ => return Err(invalid(path, "unsupported compression")),
+        };
+        if window[2] < window[0] || window[3] < window[1] {
+            return Err(invalid(path, "empty data window"));
+        }
+        let width = (window[2] as i64 - window[0] as i64 + 1) as usize;
+        let height = (window[3] as i64 - window[1] as i64 + 1) as usize;
+        let chunks = height.div_ceil(lines);
+        let sizes: Vec<usize> = channels.iter().map(|c| if c.1 == 1 { 2 } else { 4 }).collect();
+        let line_bytes: usize = sizes.iter().sum::<usize>() * width;
+        let find = |n: &str| channels.iter().position(|c| c.0 == n);
+        let (r, g, b) = match (find("R"), find("G"), find("B"), find("Y")) {
+            (Some(r), Some(g), Some(b), _) => (r, g, b),
+            (_, _, _, Some(y)) => (y, y, y),
+            _ => return Err(invalid(path, "no RGB or Y channels")),
+        };
+
+        let count = width.checked_mul(height).ok_or_else(|| invalid(path, "image too large"))?;
+        let mut pixels = vec![linear::Vec3::new(); count];
+        for chunk in 0..chunks {
+            let offset = u32_at(pos + chunk * 8)? as usize;
+            let y = u32_at(offset)? as i32 as i64 - window[1] as i64;
+            if y < 0 || y >= height as i64 {
+                return Err(invalid(path, "chunk outside the data window"));
+            }
+            let size = u32_at(offset + 4)? as usize;
+            let raw = data.get(offset + 8..offset + 8 + size).ok_or_else(eof)?;
+            let rows = lines.min(height - y as usize);
+            let block = if size == line_bytes * rows {
+                raw.to_vec()
+            } else {
+                match compression {
+                    1 => exr_unpredict(&exr_unrle(raw).ok_or_else(|| invalid(path, "corrupt RLE data"))?),
+                    2 | 3 => exr_unpredict(&miniz_oxide::inflate::decompress_to_vec_zlib(raw).map_err(|_| invalid(path, "corrupt ZIP data"))?),
+                    _ => return Err(invalid(path, "chunk size mismatch")),
+                }
+            };
+            if block.len() < line_bytes * rows {
+                return Err(invalid(path, "short chunk"));
+            }
+            for row in 0..rows {
+                let mut start = row * line_bytes;
+                let mut values = [0.0; 3];
+                let mut offsets = vec![0; channels.len()];
+                for (i, size) in sizes.iter().enumerate() {
+                    offsets[i] = start;
+                    start += size * width;
+                }
+                for x in 0..width {
+                    for (k, c) in [r, g, b].iter().enumerate() {
+                        let p = offsets[*c] + x * sizes[*c];
+                        values[k] = match channels[*c].1 {
+                            1 => half_to_f64(u16::from_le_bytes([block[p], block[p + 1]])),
+                            2 => f32::from_le_bytes([block[p], block[p + 1], block[p + 2], block[p + 3]]) as f64,
+                            _ => u32::from_le_bytes([block[p], block[p + 1], block[p + 2], block[p + 3]]) as f64,
+                        };
+                    }
+                    pixels[(y as usize + row) * width + x] = linear::Vec3 {x: values[0], y: values[1], z: values[2]};
+                }
+            }
+        }
+        Ok(Image {width, height, pixels})
+    }
+}
+
+fn exr_unrle(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let count = data[pos] as i8;
+        pos += 1;
+        if count < 0 {
+            let n = (-(count as i32)) as usize;
+            out.extend_from_slice(data.get(pos..pos + n)?);
+            pos += n;
+        } else {
+            out.extend(std::iter::repeat_n(*data.get(pos)?, count as usize + 1));
+            pos += 1;
+        }
+    }
+    Some(out)
+}
+
+// Undoes the delta predictor and byte interleaving applied before compression.
+fn exr_unpredict(data: &[u8]) -> Vec<u8> {
+    let mut t = data.to_vec();
+    for i in 1..t.len() {
+        t[i] = t[i - 1].wrapping_add(t[i]).wrapping_sub(128);
+    }
+    let half = t.len().div_ceil(2);
+    let mut out = Vec::with_capacity(t.len());
+    for i in 0..half {
+        out.push(t[i]);
+        if half + i < t.len() {
+            out.push(t[half + i]);
+        }
+    }
+    out
+}
+
+fn half_to_f64(h: u16) -> f64 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = ((h >> 10) & 0x1f) as i32;
+    let mant = (h & 0x3ff) as f64;
+    sign * match exp {
+        0 => mant * 2.0_f64.powi(-24),
+        31 => if mant == 0.0 { f64::INFINITY } else { f64::NAN },
+        _ => (1.0 + mant / 1024.0) * 2.0_f64.powi(exp - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Radiance run length encoding of one component of a scanline, with a
+    // run for every repeat and literals between.
+    fn hdr_rle(values: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < values.len() {
+            let run = values[i..].iter().take(127).take_while(|&&v| v == values[i]).count();
+            if run > 1 {
+                out.extend_from_slice(&[128 + run as u8, values[i]]);
+                i += run;
+            } else {
+                out.extend_from_slice(&[1, values[i]]);
+                i += 1;
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn hdr_decodes_run_length_and_flat_scanlines() {
+        // With an exponent of 136 each mantissa m decodes to m + 0.5.
+        let rows: [[[u8; 4]; 8]; 2] = [
+            [[1, 2, 3, 136], [1, 2, 3, 136], [1, 2, 3, 136], [4, 5, 6, 136], [7, 8, 9, 136], [7, 8, 9, 136], [0, 0, 0, 0], [10, 11, 12, 136]],
+            [[20, 21, 22, 136]; 8],
+        ];
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 8\n".to_vec();
+        data.extend_from_slice(&[2, 2, 0, 8]);
+        for c in 0..4 {
+            data.extend(hdr_rle(&rows[0].map(|px| px[c])));
+        }
+        data.extend(rows[1].iter().flatten());
+
+        let img = Image::decode_hdr("test.hdr", &data).unwrap();
+        assert_eq!((img.width, img.height), (8, 2));
+        for (px, rgbe) in img.pixels.iter().zip(rows.iter().flatten()) {
+            let expect = if rgbe[3] == 0 { [0.0; 3] } else { [rgbe[0], rgbe[1], rgbe[2]].map(|m| m as f64 + 0.5) };
+            assert_eq!([px.x, px.y, px.z], expect);
+        }
+        assert!(Image::decode_hdr("test.hdr", &data[..data.len() - 5]).is_err());
+    }
+
+    // Inverse of exr_unpredict: splits even and odd bytes into halves and
+    // stores each byte as a difference from the last.
+    fn exr_predict(data: &[u8]) -> Vec<u8> {
+        let t: Vec<u8> = data.iter().step_by(2).chain(data.iter().skip(1).step_by(2)).copied().collect();
+        (0..t.len()).map(|i| if i == 0 { t[0] } else { t[i].wrapping_sub(t[i - 1]).wrapping_add(128) }).collect()
+    }
+
+    fn exr_rle(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let run = data[i..].iter().take(128).take_while(|&&v| v == data[i]).count();
+            if run > 2 {
+                out.extend_from_slice(&[(run - 1) as u8, data[i]]);
+                i += run;
+            } else {
+                out.extend_from_slice(&[-1i8 as u8, data[i]]);
+                i += 1;
+            }
+        }
+        out
+    }
+
+    // A 5 by 3 OpenEXR file with B, G and R channels of the given pixel type
+    // (1 half, 2 float), compressed as asked, and the pixels it holds.
+    fn exr(compression: u8, ptype: u32) -> (Vec<u8>, Vec<[f64; 3]>) {
+        let (width, height) = (5usize, 3usize);
+        let pixels: Vec<[f64; 3]> = (0..width * height).map(|i| [1.0, 0.5 * (i % 2) as f64, if i < 5 { 2.0 } else { 0.25 }]).collect();
+        let mut data = Vec::new();
+        data.extend_from_slice(&20000630u32.to_le_bytes());
+        data.extend_from_slice(&2u32.to_le_bytes());
+        let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+            data.extend_from_slice(format!("{}\0{}\0", name, kind).as_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(value);
+        };
+        let mut channels = Vec::new();
+        for name in ["B", "G", "R"] {
+            channels.extend_from_slice(format!("{}\0", name).as_bytes());
+            channels.extend_from_slice(&ptype.to_le_bytes());
+            channels.extend_from_slice(&[0; 4]);
+            channels.extend_from_slice(&1u32.to_le_bytes());
+            channels.extend_from_slice(&1u32.to_le_bytes());
+        }
+        channels.push(0);
+        attribute("channels", "chlist", &channels);
+        attribute("compression", "compression", &[compression]);
+        let window: Vec<u8> = [0u32, 0, width as u32 - 1, height as u32 - 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+        attribute("dataWindow", "box2i", &window);
+        data.push(0);
+
+        let lines = if compression == 3 { 16 } else { 1 };
+        let chunks = height.div_ceil(lines);
+        let table = data.len();
+        data.resize(table + 8 * chunks, 0);
+        for chunk in 0..chunks {
+            let mut block = Vec::new();
+            for y in chunk * lines..((chunk + 1) * lines).min(height) {
+                for c in [2, 1, 0] {
+                    for x in 0..width {
+                        let v = pixels[y * width + x][c];
+                        match ptype {
+                            1 => block.extend_from_slice(&match v { 2.0 => 0x4000u16, 1.0 => 0x3c00, 0.5 => 0x3800, 0.25 => 0x3400, _ => 0 }.to_le_bytes()),
+                            _ => block.extend_from_slice(&(v as f32).to_le_bytes()),
+                        }
+                    }
+                }
+            }
+            let packed = match compression {
+                0 => block.clone(),
+                1 => exr_rle(&exr_predict(&block)),
+                _ => miniz_oxide::deflate::compress_to_vec_zlib(&exr_predict(&block), 6),
+            };
+            assert!(compression == 0 || packed.len() != block.len());
+            let offset = data.len() as u64;
+            data[table + 8 * chunk..table + 8 * chunk + 8].copy_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(&((chunk * lines) as u32).to_le_bytes());
+            data.extend_from_slice(&(packed.len() as u32).to_le_bytes());
+            data.extend(packed);
+        }
+        (data, pixels)
+    }
+
+    #[test]
+    fn exr_decodes_each_compression() {
+        for (compression, ptype) in [(0, 1), (0, 2), (1, 2), (2, 2), (3, 1)] {
+            let (data, pixels) = exr(compression, ptype);
+            let img = Image::decode_exr("test.exr", &data).unwrap();
+            assert_eq!((img.width, img.height), (5, 3));
+            for (px, expect) in img.pixels.iter().zip(&pixels) {
+                assert_eq!([px.x, px.y, px.z], *expect, "compression {}", compression);
+            }
+            assert!(Image::decode_exr("test.exr", &data[..data.len() - 3]).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let big = "99999999999";
+        let ppm = format!("P6 {} {} 255\n", big, big);
+        assert!(Image::decode_ppm("test.ppm", ppm.as_bytes()).is_err());
+        let hdr = format!("#?RADIANCE\n\n-Y {} +X {}\n", big, big);
+        assert!(Image::decode_hdr("test.hdr", hdr.as_bytes()).is_err());
+
+        let (data, _) = exr(0, 2);
+        // Offset of an attribute's value size, after its name and type.
+        let size_at = |header: &str| data.windows(header.len()).position(|w| w == header.as_bytes()).unwrap() + header.len();
+        let patched = |at: usize, value: u32| {
+            let mut d = data.clone();
+            d[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            Image::decode_exr("test.exr", &d)
+        };
+        // A channel list cut before its terminator.
+        assert!(patched(size_at("channels\0chlist\0"), 1).is_err());
+        assert!(patched(size_at("compression\0compression\0"), 0).is_err());
+        // The window's right edge left of its left edge.
+        assert!(patched(size_at("dataWindow\0box2i\0") + 12, -2i32 as u32).is_err());
+        // A chunk starting below the last line of the window.
+        let table = size_at("dataWindow\0box2i\0") + 4 + 16 + 1;
+        let chunk = u32::from_le_bytes([data[table], data[table + 1], data[table + 2], data[table + 3]]) as usize;
+        assert!(patched(chunk, 3).is_err());
+        assert!(patched(chunk, -1i32 as u32).is_err());
+        assert!(patched(chunk, 2).is_ok());
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+
+use crate::linear;
+use crate::material;
+use crate::scene;
+use crate::spectrum;
+use crate::texture::{Color, Scalar};
+
+// Color of diffuse kinds that set none.
+const GREY: linear::Vec3<f64> = linear::Vec3 {x: 0.5, y: 0.5, z: 0.5};
+
+const CONDUCTOR: &[&str] = &["eta", "k", "roughness", "anisotropy"];
+
+// Kinds a material can start from, and the parameters each takes. Materials
+// wrapping others, coated, mix, sheen, bumped and cutout, are left to scenes:
+// the library owns what it builds, and they borrow what they wrap.
+const KINDS: &[(&str, &[&str])] = &[
+    ("lambert", &["color"]),
+    ("oren_nayar", &["color", "sigma", "retro", "retro_exponent"]),
+    ("specular", &["color", "roughness"]),
+    ("conductor", CONDUCTOR),
+    ("gold", CONDUCTOR),
+    ("copper", CONDUCTOR),
+    ("silver", CONDUCTOR),
+    ("aluminium", CONDUCTOR),
+    ("dielectric", &["color", "eta", "roughness", "thin", "absorption", "distance"]),
+    ("principled", &["color", "metallic", "roughness", "anisotropy", "specular", "specular_tint", "sheen", "sheen_tint",
+                     "clearcoat", "clearcoat_gloss", "transmission", "subsurface", "eta"]),
+    ("subsurface", &["color", "mfp", "eta", "roughness", "method"]),
+    ("emitter", &["color", "scale", "two_sided", "falloff"]),
+];
+
+// A material as written in the file: its parent, a kind or another material,
+// and the parameters it sets, with the lines they are on.
+struct Definition {
+    parent: String,
+    line: usize,
+    params: Vec<(String, Vec<String>, usize)>,
+}
+
+// Named materials read from a library file, looked up by scenes with get().
+// Each material starts with a line naming it and its parent, followed by a
+// line per parameter it sets, which override those of the parent:
+//
+//     # Comments run to the end of the line.
+//     material red_plastic principled
+//     color 0.5 0.1 0.1
+//     roughness 0.3
+//
+//     material polished_red red_plastic
+//     roughness 0.05
+//
+// Colors take one grey value or three. Problems do not stop the library
+// loading, they are listed by report() along with the materials no scene
+// used and the names that were never defined.
+pub struct Library {
+    materials: BTreeMap<String, Box<dyn material::Material>>,
+    defined: BTreeSet<String>,
+    inherited: BTreeSet<String>,
+    // Problems and the lines they are on.
+    problems: Vec<(usize, String)>,
+    // Names looked up, from any render thread.
+    used: Mutex<BTreeSet<String>>,
+    undefined: Mutex<BTreeSet<String>>,
+}
+
+pub struct Report {
+    pub unused: Vec<String>,
+    pub undefined: Vec<String>,
+    pub problems: Vec<String>,
+}
+
+fn kind(name: &str) -> Option<&'static [&'static str]> {
+    KINDS.iter().find(|(k, _)| *k == name).map(|(_, params)| *params)
+}
+
+fn number(params: &BTreeMap<&str, &[String]>, key: &str, default: f64) -> Result<f64, String> {
+    match params.get(key) {
+        Some([v]) => v.parse().map_err(|_| format!("{} is not a number", key)),
+        Some(_) => Err(format!("{} takes one number", key)),
+        None => Ok(default),
+    }
+}
+
+fn color(params: &BTreeMap<&str, &[String]>, key: &str, default: linear::Vec3<f64>) -> Result<linear::Vec3<f64>, String> {
+    let values = match params.get(key) {
+        Some(values) => values.iter().map(|v| v.parse::<f64>()).collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| format!("{} is not a color", key))?,
+        None => return Ok(default),
+    };
+    match values[..] {
+        [g] => Ok(linear::Vec3 {x: g, y: g, z: g}),
+        [x, y, z] => Ok(linear::Vec3 {x, y, z}),
+        _ => Err(format!("{} takes one or three numbers", key)),
+    }
+}
+
+fn word<'b>(params: &BTreeMap<&str, &'b [String]>, key: &str, default: &'b str) -> Result<&'b str, String> {
+    match params.get(key) {
+        Some([v]) => Ok(v),
+        Some(_) => Err(format!("{} takes one word", key)),
+        None => Ok(default),
+    }
+}
+
+fn flag(params: &BTreeMap<&str, &[String]>, key: &str) -> Result<bool, String> {
+    match word(params, key, "false")? {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("{} is true or false", key)),
+    }
+}
+
+// Builds a material of the kind from its parameters.
+fn build(kind: &str, params: &BTreeMap<&str, &[String]>) -> Result<Box<dyn material::Material>, String> {
+    Ok(match kind {
+        "lambert" => Box::new(material::Lambert {color: Color::Constant(color(params, "color", GREY)?)}),
+        "oren_nayar" => Box::new(material::OrenNayar {
+            color: Color::Constant(color(params, "color", GREY)?),
+            sigma: Scalar::Constant(number(params, "sigma", 0.0)?),
+            retro: number(params, "retro", 0.0)?,
+            retro_exponent: number(params, "retro_exponent", 1.0)?,
+        }),
+        "specular" => Box::new(material::Specular {color: Color::Constant(color(params, "color", scene::WHITE)?), roughness: number(params, "roughness", 0.0)?}),
+        "conductor" | "gold" | "copper" | "silver" | "aluminium" => {
+            let base = match kind {
+                "gold" => material::GOLD,
+                "copper" => material::COPPER,
+                "silver" => material::SILVER,
+                _ => material::ALUMINIUM,
+            };
+            Box::new(material::Conductor {
+                eta: color(params, "eta", base.eta)?,
+                k: color(params, "k", base.k)?,
+                roughness: Scalar::Constant(number(params, "roughness", 0.0)?),
+                anisotropy: Scalar::Constant(number(params, "anisotropy", 0.0)?),
+                film: None,
+            })
+        },
+        "dielectric" => {
+            let eta = match word(params, "eta", "1.5")? {
+                "bk7" => spectrum::BK7,
+                "sf11" => spectrum::SF11,
+                "fused_silica" => spectrum::FUSED_SILICA,
+                _ => spectrum::Ior::Constant(number(params, "eta", 1.5)?),
+            };
+            let thin = flag(params, "thin")?;
+            let absorption = match params.get("absorption") {
+                Some(_) => Some(material::Absorption {transmittance: color(params, "absorption", scene::WHITE)?, distance: number(params, "distance", 1.0)?}),
+                None => None,
+            };
+            Box::new(material::Dielectric {
+                color: Color::Constant(color(params, "color", scene::WHITE)?),
+                eta,
+                roughness: Scalar::Constant(number(params, "roughness", 0.0)?),
+                thin,
+                film: None,
+                absorption,
+            })
+        },
+        "principled" => {
+            let mut p = material::PRINCIPLED;
+            if params.contains_key("color") {
+                p.color = Color::Constant(color(params, "color", GREY)?);
+            }
+            let weights = [
+                ("metallic", &mut p.metallic), ("roughness", &mut p.roughness), ("anisotropy", &mut p.anisotropy),
+                ("specular", &mut p.specular), ("specular_tint", &mut p.specular_tint), ("sheen", &mut p.sheen),
+                ("sheen_tint", &mut p.sheen_tint), ("clearcoat", &mut p.clearcoat), ("clearcoat_gloss", &mut p.clearcoat_gloss),
+                ("transmission", &mut p.transmission), ("subsurface", &mut p.subsurface),
+            ];
+            for (key, weight) in weights {
+                if params.contains_key(key) {
+                    *weight = Scalar::Constant(number(params, key, 0.0)?);
+                }
+            }
+            p.eta = number(params, "eta", p.eta)?;
+            Box::new(p)
+        },
+        "subsurface" => Box::new(material::Subsurface {
+            color: Color::Constant(color(params, "color", GREY)?),
+            mfp: color(params, "mfp", scene::WHITE)?,
+            eta: number(params, "eta", 1.4)?,
+            roughness: Scalar::Constant(number(params, "roughness", 0.3)?),
+            method: match word(params, "method", "random_walk")? {
+                "random_walk" => material::SubsurfaceMethod::RandomWalk,
+                "diffusion" => material::SubsurfaceMethod::Diffusion,
+                _ => return Err("method is random_walk or diffusion".to_string()),
+            },
+        }),
+        _ => Box::new(material::Emitter {
+            color: color(params, "color", scene::WHITE)?,
+            spd: spectrum::Spd::Rgb,
+            scale: number(params, "scale", 1.0)?,
+            two_sided: flag(params, "two_sided")?,
+            texture: None,
+            falloff: number(params, "falloff", 0.0)?,
+        }),
+    })
+}
+
+impl Library {
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(Library::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut problems = Vec::new();
+        let mut definitions: BTreeMap<String, Definition> = BTreeMap::new();
+        let mut current: Option<String> = None;
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let words: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+            match words[..] {
+                [] => {},
+                ["material", name, parent] => {
+                    if kind(name).is_some() {
+                        problems.push((line_no, format!("{} is the name of a kind", name)));
+                        current = None;
+                    } else if definitions.contains_key(name) {
+                        problems.push((line_no, format!("{} is defined twice", name)));
+                        current = None;
+                    } else {
+                        definitions.insert(name.to_string(), Definition {parent: parent.to_string(), line: line_no, params: Vec::new()});
+                        current = Some(name.to_string());
+                    }
+                },
+                ["material", ..] => {
+                    problems.push((line_no, "expected material, name and parent".to_string()));
+                    current = None;
+                },
+                [key, ref values @ ..] => match current.as_ref().and_then(|name| definitions.get_mut(name)) {
+                    Some(def) => def.params.push((key.to_string(), values.iter().map(|v| v.to_string()).collect(), line_no)),
+                    None => problems.push((line_no, format!("{} is outside a material", key))),
+                },
+            }
+        }
+
+        let mut library = Library {
+            materials: BTreeMap::new(),
+            defined: definitions.keys().cloned().collect(),
+            inherited: BTreeSet::new(),
+            problems,
+            used: Mutex::new(BTreeSet::new()),
+            undefined: Mutex::new(BTreeSet::new()),
+        };
+        for name in definitions.keys() {
+            if let Some(material) = library.resolve(&definitions, name) {
+                library.materials.insert(name.to_string(), material);
+            }
+        }
+        library
+    }
+
+    // Follows the parents of a material to its kind, and builds it from the
+    // parameters set along the way, the nearest setting winning.
+    fn resolve(&mut self, definitions: &BTreeMap<String, Definition>, name: &str) -> Option<Box<dyn material::Material>> {
+        let line = definitions[name].line;
+        let mut chain: Vec<&Definition> = Vec::new();
+        let mut current = name;
+        let (kind_name, allowed) = loop {
+            if let Some(allowed) = kind(current) {
+                break (current, allowed);
+            }
+            let def = match definitions.get(current) {
+                Some(def) => def,
+                None => {
+                    self.undefined.get_mut().unwrap().insert(current.to_string());
+                    self.problems.push((line, format!("{} inherits from undefined {}", name, current)));
+                    return None;
+                },
+            };
+            if chain.iter().any(|d| std::ptr::eq(*d, def)) {
+                self.problems.push((line, format!("{} inherits from itself", name)));
+                return None;
+            }
+            if current != name {
+                self.inherited.insert(current.to_string());
+            }
+            chain.push(def);
+            current = &def.parent;
+        };
+
+        let mut params: BTreeMap<&str, &[String]> = BTreeMap::new();
+        for def in chain.iter().rev() {
+            for (key, values, line) in &def.params {
+                if !allowed.contains(&key.as_str()) {
+                    // Reported once, where it is written.
+                    if std::ptr::eq(*def, chain[0]) {
+                        self.problems.push((*line, format!("{} takes no {}", kind_name, key)));
+                    }
+                    continue;
+                }
+                params.insert(key, values);
+            }
+        }
+        match build(kind_name, &params) {
+            Ok(material) => Some(material),
+            Err(msg) => {
+                self.problems.push((line, format!("{}: {}", name, msg)));
+                None
+            },
+        }
+    }
+
+    // The named material, or None if it is undefined or has problems.
+    pub fn get(&self, name: &str) -> Option<&dyn material::Material> {
+        if self.defined.contains(name) {
+            self.used.lock().unwrap().insert(name.to_string());
+        } else {
+            self.undefined.lock().unwrap().insert(name.to_string());
+        }
+        self.materials.get(name).map(|m| m.as_ref())
+    }
+
+    // Materials defined but neither used nor inherited from so far, names
+    // looked up or inherited from but never defined, and problems in the file.
+    pub fn report(&self) -> Report {
+        let used = self.used.lock().unwrap();
+        Report {
+            unused: self.defined.iter().filter(|n| !used.contains(*n) && !self.inherited.contains(*n)).cloned().collect(),
+            undefined: self.undefined.lock().unwrap().iter().cloned().collect(),
+            problems: {
+                let mut problems = self.problems.clone();
+                problems.sort_by_key(|(line, _)| *line);
+                problems.into_iter().map(|(line, msg)| format!("line {}: {}", line, msg)).collect()
+            },
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.unused.is_empty() && self.undefined.is_empty() && self.problems.is_empty() {
+            return writeln!(f, "Material library is fine.");
+        }
+        if !self.unused.is_empty() {
+            writeln!(f, "Unused materials: {}", self.unused.join(", "))?;
+        }
+        if !self.undefined.is_empty() {
+            writeln!(f, "Undefined materials: {}", self.undefined.join(", "))?;
+        }
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    const TEXT: &str = "\
+# Lamps of one color and different spreads.
+material lamp emitter
+color 2
+falloff 2
+
+material panel lamp
+falloff 0
+two_sided true
+
+material loop_a loop_b
+material loop_b loop_a
+
+material orphan missing
+material bad lamp
+glow 3
+scale 1
+";
+
+    #[test]
+    fn children_override_their_parents() {
+        let library = Library::parse(TEXT);
+        // Power per unit area is the radiance times 2 pi / (falloff + 2), on each side.
+        let lamp = library.get("lamp").unwrap().power();
+        assert!((lamp - 2.0 * PI / 4.0 * 2.0).abs() < 1e-9);
+        let panel = library.get("panel").unwrap();
+        assert!((panel.power() - 2.0 * PI / 2.0 * 2.0 * 2.0).abs() < 1e-9);
+        assert!(panel.two_sided());
+    }
+
+    #[test]
+    fn problems_carry_their_lines() {
+        let library = Library::parse(TEXT);
+        assert!(library.get("loop_a").is_none());
+        assert!(library.get("orphan").is_none());
+        assert!(library.get("bad").is_some());
+        assert!(library.get("absent").is_none());
+        let report = library.report();
+        assert_eq!(report.problems, vec![
+            "line 10: loop_a inherits from itself",
+            "line 11: loop_b inherits from itself",
+            "line 13: orphan inherits from undefined missing",
+            "line 15: emitter takes no glow",
+        ]);
+        assert_eq!(report.undefined, vec!["absent", "missing"]);
+        assert_eq!(report.unused, vec!["panel"]);
+    }
+
+    #[test]
+    fn names_are_checked() {
+        let report = Library::parse("material lambert lambert\nmaterial a lambert\nmaterial a lambert\ncolor 1\nmaterial b\n").report();
+        assert_eq!(report.problems, vec![
+            "line 1: lambert is the name of a kind",
+            "line 3: a is defined twice",
+            "line 4: color is outside a material",
+            "line 5: expected material, name and parent",
+        ]);
+    }
+}
@@ -0,0 +1,182 @@
+use std::f64::consts::PI;
+
+use crate::ies;
+use crate::lightbvh;
+use crate::linear;
+use crate::material;
+use crate::ray;
+use crate::sampling;
+use crate::scene;
+use crate::spectrum;
+
+// Lights that are not part of the geometry. Their emitter's color is the
+// radiant intensity (W/sr) for point and spot lights, and the irradiance
+// perpendicular to the light (W/m^2) for directional lights. Point and spot
+// lights can carry an IES fixture, which then shapes their intensity by
+// direction with the emitter's color as the peak intensity.
+
+// Point light shining alike in every direction, unless a fixture shapes it
+// about dir.
+pub struct PointLight<'a> {
+    pub pos: linear::Vec3<f64>,
+    pub dir: linear::Vec3<f64>,
+    pub emitter: &'a material::Emitter<'a>,
+    pub fixture: Option<ies::Fixture<'a>>,
+}
+
+// Spot light pointing along dir, at full intensity within the inner cone
+// angle and falling off smoothly to zero at the outer one (radians from dir).
+// A fixture's profile is turned to point along dir as well.
+pub struct SpotLight<'a> {
+    pub pos: linear::Vec3<f64>,
+    pub dir: linear::Vec3<f64>,
+    pub inner: f64,
+    pub outer: f64,
+    pub emitter: &'a material::Emitter<'a>,
+    pub fixture: Option<ies::Fixture<'a>>,
+}
+
+// Light from infinitely far away in the direction dir (pointing towards the
+// light). With a non-zero angular diameter (radians) it is a disk in the
+// sky, like the sun, giving soft shadows.
+pub struct DirectionalLight<'a> {
+    pub dir: linear::Vec3<f64>,
+    pub angle: f64,
+    pub emitter: &'a material::Emitter<'a>,
+}
+
+fn shadow_ray(from: &linear::Vec3<f64>, dir: linear::Vec3<f64>, lambda: &Option<spectrum::Wavelengths>) -> ray::Ray {
+    ray::Ray {origin: from.copy(), traj: dir, lambda: lambda.as_ref().map(|wl| wl.copy()), pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0}
+}
+
+// Relative intensity an optional fixture on a light with this axis gives
+// light leaving along dir.
+fn profile(fixture: &Option<ies::Fixture>, axis: &linear::Vec3<f64>, dir: &linear::Vec3<f64>) -> f64 {
+    fixture.as_ref().map_or(1.0, |f| f.intensity(axis, dir))
+}
+
+fn smoothstep(a: f64, b: f64, x: f64) -> f64 {
+    if a == b {
+        return if x < a { 0.0 } else { 1.0 };
+    }
+    let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl<'a> scene::LightSource for PointLight<'a> {
+    fn sample(&self, from: &linear::Vec3<f64>, lambda: &Option<spectrum::Wavelengths>) -> Option<scene::LightSample> {
+        let to = &self.pos - from;
+        let dist = to.norm();
+        let ray = shadow_ray(from, &to / dist, lambda);
+        let intensity = profile(&self.fixture, &self.dir, &-&ray.traj);
+        if intensity <= 0.0 {
+            return None;
+        }
+        let radiance = self.emitter.radiance(&ray) * (intensity / (dist * dist));
+        Some(scene::LightSample {ray, dist, pdf: 1.0, radiance, delta: true})
+    }
+
+    fn pdf(&self, _from: &linear::Vec3<f64>, _dir: &linear::Vec3<f64>) -> f64 {
+        0.0
+    }
+
+    fn bounds(&self) -> Option<lightbvh::LightBounds> {
+        let peak = spectrum::luminance(&self.emitter.color);
+        let power = self.fixture.as_ref().map_or(4.0 * PI * peak, |f| f.power(peak));
+        Some(lightbvh::LightBounds {min: self.pos.copy(), max: self.pos.copy(), axis: linear::Y, cos_o: -1.0, cos_e: 0.0, power})
+    }
+}
+
+impl<'a> scene::LightSource for SpotLight<'a> {
+    fn sample(&self, from: &linear::Vec3<f64>, lambda: &Option<spectrum::Wavelengths>) -> Option<scene::LightSample> {
+        let to = &self.pos - from;
+        let dist = to.norm();
+        let ray = shadow_ray(from, &to / dist, lambda);
+        let cos = -(&ray.traj * &self.dir.normalize());
+        let falloff = smoothstep(self.outer.cos(), self.inner.cos(), cos) * profile(&self.fixture, &self.dir, &-&ray.traj);
+        if falloff <= 0.0 {
+            return None;
+        }
+        let radiance = self.emitter.radiance(&ray) * (falloff / (dist * dist));
+        Some(scene::LightSample {ray, dist, pdf: 1.0, radiance, delta: true})
+    }
+
+    fn pdf(&self, _from: &linear::Vec3<f64>, _dir: &linear::Vec3<f64>) -> f64 {
+        0.0
+    }
+
+    // Full intensity within the inner cone, and about half over the falloff.
+    fn bounds(&self) -> Option<lightbvh::LightBounds> {
+        let (cos_i, cos_o) = (self.inner.cos(), self.outer.cos());
+        let peak = spectrum::luminance(&self.emitter.color);
+        let mut power = 2.0 * PI * peak * (1.0 - 0.5 * (cos_i + cos_o));
+        if let Some(f) = &self.fixture {
+            power = power.min(f.power(peak));
+        }
+        let cos_e = (self.outer - self.inner).max(0.0).cos();
+        Some(lightbvh::LightBounds {min: self.pos.copy(), max: self.pos.copy(), axis: self.dir.normalize(), cos_o: cos_i, cos_e, power})
+    }
+}
+
+impl<'a> DirectionalLight<'a> {
+    fn cos_max(&self) -> f64 {
+        (self.angle * 0.5).cos()
+    }
+}
+
+impl<'a> scene::LightSource for DirectionalLight<'a> {
+    fn sample(&self, from: &linear::Vec3<f64>, lambda: &Option<spectrum::Wavelengths>) -> Option<scene::LightSample> {
+        let axis = self.dir.normalize();
+        if self.angle <= 0.0 {
+            let ray = shadow_ray(from, axis, lambda);
+            let radiance = self.emitter.radiance(&ray);
+            return Some(scene::LightSample {ray, dist: scene::T_MAX, pdf: 1.0, radiance, delta: true});
+        }
+        let cos_max = self.cos_max();
+        let local = sampling::uniform_cone(rand::random(), rand::random(), cos_max);
+        let ray = shadow_ray(from, linear::Frame::new(&axis).to_world(&local), lambda);
+        let pdf = sampling::cone_solid_angle(cos_max).recip();
+        let radiance = self.emitter.radiance(&ray) * pdf;
+        Some(scene::LightSample {ray, dist: scene::T_MAX, pdf, radiance, delta: false})
+    }
+
+    fn pdf(&self, _from: &linear::Vec3<f64>, dir: &linear::Vec3<f64>) -> f64 {
+        let cos_max = self.cos_max();
+        if self.angle <= 0.0 || dir * &self.dir.normalize() < cos_max {
+            return 0.0;
+        }
+        sampling::cone_solid_angle(cos_max).recip()
+    }
+
+    // Radiance of the disk, the irradiance spread over its solid angle.
+    fn background(&self, ray: &ray::Ray) -> linear::Vec3<f64> {
+        let pdf = self.pdf(&ray.origin, &ray.traj);
+        if pdf <= 0.0 {
+            return scene::BLACK.copy();
+        }
+        self.emitter.radiance(ray) * pdf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::LightSource;
+
+    #[test]
+    fn spot_light_is_full_inside_inner_and_dark_outside_outer() {
+        let emitter = material::Emitter {color: linear::Vec3 {x: 8.0, y: 8.0, z: 8.0}, spd: spectrum::Spd::Rgb, scale: 1.0, two_sided: false, texture: None, falloff: 0.0};
+        let spot = SpotLight {pos: linear::Vec3::new(), dir: linear::Vec3 {x: 0.0, y: -3.0, z: 0.0}, inner: 10f64.to_radians(), outer: 20f64.to_radians(), emitter: &emitter, fixture: None};
+        // Points two units from the light at an angle from its axis, in degrees.
+        let at = |deg: f64| linear::Vec3 {x: 2.0 * deg.to_radians().sin(), y: -2.0 * deg.to_radians().cos(), z: 0.0};
+        for deg in [0.0, 5.0, 9.9] {
+            let sample = spot.sample(&at(deg), &None).unwrap();
+            assert!((sample.radiance.x - 2.0).abs() < 1e-9, "{} degrees: {}", deg, sample.radiance.x);
+        }
+        let edge = spot.sample(&at(15.0), &None).unwrap().radiance.x;
+        assert!(edge > 0.0 && edge < 2.0);
+        for deg in [20.1, 45.0, 180.0] {
+            assert!(spot.sample(&at(deg), &None).is_none(), "{} degrees", deg);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::f64::consts::PI;
+
+use crate::linear;
+
+// Spatial and directional extent of the light leaving a light or group of
+// lights: an axis aligned box, the cone of normals about axis (cos_o), the
+// further angle light spreads past those normals (cos_e), and total power.
+pub struct LightBounds {
+    pub min: linear::Vec3<f64>,
+    pub max: linear::Vec3<f64>,
+    pub axis: linear::Vec3<f64>,
+    pub cos_o: f64,
+    pub cos_e: f64,
+    pub power: f64,
+}
+
+struct Node {
+    bounds: LightBounds,
+    children: Option<(usize, usize)>,
+    parent: Option<usize>,
+}
+
+// Binary tree over bounded lights, traversed stochastically by the estimated
+// contribution of each subtree to a point (Conty Estevez and Kulla 2018).
+pub struct LightBvh {
+    nodes: Vec<Node>,
+    // Leaf node of each light, and light of each leaf node.
+    leaves: Vec<usize>,
+    lights: Vec<Option<usize>>,
+}
+
+fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b { 1.0 } else { cos_a * cos_b + sin_a * sin_b }
+}
+
+fn sin_from_cos(cos: f64) -> f64 {
+    (1.0 - cos * cos).max(0.0).sqrt()
+}
+
+impl LightBounds {
+    fn centroid(&self) -> linear::Vec3<f64> {
+        (&self.min + &self.max) * 0.5
+    }
+
+    fn union(&self, b: &LightBounds) -> LightBounds {
+        if self.power <= 0.0 {
+            return b.copy();
+        }
+        if b.power <= 0.0 {
+            return self.copy();
+        }
+        let (axis, cos_o) = cone_union(&self.axis, self.cos_o, &b.axis, b.cos_o);
+        LightBounds {
+            min: linear::Vec3 {x: self.min.x.min(b.min.x), y: self.min.y.min(b.min.y), z: self.min.z.min(b.min.z)},
+            max: linear::Vec3 {x: self.max.x.max(b.max.x), y: self.max.y.max(b.max.y), z: self.max.z.max(b.max.z)},
+            axis,
+            cos_o,
+            cos_e: self.cos_e.min(b.cos_e),
+            power: self.power + b.power,
+        }
+    }
+
+    pub fn copy(&self) -> Self {
+        LightBounds {min: self.min.copy(), max: self.max.copy(), axis: self.axis.copy(), cos_o: self.cos_o, cos_e: self.cos_e, power: self.power}
+    }
+
+    // Conservative estimate of the light reaching a point.
+    pub fn importance(&self, p: &linear::Vec3<f64>) -> f64 {
+        let pc = self.centroid();
+        let diag = (&self.max - &self.min).norm();
+        let to = p - &pc;
+        let d2 = (&to * &to).max(diag * 0.5);
+        let cos_w = if d2 > 0.0 { (&to * &self.axis) / to.norm().max(1e-12) } else { 1.0 };
+        let sin_w = sin_from_cos(cos_w);
+
+        let r2 = diag * diag * 0.25;
+        let cos_b = if &to * &to < r2 { -1.0 } else { (1.0 - r2 / (&to * &to)).sqrt() };
+
+        let cos_x = cos_sub_clamped(sin_w, cos_w, sin_from_cos(self.cos_o), self.cos_o);
+        let cos_p = cos_sub_clamped(sin_from_cos(cos_x), cos_x, sin_from_cos(cos_b), cos_b);
+        if cos_p <= self.cos_e {
+            return 0.0;
+        }
+        self.power * cos_p / d2
+    }
+}
+
+// Smallest cone holding two cones, each given by its axis and cosine spread.
+fn cone_union(wa: &linear::Vec3<f64>, cos_a: f64, wb: &linear::Vec3<f64>, cos_b: f64) -> (linear::Vec3<f64>, f64) {
+    let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+    let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+    let theta_d = (wa * wb).clamp(-1.0, 1.0).acos();
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return (wa.copy(), cos_a);
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return (wb.copy(), cos_b);
+    }
+    let theta_o = (theta_a + theta_d + theta_b) * 0.5;
+    if theta_o >= PI {
+        return (wa.copy(), -1.0);
+    }
+    let wr = wa ^ wb;
+    if &wr * &wr == 0.0 {
+        return (wa.copy(), -1.0);
+    }
+    (wa.rotate(&wr, (theta_o - theta_a) * 0.5).normalize(), theta_o.cos())
+}
+
+impl LightBvh {
+    pub fn new(bounds: Vec<LightBounds>) -> Self {
+        let mut bvh = LightBvh {nodes: Vec::new(), leaves: vec![0; bounds.len()], lights: Vec::new()};
+        let mut items: Vec<(usize, LightBounds)> = bounds.into_iter().enumerate().collect();
+        if !items.is_empty() {
+            bvh.build(&mut items, None);
+        }
+        bvh
+    }
+
+    fn build(&mut self, items: &mut [(usize, LightBounds)], parent: Option<usize>) -> usize {
+        let index = self.nodes.len();
+        if items.len() == 1 {
+            self.nodes.push(Node {bounds: items[0].1.copy(), children: None, parent});
+            self.lights.push(Some(items[0].0));
+            self.leaves[items[0].0] = index;
+            return index;
+        }
+        let mut bounds = items[0].1.copy();
+        for item in items.iter().skip(1) {
+            bounds = bounds.union(&item.1);
+        }
+        // Split at the median centroid along the longest axis of the box.
+        let ext = &bounds.max - &bounds.min;
+        let key = |b: &LightBounds| {
+            let c = b.centroid();
+            if ext.x >= ext.y && ext.x >= ext.z { c.x } else if ext.y >= ext.z { c.y } else { c.z }
+        };
+        items.sort_by(|a, b| key(&a.1).total_cmp(&key(&b.1)));
+        self.nodes.push(Node {bounds, children: None, parent});
+        self.lights.push(None);
+        let mid = items.len() / 2;
+        let (left, right) = items.split_at_mut(mid);
+        let l = self.build(left, Some(index));
+        let r = self.build(right, Some(index));
+        self.nodes[index].children = Some((l, r));
+        index
+    }
+
+    // Picks a light for a point, returning its index and probability.
+    pub fn sample(&self, p: &linear::Vec3<f64>, mut u: f64) -> Option<(usize, f64)> {
+        if self.nodes.is_empty() || self.nodes[0].bounds.importance(p) <= 0.0 {
+            return None;
+        }
+        let mut node = 0;
+        let mut pmf = 1.0;
+        while let Some((l, r)) = self.nodes[node].children {
+            let il = self.nodes[l].bounds.importance(p);
+            let ir = self.nodes[r].bounds.importance(p);
+            if il + ir <= 0.0 {
+                return None;
+            }
+            let pl = il / (il + ir);
+            if u < pl {
+                u = (u / pl).min(1.0 - f64::EPSILON);
+                pmf *= pl;
+                node = l;
+            } else {
+                u = ((u - pl) / (1.0 - pl)).min(1.0 - f64::EPSILON);
+                pmf *= 1.0 - pl;
+                node = r;
+            }
+        }
+        self.lights[node].map(|light| (light, pmf))
+    }
+
+    // Probability that sample() picks the light for the point.
+    pub fn pmf(&self, p: &linear::Vec3<f64>, light: usize) -> f64 {
+        if self.nodes.is_empty() || self.nodes[0].bounds.importance(p) <= 0.0 {
+            return 0.0;
+        }
+        let mut node = self.leaves[light];
+        let mut pmf = 1.0;
+        while let Some(parent) = self.nodes[node].parent {
+            let (l, r) = self.nodes[parent].children.unwrap();
+            let il = self.nodes[l].bounds.importance(p);
+            let ir = self.nodes[r].bounds.importance(p);
+            if il + ir <= 0.0 {
+                return 0.0;
+            }
+            pmf *= if node == l { il } else { ir } / (il + ir);
+            node = parent;
+        }
+        pmf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A point light at x facing any way, and a one-sided panel facing down.
+    fn light(x: f64, power: f64, facing: bool) -> LightBounds {
+        let p = linear::Vec3 {x, y: 2.0, z: -x * 0.5};
+        let (axis, cos_o, cos_e) = if facing { (linear::Vec3 {x: 0.0, y: -1.0, z: 0.0}, 1.0, 0.0) } else { (linear::Y, -1.0, 0.0) };
+        LightBounds {min: p.copy(), max: &p + &linear::Vec3 {x: 0.5, y: 0.0, z: 0.5}, axis, cos_o, cos_e, power}
+    }
+
+    #[test]
+    fn pmf_matches_what_sample_picks() {
+        let bvh = LightBvh::new((0..7).map(|i| light(i as f64 - 3.0, 1.0 + i as f64, i % 2 == 0)).collect());
+        let steps = 20000;
+        for p in [linear::Vec3 {x: 0.0, y: 0.0, z: 0.0}, linear::Vec3 {x: 4.0, y: 1.0, z: -1.0}] {
+            let total: f64 = (0..7).map(|i| bvh.pmf(&p, i)).sum();
+            assert!((total - 1.0).abs() < 1e-9);
+            let mut counts = [0usize; 7];
+            for i in 0..steps {
+                let (light, pmf) = bvh.sample(&p, (i as f64 + 0.5) / steps as f64).unwrap();
+                assert!((pmf - bvh.pmf(&p, light)).abs() < 1e-9);
+                counts[light] += 1;
+            }
+            for (i, count) in counts.iter().enumerate() {
+                assert!((*count as f64 / steps as f64 - bvh.pmf(&p, i)).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn lights_facing_away_are_never_picked() {
+        let bvh = LightBvh::new(vec![light(0.0, 1.0, true), light(1.0, 1.0, true)]);
+        let above = linear::Vec3 {x: 0.0, y: 5.0, z: 0.0};
+        assert!(bvh.sample(&above, 0.5).is_none());
+        assert_eq!(bvh.pmf(&above, 0), 0.0);
+    }
+}
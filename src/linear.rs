@@ -1,7 +1,6 @@
 use core::ops::{Add, Mul, Neg, Sub, Div, BitXor};
 use std::ops::BitOr;
 use num::Float;
-use rand;
 
 pub const X: Vec3<f64> = Vec3 {x: 1.0, y: 0.0, z: 0.0};
 pub const Y: Vec3<f64> = Vec3 {x: 0.0, y: 1.0, z: 0.0};
@@ -33,6 +32,7 @@ impl<T: Float> Mul for &Quat<T> {
 impl<T: Float> Div for &Quat<T> {
     type Output = Quat<T>;
 
+    #[allow(clippy::suspicious_arithmetic_impl)]
     fn div(self, v: Self) -> Quat<T> {
         self * &v.recip()
     }
@@ -58,13 +58,13 @@ impl<T: Float> Vec3<T> {
     }
 
     pub fn reflect(&self, v: &Self) -> Self {
-        let proj = &(self | &v); 
+        let proj = &(self | v);
         -self + (proj + proj)
     }
 
     pub fn refract(&self, norm: &Self, ratio: T) -> Self {
         let perp = (self + &(norm * (&-self.normalize() * norm))) * ratio;
-        let para = norm * -((ratio / ratio) - (&perp * &perp)).sqrt();
+        let para = norm * -(T::one() - (&perp * &perp)).sqrt();
         perp + para
     }
 
@@ -258,4 +258,33 @@ impl<T: Float> Clone for Vec3<T> {
     fn clone(&self) -> Self {
         self.copy()
     }
-}
\ No newline at end of file
+}
+// Orthonormal frame around a unit normal, used to move directions between
+// world space and a local space where the normal is +z.
+pub struct Frame {
+    pub s: Vec3<f64>,
+    pub t: Vec3<f64>,
+    pub n: Vec3<f64>,
+}
+
+impl Frame {
+    // Branchless basis construction (Duff et al. 2017).
+    pub fn new(n: &Vec3<f64>) -> Self {
+        let sign = 1.0_f64.copysign(n.z);
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        Frame {
+            s: Vec3 {x: 1.0 + sign * n.x * n.x * a, y: sign * b, z: -sign * n.x},
+            t: Vec3 {x: b, y: sign + n.y * n.y * a, z: -n.y},
+            n: n.copy(),
+        }
+    }
+
+    pub fn to_local(&self, v: &Vec3<f64>) -> Vec3<f64> {
+        Vec3 {x: v * &self.s, y: v * &self.t, z: v * &self.n}
+    }
+
+    pub fn to_world(&self, v: &Vec3<f64>) -> Vec3<f64> {
+        &(&(&self.s * v.x) + &(&self.t * v.y)) + &(&self.n * v.z)
+    }
+}
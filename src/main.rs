@@ -1,10 +1,25 @@
-use std::{env::args, f64::consts::PI, ops::Rem, thread};
+use std::{env::args, ops::Rem, thread};
 
 mod linear;
 mod camera;
 mod ray;
 mod scene;
 mod material;
+mod spectrum;
+mod sampling;
+mod microfacet;
+mod medium;
+mod lightbvh;
+mod light;
+mod image;
+mod envmap;
+mod sky;
+mod ies;
+mod texture;
+mod displace;
+mod merl;
+mod library;
+mod scenes;
 
 
 fn write_color(color: &linear::Vec3<f64>) {
@@ -33,39 +48,34 @@ fn develop(width: u32, height: u32, films: &Vec<Vec<linear::Vec3<f64>>>) {
             }
             write_color(&(color * scale));
         }
-        print!("\n");
+        println!();
     }
 }
 
-fn do_render(width: u32, height: u32, samples: u16) -> Vec<linear::Vec3<f64>> {
-    let sph = scene::Sphere{mat: &material::GLASS_TESTING, pos: linear::Vec3{x: 0.0, y: 1.5, z: -7.0}, rad: 1.5};
-    let sph2 = scene::Sphere{mat: &material::MIRROR_TESTING, pos: linear::Vec3{x: 2.0, y: 0.8, z: -4.2}, rad: 0.8};
-    let sph3 = scene::Sphere{mat: &material::RED_TESTING, pos: linear::Vec3{x: 1.0, y: 0.3, z: -2.0}, rad: 0.3};
-    let sph4 = scene::Sphere{mat: &material::GOLD_TESTING, pos: linear::Vec3{x: -2.0, y: 0.8, z: -4.2}, rad: 0.8};
-    let sph5 = scene::Sphere{mat: &material::GLASS_TESTING, pos: linear::Vec3{x: 0.0, y: 0.6, z: -3.4}, rad: 0.6};
-    let sph6 = scene::Sphere{mat: &material::WHITE_TESTING, pos: linear::Vec3{x: -6.0, y: 2.0, z: -13.0}, rad: 2.0};
-    let sph7 = scene::Sphere{mat: &material::WHITE_TESTING, pos: linear::Vec3{x: 6.0, y: 2.0, z: -13.0}, rad: 2.0};
-    let sph8 = scene::Sphere{mat: &material::METAL_TESTING, pos: linear::Vec3{x: -5.0, y: 1.0, z: -3.0}, rad: 1.0};
-    let sph9 = scene::Sphere{mat: &material::PURPLE_TESTING, pos: linear::Vec3{x: -1.0, y: 0.3, z: -2.0}, rad: 0.3};
-    let light = scene::Sphere{mat: &material::LIGHT_TESTING, pos: linear::Vec3{x: 0.0, y: 10.0, z: -6.0}, rad: 2.0};
-    let earth = scene::Sphere{mat: &material::GREEN_TESTING, pos: linear::Vec3{x: 0.0, y: -800.0, z: -5.0}, rad: 800.0};
-    let red_light = scene::Sphere{mat: &material::REDL_TESTING, pos: linear::Vec3{x: -4.0, y: 1.8, z: -3.2}, rad: 0.8};
-    let blue_light = scene::Sphere{mat: &material::BLUEL_TESTING, pos: linear::Vec3{x: 4.0, y: 1.8, z: -3.2}, rad: 0.8};
-    let back_face = scene::Face{d: -15.0, facing: scene::FaceAxis::FaceZ, mat: &material::GREEN_TESTING, w1: -10.0, w2: 10.0, h1: 0.0, h2: 10.0};
-    let right_face = scene::Face{d: -8.0, facing: scene::FaceAxis::FaceX, mat: &material::RED_TESTING, w1: 0.0, w2: 10.0, h1: -16.0, h2: 10.0};
-    let left_face = scene::Face{d: 8.0, facing: scene::FaceAxis::FaceX, mat: &material::BLUE_TESTING, w1: 0.0, w2: 10.0, h1: -16.0, h2: 10.0};
-    let floor_face = scene::Face{d: -0.0001, facing: scene::FaceAxis::FaceY, mat: &material::WHITE_TESTING, w1: -15.0, w2: 10.0, h1: -8.0, h2: 8.0};
-    let ceiling_face = scene::Face{d: 10.0, facing: scene::FaceAxis::FaceY, mat: &material::WHITE_TESTING, w1: -15.0, w2: 10.0, h1: -8.0, h2: 8.0};
-    let mut cam: camera::Camera = camera::Camera {
-        fov: std::f64::consts::PI as f64 * 0.5, 
-        look: linear::Vec3{x: 0.0, y: 0.0, z: -1.0}, 
-        pos: linear::Vec3 {x: 0.0, y: 0.0, z: 0.0}, 
-        ratio: 0.0, 
-        scene: scene::Scene{objects: Vec::new(), lights: Vec::new()},
-        up: linear::Vec3{x: 0.0, y: 1.0, z: 0.0},
-        lens_rad: 0.05,
-        film: Vec::new()
-    };
+fn do_render(options: &scenes::Options, assets: &scenes::Assets, samples: u16) -> Result<Vec<linear::Vec3<f64>>, String> {
+    match options.scene {
+        scenes::Showcase::Studio => {},
+        scenes::Showcase::Spectral => return scenes::spectral(options, assets, samples),
+        scenes::Showcase::Outdoor => return scenes::outdoor(options, samples),
+        scenes::Showcase::Stage => return scenes::stage(options, assets, samples),
+        scenes::Showcase::Gallery => return scenes::gallery(options, assets, samples),
+    }
+    let sph = scene::Sphere{mat: assets.material("glass", &material::GLASS_TESTING), pos: linear::Vec3{x: 0.0, y: 1.5, z: -7.0}, rad: 1.5};
+    let sph2 = scene::Sphere{mat: assets.material("mirror", &material::MIRROR_TESTING), pos: linear::Vec3{x: 2.0, y: 0.8, z: -4.2}, rad: 0.8};
+    let sph3 = scene::Sphere{mat: assets.material("red", &material::RED_TESTING), pos: linear::Vec3{x: 1.0, y: 0.3, z: -2.0}, rad: 0.3};
+    let sph4 = scene::Sphere{mat: assets.material("gold", &material::GOLD_TESTING), pos: linear::Vec3{x: -2.0, y: 0.8, z: -4.2}, rad: 0.8};
+    let sph5 = scene::Sphere{mat: assets.material("glass", &material::GLASS_TESTING), pos: linear::Vec3{x: 0.0, y: 0.6, z: -3.4}, rad: 0.6};
+    let sph6 = scene::Sphere{mat: assets.material("white", &material::WHITE_TESTING), pos: linear::Vec3{x: -6.0, y: 2.0, z: -13.0}, rad: 2.0};
+    let sph7 = scene::Sphere{mat: assets.material("white", &material::WHITE_TESTING), pos: linear::Vec3{x: 6.0, y: 2.0, z: -13.0}, rad: 2.0};
+    let sph8 = scene::Sphere{mat: assets.material("metal", &material::METAL_TESTING), pos: linear::Vec3{x: -5.0, y: 1.0, z: -3.0}, rad: 1.0};
+    let sph9 = scene::Sphere{mat: assets.material("purple", &material::PURPLE_TESTING), pos: linear::Vec3{x: -1.0, y: 0.3, z: -2.0}, rad: 0.3};
+    let light = scene::Sphere{mat: assets.material("light", &material::LIGHT_TESTING), pos: linear::Vec3{x: 0.0, y: 10.0, z: -6.0}, rad: 2.0};
+    let back_face = scene::Face{d: -15.0, facing: scene::FaceAxis::FaceZ, mat: assets.material("green", &material::GREEN_TESTING), w1: -10.0, w2: 10.0, h1: 0.0, h2: 10.0};
+    let right_face = scene::Face{d: -8.0, facing: scene::FaceAxis::FaceX, mat: assets.material("red", &material::RED_TESTING), w1: 0.0, w2: 10.0, h1: -16.0, h2: 10.0};
+    let left_face = scene::Face{d: 8.0, facing: scene::FaceAxis::FaceX, mat: assets.material("blue", &material::BLUE_TESTING), w1: 0.0, w2: 10.0, h1: -16.0, h2: 10.0};
+    let floor_face = scene::Face{d: -0.0001, facing: scene::FaceAxis::FaceY, mat: assets.material("white", &material::WHITE_TESTING), w1: -15.0, w2: 10.0, h1: -8.0, h2: 8.0};
+    let ceiling_face = scene::Face{d: 10.0, facing: scene::FaceAxis::FaceY, mat: assets.material("white", &material::WHITE_TESTING), w1: -15.0, w2: 10.0, h1: -8.0, h2: 8.0};
+    let mut cam = scenes::camera(options);
 
     let ptr = linear::Vec3{x: 0.0, y: 2.0, z: -6.0};
     cam.translate(&linear::Vec3 {x: 0.0, y: 3.0, z: 6.0});
@@ -81,16 +91,12 @@ fn do_render(width: u32, height: u32, samples: u16) -> Vec<linear::Vec3<f64>> {
     cam.scene.objects.push(&sph7);
     cam.scene.objects.push(&sph8);
     cam.scene.objects.push(&sph9);
-    // cam.scene.objects.push(&blue_light);
-    // cam.scene.objects.push(&red_light);
-    // cam.scene.objects.push(&earth);
     cam.scene.objects.push(&back_face);
     cam.scene.objects.push(&right_face);
     cam.scene.objects.push(&left_face);
     cam.scene.objects.push(&floor_face);
     cam.scene.objects.push(&ceiling_face);
-    
-    cam.scene.lights.push(&light);
+    scenes::surround(&mut cam, assets);
 
     // let mut sph_vec: Vec<scene::Sphere> = Vec::new();
     // let mut seed = oorandom::Rand64::new(915321); //915321 299323422
@@ -115,8 +121,26 @@ fn do_render(width: u32, height: u32, samples: u16) -> Vec<linear::Vec3<f64>> {
     //     cam.scene.objects.push(sphr);
     // }
 
-    cam.shoot(width, height, samples);
-    cam.film
+    cam.shoot(options.width, options.height, samples);
+    Ok(cam.film)
+}
+
+// Value given after a flag, as in --scene studio.
+fn flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1)).map(|s| s.as_str())
+}
+
+// Number given after a flag, or the default without one. Exits on a value
+// that is not a number.
+fn number(args: &[String], name: &str, default: f64) -> f64 {
+    match flag(args, name).map(|value| (value, value.parse())) {
+        Some((_, Ok(n))) => n,
+        Some((value, Err(_))) => {
+            eprintln!("Bad number \"{}\" for {}.", value, name);
+            std::process::exit(1);
+        },
+        None => default,
+    }
 }
 
 fn main() {
@@ -125,24 +149,89 @@ fn main() {
     let mut height: u32 = 360;
     
     let args: Vec<String> = args().collect();
+    let spectral = args.iter().any(|arg| arg == "--spectral");
     if args.len() >= 2 {
-        let tmp = args[1].parse();
-        if tmp.is_ok() {
-            samples = tmp.unwrap();
+        if let Ok(tmp) = args[1].parse() {
+            samples = tmp;
         }
     }
     if args.len() >= 4 {
-        let tmp = args[2].parse();
-        if tmp.is_ok() {
-            width = tmp.unwrap();
+        if let Ok(tmp) = args[2].parse() {
+            width = tmp;
         }
-        let tmp = args[3].parse();
-        if tmp.is_ok() {
-            height = tmp.unwrap();
+        if let Ok(tmp) = args[3].parse() {
+            height = tmp;
         }
     }
     
-    let mut handles: Vec<thread::JoinHandle<Vec<linear::Vec3<f64>>>> = Vec::new();
+    let scene = match flag(&args, "--scene") {
+        Some(name) => match scenes::Showcase::parse(name) {
+            Some(scene) => scene,
+            None => {
+                eprintln!("Unknown scene \"{}\".", name);
+                std::process::exit(1);
+            }
+        },
+        None => scenes::Showcase::Studio,
+    };
+    let lights = match flag(&args, "--lights") {
+        Some(name) => match scene::LightStrategy::parse(name) {
+            Some(lights) => lights,
+            None => {
+                eprintln!("Unknown light strategy \"{}\".", name);
+                std::process::exit(1);
+            }
+        },
+        None => scene::LightStrategy::Bvh,
+    };
+    let texture_wrap = match flag(&args, "--texture-wrap") {
+        Some(name) => match texture::Wrap::parse(name) {
+            Some(wrap) => wrap,
+            None => {
+                eprintln!("Unknown texture wrap \"{}\".", name);
+                std::process::exit(1);
+            }
+        },
+        None => texture::Wrap::Repeat,
+    };
+    let texture_filter = match flag(&args, "--texture-filter") {
+        Some(name) => match texture::Filter::parse(name) {
+            Some(filter) => filter,
+            None => {
+                eprintln!("Unknown texture filter \"{}\".", name);
+                std::process::exit(1);
+            }
+        },
+        None => texture::Filter::Mipmap,
+    };
+    let ortho = match flag(&args, "--ortho").map(|view| (view, view.parse::<f64>())) {
+        Some((_, Ok(view))) if view > 0.0 => Some(view),
+        Some((view, _)) => {
+            eprintln!("Bad orthographic view width \"{}\".", view);
+            std::process::exit(1);
+        },
+        None => None,
+    };
+    let options = scenes::Options {
+        width, height, spectral, scene, lights, ortho, texture_wrap, texture_filter,
+        spd: flag(&args, "--spd").map(String::from),
+        env: flag(&args, "--env").map(|paths| paths.split(',').map(String::from).collect()),
+        env_rotate: number(&args, "--env-rotate", 0.0),
+        env_intensity: number(&args, "--env-intensity", 1.0),
+        ies: flag(&args, "--ies").map(String::from),
+        brdf: flag(&args, "--brdf").map(String::from),
+        library: flag(&args, "--library").map(String::from),
+        texture: flag(&args, "--texture").map(String::from),
+        normal_map: flag(&args, "--normal-map").map(String::from),
+    };
+    let assets = match scenes::Assets::load(&options) {
+        Ok(assets) => assets,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     println!("P3");
     println!("{} {}", width, height);
     println!("255");
@@ -151,32 +240,40 @@ fn main() {
     
     let sub = samples / 12;
     let rem = samples.rem(12);
-    for i in 0..rem {
-        handles.push(
-            thread::Builder::new()
-            .name(i.to_string())
-            .spawn(move || { do_render(width, height, sub + 1) })
-            .unwrap()
-        );
-    }
-    if sub != 0 {
-        for i in 0..(12 - rem) {
+    let films: Result<Vec<Vec<linear::Vec3<f64>>>, String> = thread::scope(|s| {
+        let mut handles: Vec<thread::ScopedJoinHandle<Result<Vec<linear::Vec3<f64>>, String>>> = Vec::new();
+        for i in 0..rem {
             handles.push(
                 thread::Builder::new()
-                .name((i + rem).to_string())
-                .spawn(move || { do_render(width, height, sub) })
+                .name(i.to_string())
+                .spawn_scoped(s, || { do_render(&options, &assets, sub + 1) })
                 .unwrap()
             );
         }
-    }
-    
-    let mut films: Vec<Vec<linear::Vec3<f64>>> = Vec::new();
-
-    for handle in handles {
-        films.push(handle.join().unwrap());
-    }
+        if sub != 0 {
+            for i in 0..(12 - rem) {
+                handles.push(
+                    thread::Builder::new()
+                    .name((i + rem).to_string())
+                    .spawn_scoped(s, || { do_render(&options, &assets, sub) })
+                    .unwrap()
+                );
+            }
+        }
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
 
+    let films = match films {
+        Ok(films) => films,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
     develop(width, height, &films);
+    if let Some(library) = &assets.library {
+        eprint!("{}", library.report());
+    }
     match now.elapsed() {
         Ok(elapsed) => eprintln!("Seconds to render: {}", (elapsed.as_millis() as f64) / 1000.0),
         Err(_elapsed) => eprintln!("Error getting time.")
@@ -1,69 +1,482 @@
+use std::f64::consts::{FRAC_1_PI, PI};
+use std::sync::OnceLock;
+
 use crate::linear;
+use crate::medium;
+use crate::microfacet;
+use crate::ray;
+use crate::sampling;
 use crate::scene;
+use crate::spectrum;
+use crate::texture;
+use crate::texture::{Color, Scalar};
 
-pub const BLANK: Lambert = Lambert {color: linear::Vec3 {x: 0.0, y: 0.0, z: 0.0}};
-pub const RED_TESTING: Lambert = Lambert { color: linear::Vec3 {x: 0.5, y: 0.2, z: 0.1}};
-pub const GREEN_TESTING: Lambert = Lambert { color: linear::Vec3 {x: 0.1, y: 0.5, z: 0.2} };
-pub const BLUE_TESTING: Lambert = Lambert { color: linear::Vec3 {x: 0.1, y: 0.1, z: 0.5} };
-pub const PURPLE_TESTING: Lambert = Lambert { color: linear::Vec3 {x: 0.5, y: 0.1, z: 0.5} };
-pub const WHITE_TESTING: Lambert = Lambert { color: linear::Vec3 {x: 0.5, y: 0.5, z: 0.5} };
-pub const MIRROR_TESTING: Specular = Specular { color: linear::Vec3 {x: 0.97, y: 0.98, z: 0.97}, roughness: 0.0 };
-pub const METAL_TESTING: Specular = Specular { color: linear::Vec3 {x: 0.97, y: 0.98, z: 0.97}, roughness: 0.3 };
-pub const GOLD_TESTING: Specular = Specular { color: linear::Vec3 {x: 0.98, y: 0.7, z: 0.1}, roughness: 0.7 };
-pub const GLASS_TESTING: Dielectric = Dielectric { color: linear::Vec3 {x: 0.97, y: 0.98, z: 0.97}, eta: 1.3};
-pub const LIGHT_TESTING: Emitter = Emitter { color: linear::Vec3 {x: 9.0, y: 9.0, z: 9.0} };
-pub const REDL_TESTING: Emitter = Emitter { color: linear::Vec3 {x: 16.0, y: 0.6, z: 1.6} };
-pub const BLUEL_TESTING: Emitter = Emitter { color: linear::Vec3 {x: 1.0, y: 4.4, z: 16.0} };
-
-pub trait Material {
-    fn bsdf(&self, incident: f64, exitant: f64) -> linear::Vec3<f64>;
-    fn sample(&self, incident: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> linear::Vec3<f64>;
-    fn pdf(&self, exitant: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> f64;
-}
-
-pub struct Lambert {
-    pub color: linear::Vec3<f64>,
+pub const BLANK: Lambert = Lambert { color: Color::Constant(linear::Vec3 {x: 0.0, y: 0.0, z: 0.0}) };
+pub const RED_TESTING: Lambert = Lambert { color: Color::Constant(linear::Vec3 {x: 0.5, y: 0.2, z: 0.1}) };
+pub const GREEN_TESTING: Lambert = Lambert { color: Color::Constant(linear::Vec3 {x: 0.1, y: 0.5, z: 0.2}) };
+pub const BLUE_TESTING: Lambert = Lambert { color: Color::Constant(linear::Vec3 {x: 0.1, y: 0.1, z: 0.5}) };
+pub const PURPLE_TESTING: Lambert = Lambert { color: Color::Constant(linear::Vec3 {x: 0.5, y: 0.1, z: 0.5}) };
+pub const WHITE_TESTING: Lambert = Lambert { color: Color::Constant(linear::Vec3 {x: 0.5, y: 0.5, z: 0.5}) };
+pub const MIRROR_TESTING: Specular = Specular { color: Color::Constant(linear::Vec3 {x: 0.97, y: 0.98, z: 0.97}), roughness: 0.0 };
+pub const METAL_TESTING: Specular = Specular { color: Color::Constant(linear::Vec3 {x: 0.97, y: 0.98, z: 0.97}), roughness: 0.3 };
+pub const GOLD_TESTING: Specular = Specular { color: Color::Constant(linear::Vec3 {x: 0.98, y: 0.7, z: 0.1}), roughness: 0.7 };
+pub const BRUSHED_TESTING: Conductor = Conductor { roughness: Scalar::Constant(0.3), anisotropy: Scalar::Constant(0.6), ..ALUMINIUM };
+pub const GILT_TESTING: Conductor = Conductor { roughness: Scalar::Constant(0.4), ..GOLD };
+pub const GLASS_TESTING: Dielectric = Dielectric { color: Color::Constant(linear::Vec3 {x: 0.97, y: 0.98, z: 0.97}), eta: spectrum::Ior::Constant(1.3), roughness: Scalar::Constant(0.0), thin: false, film: None, absorption: None };
+pub const PRISM_TESTING: Dielectric = Dielectric { color: Color::Constant(linear::Vec3 {x: 0.97, y: 0.98, z: 0.97}), eta: spectrum::SF11, roughness: Scalar::Constant(0.0), thin: false, film: None, absorption: None };
+pub const CROWN_TESTING: Dielectric = Dielectric { eta: spectrum::BK7, ..PRISM_TESTING };
+pub const SILICA_TESTING: Dielectric = Dielectric { eta: spectrum::FUSED_SILICA, ..PRISM_TESTING };
+
+// Smooth metals, with complex indices of refraction sampled at the red,
+// green and blue primaries. Set roughness with struct update syntax.
+pub const GOLD: Conductor = Conductor { eta: linear::Vec3 {x: 0.18299, y: 0.42108, z: 1.3734}, k: linear::Vec3 {x: 3.4242, y: 2.3459, z: 1.7704}, roughness: Scalar::Constant(0.0), anisotropy: Scalar::Constant(0.0), film: None };
+pub const COPPER: Conductor = Conductor { eta: linear::Vec3 {x: 0.27105, y: 0.67693, z: 1.3164}, k: linear::Vec3 {x: 3.6092, y: 2.6248, z: 2.2921}, roughness: Scalar::Constant(0.0), anisotropy: Scalar::Constant(0.0), film: None };
+pub const SILVER: Conductor = Conductor { eta: linear::Vec3 {x: 0.15943, y: 0.14512, z: 0.13547}, k: linear::Vec3 {x: 3.9291, y: 3.19, z: 2.3808}, roughness: Scalar::Constant(0.0), anisotropy: Scalar::Constant(0.0), film: None };
+pub const ALUMINIUM: Conductor = Conductor { eta: linear::Vec3 {x: 1.6574, y: 0.88036, z: 0.5212}, k: linear::Vec3 {x: 9.2238, y: 6.2695, z: 4.837}, roughness: Scalar::Constant(0.0), anisotropy: Scalar::Constant(0.0), film: None };
+
+// Principled material defaults, to start from with struct update syntax.
+pub const PRINCIPLED: Principled = Principled {
+    color: Color::Constant(linear::Vec3 {x: 0.8, y: 0.8, z: 0.8}), metallic: Scalar::Constant(0.0),
+    roughness: Scalar::Constant(0.5), anisotropy: Scalar::Constant(0.0), specular: Scalar::Constant(0.5),
+    specular_tint: Scalar::Constant(0.0), sheen: Scalar::Constant(0.0), sheen_tint: Scalar::Constant(0.5),
+    clearcoat: Scalar::Constant(0.0), clearcoat_gloss: Scalar::Constant(1.0), transmission: Scalar::Constant(0.0),
+    subsurface: Scalar::Constant(0.0), eta: 1.5,
+};
+
+pub const LIGHT_TESTING: Emitter = Emitter { color: linear::Vec3 {x: 9.0, y: 9.0, z: 9.0}, spd: spectrum::Spd::Rgb, scale: 1.0, two_sided: false, texture: None, falloff: 0.0 };
+
+// Materials are queried with the incoming ray, so that colors can be returned
+// in whatever space the ray carries (RGB, or sampled wavelengths). Exitant
+// directions point away from the surface. Materials loaded from files are
+// shared by the render threads, so they must be Sync.
+pub trait Material: Sync {
+    // Value of the BSDF for light scattered from the exitant direction back along the ray.
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64>;
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter>;
+    // Solid angle density with which sample() would produce the exitant direction.
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64;
+
+    fn emit(&self, _ray: &ray::Ray, _inter: &scene::Intersection) -> linear::Vec3<f64> {
+        scene::BLACK.copy()
+    }
+
+    fn emissive(&self) -> bool {
+        false
+    }
+
+    // Luminance emitted per unit area, summed over the sides that emit.
+    fn power(&self) -> f64 {
+        0.0
+    }
+
+    // Whether scattering depends on wavelength, which ends secondary wavelengths.
+    fn dispersive(&self) -> bool {
+        false
+    }
+
+    // Whether light passes through, so that back faces are hit.
+    fn transmissive(&self) -> bool {
+        false
+    }
+
+    // Whether rays hit the back of the surface as well as the front.
+    fn two_sided(&self) -> bool {
+        self.transmissive()
+    }
+
+    // Medium the surface encloses, if light enters it.
+    fn medium(&self, _ray: &ray::Ray, _inter: &scene::Intersection) -> Option<medium::Medium> {
+        None
+    }
+
+    // Diffusion profile for light going under the surface, if it does.
+    fn diffusion(&self, _ray: &ray::Ray, _inter: &scene::Intersection) -> Option<medium::Diffusion> {
+        None
+    }
+
+    // Normal to shade the hit with, for materials that bend it.
+    fn shading_normal(&self, _inter: &scene::Intersection) -> Option<linear::Vec3<f64>> {
+        None
+    }
+
+    // Whether the surface is there at the hit, or cut out for rays to pass.
+    fn opaque(&self, _inter: &scene::Intersection) -> bool {
+        true
+    }
 }
 
-pub struct Specular {
-    pub color: linear::Vec3<f64>,
+// Implements the named Material methods by handing them to self.base, for
+// materials that wrap another and change only some of what it does.
+macro_rules! forward_to_base {
+    ($($method:ident),* $(,)?) => {
+        $(forward_to_base!(@ $method);)*
+    };
+    (@ bsdf) => {
+        fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+            self.base.bsdf(ray, inter, exitant)
+        }
+    };
+    (@ sample) => {
+        fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+            self.base.sample(ray, inter)
+        }
+    };
+    (@ pdf) => {
+        fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+            self.base.pdf(ray, inter, exitant)
+        }
+    };
+    (@ emit) => {
+        fn emit(&self, ray: &ray::Ray, inter: &scene::Intersection) -> linear::Vec3<f64> {
+            self.base.emit(ray, inter)
+        }
+    };
+    (@ emissive) => {
+        fn emissive(&self) -> bool {
+            self.base.emissive()
+        }
+    };
+    (@ power) => {
+        fn power(&self) -> f64 {
+            self.base.power()
+        }
+    };
+    (@ dispersive) => {
+        fn dispersive(&self) -> bool {
+            self.base.dispersive()
+        }
+    };
+    (@ transmissive) => {
+        fn transmissive(&self) -> bool {
+            self.base.transmissive()
+        }
+    };
+    (@ two_sided) => {
+        fn two_sided(&self) -> bool {
+            self.base.two_sided()
+        }
+    };
+    (@ medium) => {
+        fn medium(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<medium::Medium> {
+            self.base.medium(ray, inter)
+        }
+    };
+    (@ diffusion) => {
+        fn diffusion(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<medium::Diffusion> {
+            self.base.diffusion(ray, inter)
+        }
+    };
+    (@ shading_normal) => {
+        fn shading_normal(&self, inter: &scene::Intersection) -> Option<linear::Vec3<f64>> {
+            self.base.shading_normal(inter)
+        }
+    };
+    (@ opaque) => {
+        fn opaque(&self, inter: &scene::Intersection) -> bool {
+            self.base.opaque(inter)
+        }
+    };
+}
+
+// A sampled scattering direction. The weight is bsdf * cos / pdf, and a pdf
+// of zero marks a specular lobe, which light sampling cannot reach.
+pub struct Scatter {
+    pub dir: linear::Vec3<f64>,
+    pub weight: linear::Vec3<f64>,
+    pub pdf: f64,
+}
+
+pub struct Lambert<'a> {
+    pub color: Color<'a>,
+}
+
+// Rough diffuse surface (Oren and Nayar 1994) with sigma the standard
+// deviation of facet slopes in radians, zero being Lambertian. A retro weight
+// above zero blends in a lobe scattering back towards the viewer, as from
+// road signs or the moon, narrower for larger exponents.
+pub struct OrenNayar<'a> {
+    pub color: Color<'a>,
+    pub sigma: Scalar<'a>,
+    pub retro: f64,
+    pub retro_exponent: f64,
+}
+
+pub struct Specular<'a> {
+    pub color: Color<'a>,
     pub roughness: f64,
 }
 
-pub struct Dielectric {
-    pub color: linear::Vec3<f64>,
+// Thin transparent film of index eta over a surface, thickness in
+// nanometers, like oil on water, the wall of a soap bubble or the oxide on
+// anodized metal. Light bouncing inside it interferes into shifting colors.
+pub struct Film {
+    pub thickness: f64,
+    pub eta: f64,
+}
+
+// Light absorbed inside a material, given as the share of each channel left
+// after travelling the reference distance through it, in scene units.
+pub struct Absorption {
+    pub transmittance: linear::Vec3<f64>,
+    pub distance: f64,
+}
+
+// Metal with a GGX microfacet surface. Roughness is perceptual (squared
+// into alpha) and anisotropy in [0, 1) stretches the highlight along the
+// surface tangent. An optional film covers it.
+pub struct Conductor<'a> {
+    pub eta: linear::Vec3<f64>,
+    pub k: linear::Vec3<f64>,
+    pub roughness: Scalar<'a>,
+    pub anisotropy: Scalar<'a>,
+    pub film: Option<Film>,
+}
+
+// Glass and other clear materials, with a GGX rough surface above zero
+// roughness. Thin walled surfaces stand for a sheet of the material, like a
+// window pane or a bubble, which light leaves with its direction unchanged.
+// An optional film covers the surface. On a thin walled surface the film is
+// the sheet itself, so a soap bubble is a film over a sheet of index one.
+// Color tints the surface at every bounce, while absorption darkens light by
+// how far it goes inside, so thick glass shows a deeper color than thin.
+// Thin walled surfaces have no inside to absorb.
+pub struct Dielectric<'a> {
+    pub color: Color<'a>,
+    pub eta: spectrum::Ior,
+    pub roughness: Scalar<'a>,
+    pub thin: bool,
+    pub film: Option<Film>,
+    pub absorption: Option<Absorption>,
+}
+
+// Artist friendly material after the Disney BRDF (Burley 2012, 2015). Weights
+// are in [0, 1]: metallic blends to a tinted conductor, transmission to rough
+// glass of index eta, and subsurface flattens the diffuse lobe. Specular sets
+// the reflectance of the dielectric part, 0.5 being 4%.
+pub struct Principled<'a> {
+    pub color: Color<'a>,
+    pub metallic: Scalar<'a>,
+    pub roughness: Scalar<'a>,
+    pub anisotropy: Scalar<'a>,
+    pub specular: Scalar<'a>,
+    pub specular_tint: Scalar<'a>,
+    pub sheen: Scalar<'a>,
+    pub sheen_tint: Scalar<'a>,
+    pub clearcoat: Scalar<'a>,
+    pub clearcoat_gloss: Scalar<'a>,
+    pub transmission: Scalar<'a>,
+    pub subsurface: Scalar<'a>,
+    pub eta: f64,
+}
+
+// Parameters of the principled material looked up at a shading point.
+struct PrincipledAt {
+    color: linear::Vec3<f64>,
+    metallic: f64,
+    roughness: f64,
+    anisotropy: f64,
+    specular: f64,
+    specular_tint: f64,
+    sheen: f64,
+    sheen_tint: f64,
+    clearcoat: f64,
+    clearcoat_gloss: f64,
+    transmission: f64,
+    subsurface: f64,
+    eta: f64,
+}
+
+// A clear coat of index eta over another material, like varnish or the
+// lacquer on car paint. The coat is smooth at zero roughness, else GGX, and
+// tint is the color seen through it head on, for a coat that absorbs.
+// Light either reflects off the coat or refracts through to the base and
+// back out, one of the two chosen at random when sampling.
+pub struct Coated<'a> {
+    pub base: &'a dyn Material,
+    pub eta: f64,
+    pub roughness: Scalar<'a>,
+    pub tint: Color<'a>,
+}
+
+// How much of the second material a Mix shows.
+pub enum MixWeight<'a> {
+    Constant(f64),
+    Texture(&'a dyn texture::Texture),
+    // Dielectric Fresnel reflectance for this index, so the second material
+    // shows at grazing angles.
+    Fresnel(f64),
+}
+
+// Blend of two materials, like rust patches on metal or worn paint. Sampling
+// picks one material by the weight and evaluates the blend of both.
+pub struct Mix<'a> {
+    pub a: &'a dyn Material,
+    pub b: &'a dyn Material,
+    pub weight: MixWeight<'a>,
+}
+
+// Sheen of fibers standing up from a surface, like velvet or cloth, over
+// another material. Color is the sheen's reflectance, and roughness spreads
+// it from a rim at grazing angles to a soft haze over the whole surface.
+pub struct Sheen<'a> {
+    pub base: &'a dyn Material,
+    pub color: Color<'a>,
+    pub roughness: Scalar<'a>,
+}
+
+pub enum SubsurfaceMethod {
+    // Follows light scattering through the volume inside a closed surface.
+    RandomWalk,
+    // Spreads light entering the surface over the nearby surface by a
+    // diffusion profile. Cheaper, and works on open surfaces too.
+    Diffusion,
+}
+
+// Translucent material like skin, wax or marble. Color is the albedo seen on
+// the surface and mfp the mean free path of light inside, per channel in
+// scene units, so red light going deepest into skin has the largest value.
+// The surface is a dielectric of index eta and the given roughness.
+pub struct Subsurface<'a> {
+    pub color: Color<'a>,
+    pub mfp: linear::Vec3<f64>,
     pub eta: f64,
+    pub roughness: Scalar<'a>,
+    pub method: SubsurfaceMethod,
+}
+
+// How a Bumped material bends the normal.
+pub enum Bump<'a> {
+    // Tangent space normals stored as colors, with red along u, green along v
+    // and blue out of the surface. Strength scales the tilt.
+    Normal(&'a dyn texture::Texture, f64),
+    // Heights above the surface, read as scalars and times the scale in
+    // scene units.
+    Height(&'a dyn texture::Texture, f64),
+}
+
+// Another material shaded with its normal bent by a map, for detail too fine
+// to model like brick mortar, scratches or grain. Only the shading changes,
+// so silhouettes stay smooth.
+pub struct Bumped<'a> {
+    pub base: &'a dyn Material,
+    pub bump: Bump<'a>,
+}
+
+// Another material with holes where the mask reads below one half, like
+// leaves drawn on a card or a chain-link fence on a quad. Rays pass through
+// the holes when finding hits and shadows alike.
+pub struct Cutout<'a> {
+    pub base: &'a dyn Material,
+    pub mask: &'a dyn texture::Texture,
+}
+
+// Emission is either an RGB color, or a spectral distribution times scale
+// with color holding its RGB equivalent for non-spectral renders. Surfaces
+// emit from the front only unless two sided, optionally times a texture
+// like a screen or a sign. Falloff is the power of the cosine to the normal
+// emission follows, zero being Lambertian and larger values narrowing it
+// towards the normal like a directional panel.
+pub struct Emitter<'a> {
+    pub color: linear::Vec3<f64>,
+    pub spd: spectrum::Spd,
+    pub scale: f64,
+    pub two_sided: bool,
+    pub texture: Option<&'a dyn texture::Texture>,
+    pub falloff: f64,
+}
+
+impl<'a> Material for Lambert<'a> {
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        if exitant * &inter.norm <= 0.0 {
+            return scene::BLACK.copy();
+        }
+        ray.reflectance(&self.color.at(inter)) * FRAC_1_PI
+    }
+
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        let local = sampling::cosine_hemisphere(rand::random(), rand::random());
+        let dir = linear::Frame::new(&inter.norm).to_world(&local);
+        Some(Scatter {dir, weight: ray.reflectance(&self.color.at(inter)), pdf: local.z * FRAC_1_PI})
+    }
+
+    fn pdf(&self, _ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        (exitant * &inter.norm).max(0.0) * FRAC_1_PI
+    }
 }
 
-pub struct Emitter {
-    pub color: linear::Vec3<f64>
+impl<'a> OrenNayar<'a> {
+    // Value in the local frame with wo and wi above the surface, relative to color.
+    fn eval(&self, sigma: f64, wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>) -> f64 {
+        let s2 = sigma * sigma;
+        let a = 1.0 - s2 / (2.0 * (s2 + 0.33));
+        let b = 0.45 * s2 / (s2 + 0.09);
+        let sin_o = (1.0 - wo.z * wo.z).max(0.0).sqrt();
+        let sin_i = (1.0 - wi.z * wi.z).max(0.0).sqrt();
+        let mut rough = 0.0;
+        if sin_o > 1e-4 && sin_i > 1e-4 {
+            let cos_phi = ((wi.x * wo.x + wi.y * wo.y) / (sin_i * sin_o)).max(0.0);
+            let (sin_alpha, tan_beta) = if wi.z > wo.z { (sin_o, sin_i / wi.z) } else { (sin_i, sin_o / wo.z) };
+            rough = b * cos_phi * sin_alpha * tan_beta;
+        }
+        let n = self.retro_exponent;
+        let retro = (n + 2.0) * 0.5 * FRAC_1_PI * (wo * wi).max(0.0).powf(n);
+        (1.0 - self.retro) * FRAC_1_PI * (a + rough) + self.retro * retro
+    }
+
+    fn density(&self, wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>) -> f64 {
+        let n = self.retro_exponent;
+        let retro = (n + 1.0) * 0.5 * FRAC_1_PI * (wo * wi).max(0.0).powf(n);
+        (1.0 - self.retro) * wi.z * FRAC_1_PI + self.retro * retro
+    }
 }
 
-impl Material for Lambert {
-    fn bsdf(&self, incident: f64, exitant: f64) -> linear::Vec3<f64> {
-        self.color.copy()
+impl<'a> Material for OrenNayar<'a> {
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let frame = linear::Frame::new(&inter.norm);
+        let (wo, wi) = (frame.to_local(&-&ray.traj), frame.to_local(exitant));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return scene::BLACK.copy();
+        }
+        ray.reflectance(&self.color.at(inter)) * self.eval(self.sigma.at(inter), &wo, &wi)
     }
 
-    fn sample(&self, incident: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> linear::Vec3<f64> {
-        let mut vec: linear::Vec3<f64> = linear::Vec3::<f64>::rand(1.0); 
-        while vec.norm() > 1.0 {
-            vec = linear::Vec3::<f64>::rand(1.0); 
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        let frame = linear::Frame::new(&inter.norm);
+        let wo = frame.to_local(&-&ray.traj);
+        let wi = if self.retro > rand::random() {
+            let cos = rand::random::<f64>().powf(1.0 / (self.retro_exponent + 1.0));
+            let sin = (1.0 - cos * cos).max(0.0).sqrt();
+            let phi = 2.0 * PI * rand::random::<f64>();
+            linear::Frame::new(&wo).to_world(&linear::Vec3 {x: sin * phi.cos(), y: sin * phi.sin(), z: cos})
+        } else {
+            sampling::cosine_hemisphere(rand::random(), rand::random())
+        };
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return None;
         }
-        norm + &vec
+        let pdf = self.density(&wo, &wi);
+        let weight = ray.reflectance(&self.color.at(inter)) * (self.eval(self.sigma.at(inter), &wo, &wi) * wi.z / pdf);
+        Some(Scatter {dir: frame.to_world(&wi), weight, pdf})
     }
 
-    fn pdf(&self, exitant: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> f64 {
-        1.0 / 6.28318
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        let frame = linear::Frame::new(&inter.norm);
+        let (wo, wi) = (frame.to_local(&-&ray.traj), frame.to_local(exitant));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        self.density(&wo, &wi)
     }
 }
 
-impl Material for Specular {
-    fn bsdf(&self, incident: f64, exitant: f64) -> linear::Vec3<f64> {
-        self.color.copy()
+impl<'a> Material for Specular<'a> {
+    fn bsdf(&self, _ray: &ray::Ray, _inter: &scene::Intersection, _exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        scene::BLACK.copy()
     }
     
-    fn sample(&self, incident: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> linear::Vec3<f64> {
-        let refl = (-incident).reflect(norm);
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        let norm = &inter.norm;
+        let refl = (-&ray.traj).reflect(norm);
         let mut vec = linear::Vec3::new();
         if self.roughness != 0.0 {
             vec = linear::Vec3::<f64>::rand(self.roughness); 
@@ -73,58 +486,1046 @@ impl Material for Specular {
         }
         let res = refl + vec;
         if &res * norm <= 0.0 {
-            return scene::BLACK.copy();
+            None
         } else {
-            return res;
+            Some(Scatter {dir: res.normalize(), weight: ray.reflectance(&self.color.at(inter)), pdf: 0.0})
         }
     }
 
-    fn pdf(&self, exitant: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> f64 {
+    fn pdf(&self, _ray: &ray::Ray, _inter: &scene::Intersection, _exitant: &linear::Vec3<f64>) -> f64 {
         0.0
     }
 }
 
-impl Material for Dielectric {
-    fn bsdf(&self, incident: f64, exitant: f64) -> linear::Vec3<f64> {
-        self.color.copy()
+impl Absorption {
+    // Absorption coefficient per unit length, in the ray's color space.
+    fn coefficient(&self, ray: &ray::Ray) -> linear::Vec3<f64> {
+        let t = ray.reflectance(&self.transmittance);
+        let sigma = |t: f64| -t.clamp(1e-6, 1.0).ln() / self.distance.max(1e-6);
+        linear::Vec3 {x: sigma(t.x), y: sigma(t.y), z: sigma(t.z)}
     }
-    
-    fn sample(&self, incident: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> linear::Vec3<f64> {
-        let cos = &-incident * norm;
-        let ratio = if cos >= 0.0 { self.eta.recip() } else { self.eta };
-        if ratio * cos.acos().sin() > 1.0 || Dielectric::schlick(cos, ratio) > rand::random() {
-            (-incident).reflect(norm)
+}
+
+// Mean over the channels, for choosing between lobes of colored weight.
+fn mean(v: &linear::Vec3<f64>) -> f64 {
+    (v.x + v.y + v.z) / 3.0
+}
+
+impl<'a> Conductor<'a> {
+    // Complex index at a wavelength, interpolated between the primaries.
+    fn index_at(&self, lambda: f64) -> (f64, f64) {
+        let at = |v: &linear::Vec3<f64>| {
+            if lambda >= 630.0 {
+                v.x
+            } else if lambda >= 532.0 {
+                let t = (lambda - 532.0) / 98.0;
+                v.y * (1.0 - t) + v.x * t
+            } else if lambda >= 465.0 {
+                let t = (lambda - 465.0) / 67.0;
+                v.z * (1.0 - t) + v.y * t
+            } else {
+                v.z
+            }
+        };
+        (at(&self.eta), at(&self.k))
+    }
+
+    fn fresnel(&self, ray: &ray::Ray, cos: f64) -> linear::Vec3<f64> {
+        if let Some(film) = &self.film {
+            return ray.spectrum(|l| {
+                let (eta, k) = self.index_at(l);
+                microfacet::fresnel_thin_film(cos, film.eta, film.thickness, eta, k, l)
+            });
+        }
+        ray.reflectance(&linear::Vec3 {
+            x: microfacet::fresnel_conductor(cos, self.eta.x, self.k.x),
+            y: microfacet::fresnel_conductor(cos, self.eta.y, self.k.y),
+            z: microfacet::fresnel_conductor(cos, self.eta.z, self.k.z),
+        })
+    }
+}
+
+impl<'a> Material for Conductor<'a> {
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), self.anisotropy.at(inter));
+        let frame = linear::Frame::new(&inter.norm);
+        let wo = frame.to_local(&-&ray.traj);
+        let wi = frame.to_local(exitant);
+        if ggx.smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return scene::BLACK.copy();
+        }
+        let wm = (&wo + &wi).normalize();
+        self.fresnel(ray, &wi * &wm) * (ggx.d(&wm) * ggx.g(&wo, &wi) / (4.0 * wo.z * wi.z))
+    }
+
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), self.anisotropy.at(inter));
+        let frame = linear::Frame::new(&inter.norm);
+        let wo = frame.to_local(&-&ray.traj);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        if ggx.smooth() {
+            let wi = linear::Vec3 {x: -wo.x, y: -wo.y, z: wo.z};
+            return Some(Scatter {dir: frame.to_world(&wi), weight: self.fresnel(ray, wo.z), pdf: 0.0});
+        }
+        let wm = ggx.sample_visible(&wo, rand::random(), rand::random());
+        let wi = microfacet::reflect(&wo, &wm);
+        if wi.z <= 0.0 {
+            return None;
+        }
+        let pdf = ggx.pdf(&wo, &wm) / (4.0 * (&wo * &wm));
+        let weight = self.fresnel(ray, &wo * &wm) * (ggx.g(&wo, &wi) / ggx.g1(&wo));
+        Some(Scatter {dir: frame.to_world(&wi), weight, pdf})
+    }
+
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), self.anisotropy.at(inter));
+        let frame = linear::Frame::new(&inter.norm);
+        let wo = frame.to_local(&-&ray.traj);
+        let wi = frame.to_local(exitant);
+        if ggx.smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let wm = (&wo + &wi).normalize();
+        ggx.pdf(&wo, &wm) / (4.0 * (&wo * &wm))
+    }
+}
+
+impl<'a> Material for Dielectric<'a> {
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), 0.0);
+        let b = self.local(ray, inter);
+        if ggx.smooth() || b.wo.z <= 0.0 {
+            return scene::BLACK.copy();
+        }
+        let (f, _) = self.lobes(ray, &b, &ggx, &b.frame.to_local(exitant));
+        ray.reflectance(&self.color.at(inter)).color_prod(&f)
+    }
+
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), 0.0);
+        let b = self.local(ray, inter);
+        let (wo, rel) = (&b.wo, b.rel);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let color = ray.reflectance(&self.color.at(inter));
+        if ggx.smooth() {
+            let f = self.fresnel(ray, &b, wo.z);
+            let r = mean(&f);
+            let (wi, weight) = if r > rand::random() {
+                (linear::Vec3 {x: -wo.x, y: -wo.y, z: wo.z}, color.color_prod(&f) / r)
+            } else if self.thin {
+                (-wo, color.color_prod(&(&scene::WHITE - &f)) / (1.0 - r))
+            } else {
+                (microfacet::refract(wo, &linear::Z, rel)?, color.color_prod(&(&scene::WHITE - &f)) / ((1.0 - r) * rel * rel))
+            };
+            return Some(Scatter {dir: b.frame.to_world(&wi).normalize(), weight, pdf: 0.0});
+        }
+        let wm = ggx.sample_visible(wo, rand::random(), rand::random());
+        let wi = if mean(&self.fresnel(ray, &b, wo * &wm)) > rand::random() {
+            microfacet::reflect(wo, &wm)
+        } else if self.thin {
+            let wr = microfacet::reflect(wo, &wm);
+            linear::Vec3 {x: wr.x, y: wr.y, z: -wr.z}
         } else {
-            (-incident).refract(norm,  ratio)
+            microfacet::refract(wo, &wm, rel)?
+        };
+        let (f, pdf) = self.lobes(ray, &b, &ggx, &wi);
+        if pdf <= 0.0 {
+            return None;
         }
+        Some(Scatter {dir: b.frame.to_world(&wi).normalize(), weight: color.color_prod(&f) * (wi.z.abs() / pdf), pdf})
     }
 
-    fn pdf(&self, exitant: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> f64 {
-        0.0
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), 0.0);
+        let b = self.local(ray, inter);
+        if ggx.smooth() || b.wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.lobes(ray, &b, &ggx, &b.frame.to_local(exitant)).1
+    }
+
+    fn dispersive(&self) -> bool {
+        self.eta.dispersive()
+    }
+
+    fn transmissive(&self) -> bool {
+        true
+    }
+
+    fn medium(&self, ray: &ray::Ray, _inter: &scene::Intersection) -> Option<medium::Medium> {
+        if self.thin {
+            return None;
+        }
+        let eta = self.eta_at(ray);
+        Some(match &self.absorption {
+            Some(a) => medium::Medium {sigma_a: a.coefficient(ray), ..medium::Medium::clear(eta)},
+            None => medium::Medium::clear(eta),
+        })
+    }
+}
+
+// Where a ray meets a dielectric surface.
+struct Boundary {
+    // Shading frame on the side the ray arrives from.
+    frame: linear::Frame,
+    // Direction back along the ray, in that frame.
+    wo: linear::Vec3<f64>,
+    // Index on the far side over the index on the near side.
+    rel: f64,
+    // Index on the near side.
+    incident: f64,
+}
+
+impl<'a> Dielectric<'a> {
+    fn eta_at(&self, ray: &ray::Ray) -> f64 {
+        match &ray.lambda {
+            Some(wl) => self.eta.at(wl.lambda.x),
+            None => self.eta.at(spectrum::LAMBDA_D),
+        }
+    }
+
+    fn local(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Boundary {
+        let eta = self.eta_at(ray);
+        let entering = &ray.traj * &inter.geom < 0.0;
+        let frame = linear::Frame::new(&if entering { inter.norm.copy() } else { -&inter.norm });
+        let wo = frame.to_local(&-&ray.traj);
+        let rel = ray.relative_ior(eta, inter, entering || self.thin);
+        let incident = if entering || self.thin { eta / rel } else { eta };
+        Boundary {frame, wo, rel, incident}
+    }
+
+    // Share of light reflected per channel, counting the bounces inside a
+    // thin sheet.
+    fn fresnel(&self, ray: &ray::Ray, b: &Boundary, cos: f64) -> linear::Vec3<f64> {
+        if let Some(film) = &self.film {
+            let rel = if self.thin { 1.0 } else { b.rel };
+            return ray.spectrum(|l| microfacet::fresnel_thin_film(cos, film.eta / b.incident, film.thickness, rel, 0.0, l / b.incident));
+        }
+        let f = microfacet::fresnel_dielectric(cos, b.rel);
+        let f = if self.thin { 2.0 * f / (1.0 + f) } else { f };
+        linear::Vec3 {x: f, y: f, z: f}
+    }
+
+    // Rough surface BSDF value and sampling density for wi, with wo above
+    // the surface (Walter et al. 2007). Radiance is scaled by the squared
+    // relative index on refraction.
+    fn lobes(&self, ray: &ray::Ray, b: &Boundary, ggx: &microfacet::Ggx, wi: &linear::Vec3<f64>) -> (linear::Vec3<f64>, f64) {
+        let (wo, rel) = (&b.wo, b.rel);
+        if wi.z > 0.0 || self.thin {
+            // Thin sheets transmit the mirror image of a reflection.
+            let wr = linear::Vec3 {x: wi.x, y: wi.y, z: wi.z.abs()};
+            let wm = (wo + &wr).normalize();
+            if &wm * wo <= 0.0 {
+                return (scene::BLACK.copy(), 0.0);
+            }
+            let f = self.fresnel(ray, b, wo * &wm);
+            let p = if wi.z > 0.0 { f } else { &scene::WHITE - &f };
+            let lobe = ggx.d(&wm) * ggx.g(wo, &wr) / (4.0 * wo.z * wr.z);
+            return (&p * lobe, ggx.pdf(wo, &wm) / (4.0 * (wo * &wm)) * mean(&p));
+        }
+        let (f, pdf) = microfacet::transmission(ggx, wo, wi, rel);
+        let wm = (&(wi * rel) + wo).normalize();
+        let cos = (wo * &wm).abs();
+        let through = &scene::WHITE - &self.fresnel(ray, b, cos);
+        // transmission() counts the bare surface's Fresnel term, which a
+        // film replaces.
+        let bare = 1.0 - microfacet::fresnel_dielectric(cos, rel);
+        if bare <= 0.0 {
+            return (scene::BLACK.copy(), 0.0);
+        }
+        (&through * (f / bare), pdf * mean(&through))
+    }
+}
+
+// Lobes of the principled material at a shading point, in RGB.
+struct PrincipledLobes {
+    ggx: microfacet::Ggx,
+    tint: linear::Vec3<f64>,
+    spec0: linear::Vec3<f64>,
+    rel: f64,
+    // Whether the ray arrived on the outside, where the diffuse and coat are.
+    front: bool,
+    // Probabilities of sampling the diffuse, specular, transmission and coat lobes.
+    probs: [f64; 4],
+}
+
+impl<'a> Principled<'a> {
+    fn at(&self, inter: &scene::Intersection) -> PrincipledAt {
+        PrincipledAt {
+            color: self.color.at(inter),
+            metallic: self.metallic.at(inter),
+            roughness: self.roughness.at(inter),
+            anisotropy: self.anisotropy.at(inter),
+            specular: self.specular.at(inter),
+            specular_tint: self.specular_tint.at(inter),
+            sheen: self.sheen.at(inter),
+            sheen_tint: self.sheen_tint.at(inter),
+            clearcoat: self.clearcoat.at(inter),
+            clearcoat_gloss: self.clearcoat_gloss.at(inter),
+            transmission: self.transmission.at(inter),
+            subsurface: self.subsurface.at(inter),
+            eta: self.eta,
+        }
+    }
+}
+
+impl PrincipledAt {
+    // Below this roughness the specular lobes become too sharp to sample well.
+    const MIN_ROUGHNESS: f64 = 0.03;
+
+    fn lobes(&self, ray: &ray::Ray, inter: &scene::Intersection, front: bool) -> PrincipledLobes {
+        let ggx = microfacet::Ggx::new(self.roughness.max(PrincipledAt::MIN_ROUGHNESS), self.anisotropy);
+        let lum = spectrum::luminance(&self.color);
+        let tint = if lum > 0.0 { &self.color / lum } else { scene::WHITE.copy() };
+        let dielectric = &(&scene::WHITE * (1.0 - self.specular_tint)) + &(&tint * self.specular_tint);
+        let spec0 = &(dielectric * (0.08 * self.specular * (1.0 - self.metallic))) + &(&self.color * self.metallic);
+        let rel = ray.relative_ior(self.eta, inter, front);
+
+        let side = if front { 1.0 } else { 0.0 };
+        let diffuse = (1.0 - self.metallic) * (1.0 - self.transmission) * side;
+        let transmit = (1.0 - self.metallic) * self.transmission;
+        let coat = 0.25 * self.clearcoat * side;
+        let specular = spectrum::luminance(&spec0).max(0.25);
+        let total = diffuse + specular + transmit + coat;
+        let probs = [diffuse / total, specular / total, transmit / total, coat / total];
+        PrincipledLobes {ggx, tint, spec0, rel, front, probs}
+    }
+
+    fn coat_alpha(&self) -> f64 {
+        0.1 + (0.001 - 0.1) * self.clearcoat_gloss
+    }
+
+    // BSDF value in RGB with wo above the surface.
+    fn eval(&self, l: &PrincipledLobes, wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        if wi.z < 0.0 {
+            let (f, _) = microfacet::transmission(&l.ggx, wo, wi, l.rel);
+            return &self.color * (f * (1.0 - self.metallic) * self.transmission);
+        }
+        let wh = (wo + wi).normalize();
+        let cos_d = wi * &wh;
+        let fh = microfacet::schlick_weight(cos_d);
+
+        // Schlick Fresnel on the tinted reflectance, with exact Fresnel for
+        // the share of the dielectric that also transmits.
+        let glass = (1.0 - self.metallic) * self.transmission;
+        let schlick = &(&l.spec0 * (1.0 - fh)) + &(&scene::WHITE * fh);
+        let fresnel = &(schlick * (1.0 - glass)) + &(&scene::WHITE * (microfacet::fresnel_dielectric(wo * &wh, l.rel) * glass));
+        let mut f = fresnel * (l.ggx.d(&wh) * l.ggx.g(wo, wi) / (4.0 * wo.z * wi.z));
+        if !l.front {
+            return f;
+        }
+
+        let (fl, fv) = (microfacet::schlick_weight(wi.z), microfacet::schlick_weight(wo.z));
+        let r = self.roughness;
+        let fd90 = 0.5 + 2.0 * r * cos_d * cos_d;
+        let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+        let fss90 = r * cos_d * cos_d;
+        let fss = (1.0 + (fss90 - 1.0) * fl) * (1.0 + (fss90 - 1.0) * fv);
+        let ss = 1.25 * (fss * (1.0 / (wi.z + wo.z) - 0.5) + 0.5);
+        let diffuse = (1.0 - self.metallic) * (1.0 - self.transmission);
+        let base = fd + (ss - fd) * self.subsurface;
+        f = f + &self.color * (base * FRAC_1_PI * diffuse);
+        let sheen = &(&scene::WHITE * (1.0 - self.sheen_tint)) + &(&l.tint * self.sheen_tint);
+        f = f + sheen * (fh * self.sheen * diffuse);
+
+        if self.clearcoat > 0.0 {
+            let coat_f = 0.04 + 0.96 * fh;
+            let coat_g = microfacet::Ggx {alpha_x: 0.25, alpha_y: 0.25};
+            let coat = microfacet::gtr1(wh.z, self.coat_alpha()) * coat_f * coat_g.g1(wo) * coat_g.g1(wi) / (4.0 * wo.z * wi.z);
+            f = f + &scene::WHITE * (0.25 * self.clearcoat * coat);
+        }
+        f
+    }
+
+    fn density(&self, l: &PrincipledLobes, wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>) -> f64 {
+        if wi.z < 0.0 {
+            return l.probs[2] * microfacet::transmission(&l.ggx, wo, wi, l.rel).1;
+        }
+        let wh = (wo + wi).normalize();
+        let spec = l.ggx.pdf(wo, &wh) / (4.0 * (wo * &wh));
+        let coat = microfacet::gtr1(wh.z, self.coat_alpha()) * wh.z / (4.0 * (wo * &wh));
+        l.probs[0] * wi.z * FRAC_1_PI + l.probs[1] * spec + l.probs[3] * coat
+    }
+
+    fn local(&self, ray: &ray::Ray, inter: &scene::Intersection) -> (linear::Frame, linear::Vec3<f64>, PrincipledLobes) {
+        let front = &ray.traj * &inter.geom < 0.0;
+        let frame = linear::Frame::new(&if front { inter.norm.copy() } else { -&inter.norm });
+        let wo = frame.to_local(&-&ray.traj);
+        (frame, wo, self.lobes(ray, inter, front))
+    }
+}
+
+impl<'a> Material for Principled<'a> {
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let at = self.at(inter);
+        let (frame, wo, lobes) = at.local(ray, inter);
+        let wi = frame.to_local(exitant);
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return scene::BLACK.copy();
+        }
+        ray.reflectance(&at.eval(&lobes, &wo, &wi))
+    }
+
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        let at = self.at(inter);
+        let (frame, wo, lobes) = at.local(ray, inter);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let (u, u1, u2) = (rand::random::<f64>(), rand::random(), rand::random());
+        let p = &lobes.probs;
+        let wi = if u < p[0] {
+            sampling::cosine_hemisphere(u1, u2)
+        } else if u < p[0] + p[1] {
+            microfacet::reflect(&wo, &lobes.ggx.sample_visible(&wo, u1, u2))
+        } else if u < p[0] + p[1] + p[2] {
+            microfacet::refract(&wo, &lobes.ggx.sample_visible(&wo, u1, u2), lobes.rel)?
+        } else {
+            microfacet::reflect(&wo, &microfacet::sample_gtr1(at.coat_alpha(), u1, u2))
+        };
+        let pdf = at.density(&lobes, &wo, &wi);
+        if wi.z == 0.0 || pdf <= 0.0 {
+            return None;
+        }
+        let weight = ray.reflectance(&at.eval(&lobes, &wo, &wi)) * (wi.z.abs() / pdf);
+        Some(Scatter {dir: frame.to_world(&wi), weight, pdf})
+    }
+
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        let at = self.at(inter);
+        let (frame, wo, lobes) = at.local(ray, inter);
+        let wi = frame.to_local(exitant);
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return 0.0;
+        }
+        at.density(&lobes, &wo, &wi)
+    }
+
+    // Textured weights may transmit anywhere on the surface.
+    fn transmissive(&self) -> bool {
+        let clear = !matches!(self.transmission, Scalar::Constant(t) if t <= 0.0);
+        clear && !matches!(self.metallic, Scalar::Constant(m) if m >= 1.0)
+    }
+
+    fn medium(&self, _ray: &ray::Ray, _inter: &scene::Intersection) -> Option<medium::Medium> {
+        if self.transmissive() { Some(medium::Medium::clear(self.eta)) } else { None }
+    }
+}
+
+// Direction inside a coat of relative index rel for one outside it, both
+// pointing away from the surface.
+fn into_coat(w: &linear::Vec3<f64>, rel: f64) -> linear::Vec3<f64> {
+    let (x, y) = (w.x / rel, w.y / rel);
+    linear::Vec3 {x, y, z: (1.0 - x * x - y * y).max(0.0).sqrt()}
+}
+
+// Direction outside a coat for one inside it, None if reflected internally.
+fn out_of_coat(w: &linear::Vec3<f64>, rel: f64) -> Option<linear::Vec3<f64>> {
+    let (x, y) = (w.x * rel, w.y * rel);
+    let z2 = 1.0 - x * x - y * y;
+    if z2 <= 0.0 { None } else { Some(linear::Vec3 {x, y, z: z2.sqrt()}) }
+}
+
+impl<'a> Coated<'a> {
+    // Ray reaching the base from inside the coat along the local direction -w.
+    fn inner_ray(ray: &ray::Ray, frame: &linear::Frame, w: &linear::Vec3<f64>) -> ray::Ray {
+        ray::Ray {origin: ray.origin.copy(), traj: -frame.to_world(w), lambda: ray.lambda.as_ref().map(|wl| wl.copy()), pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0}
+    }
+
+    // Transmittance into the coat, down to the base and back out along wi.
+    fn through(&self, inter: &scene::Intersection, wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>, wo_c: &linear::Vec3<f64>, wi_c: &linear::Vec3<f64>, rel: f64) -> linear::Vec3<f64> {
+        let t = (1.0 - microfacet::fresnel_dielectric(wo.z, rel)) * (1.0 - microfacet::fresnel_dielectric(wi.z, rel));
+        let path = 0.5 * (1.0 / wo_c.z.max(1e-4) + 1.0 / wi_c.z.max(1e-4));
+        let tint = self.tint.at(inter);
+        linear::Vec3 {x: tint.x.powf(path), y: tint.y.powf(path), z: tint.z.powf(path)} * t
+    }
+
+    // Probability of sampling the coat rather than the base.
+    fn coat_prob(&self, wo: &linear::Vec3<f64>, rel: f64) -> f64 {
+        microfacet::fresnel_dielectric(wo.z, rel).clamp(0.1, 0.9)
+    }
+
+    // Value of both layers and the density of sampling wi, in the local frame.
+    fn eval(&self, ray: &ray::Ray, inter: &scene::Intersection, frame: &linear::Frame,
+            wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>, rel: f64) -> (linear::Vec3<f64>, f64) {
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), 0.0);
+        let p_coat = self.coat_prob(wo, rel);
+        let mut f = scene::BLACK.copy();
+        let mut pdf = 0.0;
+        if !ggx.smooth() {
+            let wm = (wo + wi).normalize();
+            let coat = ggx.d(&wm) * ggx.g(wo, wi) * microfacet::fresnel_dielectric(wo * &wm, rel) / (4.0 * wo.z * wi.z);
+            f = f + &scene::WHITE * coat;
+            pdf += p_coat * ggx.pdf(wo, &wm) / (4.0 * (wo * &wm));
+        }
+        let (wo_c, wi_c) = (into_coat(wo, rel), into_coat(wi, rel));
+        let inner = Coated::inner_ray(ray, frame, &wo_c);
+        let base = self.base.bsdf(&inner, inter, &frame.to_world(&wi_c));
+        f = f + base.color_prod(&ray.reflectance(&self.through(inter, wo, wi, &wo_c, &wi_c, rel))) / (rel * rel);
+        let jacobian = wi.z / (rel * rel * wi_c.z.max(1e-4));
+        pdf += (1.0 - p_coat) * self.base.pdf(&inner, inter, &frame.to_world(&wi_c)) * jacobian;
+        (f, pdf)
+    }
+}
+
+impl<'a> Material for Coated<'a> {
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let frame = linear::Frame::new(&inter.norm);
+        let (wo, wi) = (frame.to_local(&-&ray.traj), frame.to_local(exitant));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return scene::BLACK.copy();
+        }
+        self.eval(ray, inter, &frame, &wo, &wi, ray.relative_ior(self.eta, inter, true)).0
+    }
+
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        let frame = linear::Frame::new(&inter.norm);
+        let wo = frame.to_local(&-&ray.traj);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let rel = ray.relative_ior(self.eta, inter, true);
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), 0.0);
+        let p_coat = self.coat_prob(&wo, rel);
+        let wi = if p_coat > rand::random() {
+            if ggx.smooth() {
+                let f = microfacet::fresnel_dielectric(wo.z, rel);
+                let wi = linear::Vec3 {x: -wo.x, y: -wo.y, z: wo.z};
+                return Some(Scatter {dir: frame.to_world(&wi), weight: ray.reflectance(&scene::WHITE) * (f / p_coat), pdf: 0.0});
+            }
+            microfacet::reflect(&wo, &ggx.sample_visible(&wo, rand::random(), rand::random()))
+        } else {
+            let wo_c = into_coat(&wo, rel);
+            let inner = Coated::inner_ray(ray, &frame, &wo_c);
+            let scatter = self.base.sample(&inner, inter)?;
+            let wi_c = frame.to_local(&scatter.dir);
+            if wi_c.z <= 0.0 {
+                return None;
+            }
+            let wi = out_of_coat(&wi_c, rel)?;
+            if scatter.pdf == 0.0 {
+                // Specular base: carry its weight through the coat.
+                let through = ray.reflectance(&self.through(inter, &wo, &wi, &wo_c, &wi_c, rel));
+                return Some(Scatter {dir: frame.to_world(&wi), weight: scatter.weight.color_prod(&through) / (1.0 - p_coat), pdf: 0.0});
+            }
+            wi
+        };
+        if wi.z <= 0.0 {
+            return None;
+        }
+        let (f, pdf) = self.eval(ray, inter, &frame, &wo, &wi, rel);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(Scatter {dir: frame.to_world(&wi), weight: f * (wi.z / pdf), pdf})
+    }
+
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        let frame = linear::Frame::new(&inter.norm);
+        let (wo, wi) = (frame.to_local(&-&ray.traj), frame.to_local(exitant));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        self.eval(ray, inter, &frame, &wo, &wi, ray.relative_ior(self.eta, inter, true)).1
+    }
+
+    forward_to_base!(emit, emissive, power, dispersive, two_sided, medium, diffusion, shading_normal, opaque);
+}
+
+impl<'a> Mix<'a> {
+    fn weight(&self, ray: &ray::Ray, inter: &scene::Intersection) -> f64 {
+        match &self.weight {
+            MixWeight::Constant(w) => *w,
+            MixWeight::Texture(tex) => tex.scalar(inter),
+            MixWeight::Fresnel(eta) => microfacet::fresnel_dielectric((&ray.traj * &inter.norm).abs(), *eta),
+        }.clamp(0.0, 1.0)
+    }
+
+    // Weight without the ray, Fresnel taken head-on, so that properties
+    // picked by it agree between entering and leaving a surface.
+    fn mask(&self, inter: &scene::Intersection) -> f64 {
+        match &self.weight {
+            MixWeight::Constant(w) => *w,
+            MixWeight::Texture(tex) => tex.scalar(inter),
+            MixWeight::Fresnel(eta) => microfacet::fresnel_dielectric(1.0, *eta),
+        }.clamp(0.0, 1.0)
+    }
+
+    // The material that mostly shows at a hit, for properties that cannot
+    // be blended like the medium behind the surface.
+    fn pick(&self, w: f64) -> &dyn Material {
+        if w < 0.5 { self.a } else { self.b }
+    }
+}
+
+impl<'a> Material for Mix<'a> {
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let w = self.weight(ray, inter);
+        &(self.a.bsdf(ray, inter, exitant) * (1.0 - w)) + &(self.b.bsdf(ray, inter, exitant) * w)
+    }
+
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        let w = self.weight(ray, inter);
+        let scatter = if w > rand::random() { self.b.sample(ray, inter)? } else { self.a.sample(ray, inter)? };
+        if scatter.pdf == 0.0 {
+            // Specular lobes are only reached by sampling, so keep the lobe's weight.
+            return Some(scatter);
+        }
+        let pdf = self.pdf(ray, inter, &scatter.dir);
+        let cos = (&scatter.dir * &inter.norm).abs();
+        Some(Scatter {weight: self.bsdf(ray, inter, &scatter.dir) * (cos / pdf), dir: scatter.dir, pdf})
+    }
+
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        let w = self.weight(ray, inter);
+        self.a.pdf(ray, inter, exitant) * (1.0 - w) + self.b.pdf(ray, inter, exitant) * w
+    }
+
+    fn emit(&self, ray: &ray::Ray, inter: &scene::Intersection) -> linear::Vec3<f64> {
+        let w = self.weight(ray, inter);
+        &(self.a.emit(ray, inter) * (1.0 - w)) + &(self.b.emit(ray, inter) * w)
+    }
+
+    fn emissive(&self) -> bool {
+        self.a.emissive() || self.b.emissive()
+    }
+
+    // Weights varying over the surface or with angle bound it by the brighter.
+    fn power(&self) -> f64 {
+        match self.weight {
+            MixWeight::Constant(w) => self.a.power() * (1.0 - w) + self.b.power() * w,
+            _ => self.a.power().max(self.b.power()),
+        }
+    }
+
+    fn dispersive(&self) -> bool {
+        self.a.dispersive() || self.b.dispersive()
+    }
+
+    fn transmissive(&self) -> bool {
+        self.a.transmissive() || self.b.transmissive()
+    }
+
+    fn two_sided(&self) -> bool {
+        self.a.two_sided() || self.b.two_sided()
+    }
+
+    fn medium(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<medium::Medium> {
+        self.pick(self.mask(inter)).medium(ray, inter)
+    }
+
+    fn diffusion(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<medium::Diffusion> {
+        self.pick(self.mask(inter)).diffusion(ray, inter)
+    }
+
+    fn shading_normal(&self, inter: &scene::Intersection) -> Option<linear::Vec3<f64>> {
+        self.pick(self.mask(inter)).shading_normal(inter)
+    }
+
+    fn opaque(&self, inter: &scene::Intersection) -> bool {
+        self.pick(self.mask(inter)).opaque(inter)
+    }
+}
+
+// Points along roughness and the cosine to the viewer, from 0 to 1, at which
+// the sheen's directional albedo is tabulated.
+const SHEEN_TABLE: usize = 32;
+
+// Fraction of the light from a direction at this cosine to the normal that
+// the sheen lobe reflects, interpolated from a table integrated on first use.
+fn sheen_albedo(roughness: f64, cos: f64) -> f64 {
+    static TABLE: OnceLock<Vec<f64>> = OnceLock::new();
+    let n = SHEEN_TABLE;
+    let table = TABLE.get_or_init(|| {
+        let steps = 128;
+        let mut table = Vec::with_capacity(n * n);
+        for r in 0..n {
+            for c in 0..n {
+                let cos = (c as f64 / (n - 1) as f64).max(1e-3);
+                let wo = linear::Vec3 {x: (1.0 - cos * cos).sqrt(), y: 0.0, z: cos};
+                let mut sum = 0.0;
+                for i in 0..steps {
+                    let mu = (i as f64 + 0.5) / steps as f64;
+                    let sin = (1.0 - mu * mu).sqrt();
+                    for j in 0..steps {
+                        let phi = (j as f64 + 0.5) / steps as f64 * 2.0 * PI;
+                        let wi = linear::Vec3 {x: sin * phi.cos(), y: sin * phi.sin(), z: mu};
+                        sum += Sheen::lobe(r as f64 / (n - 1) as f64, &wo, &wi) * mu;
+                    }
+                }
+                table.push(sum * 2.0 * PI / (steps * steps) as f64);
+            }
+        }
+        table
+    });
+    let (fr, fc) = (roughness.clamp(0.0, 1.0) * (n - 1) as f64, cos.clamp(0.0, 1.0) * (n - 1) as f64);
+    let (r, c) = ((fr as usize).min(n - 2), (fc as usize).min(n - 2));
+    let (tr, tc) = (fr - r as f64, fc - c as f64);
+    let row = |r: usize| table[r * n + c] * (1.0 - tc) + table[r * n + c + 1] * tc;
+    row(r) * (1.0 - tr) + row(r + 1) * tr
+}
+
+impl<'a> Sheen<'a> {
+    // Charlie sheen with the visibility term of Neubelt and Pettineo 2013,
+    // in the local frame.
+    fn lobe(roughness: f64, wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>) -> f64 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let wm = (wo + wi).normalize();
+        let alpha = roughness.max(0.07).powi(2);
+        microfacet::charlie(wm.z, alpha) / (4.0 * (wi.z + wo.z - wi.z * wo.z))
+    }
+
+    // What the base is scaled by: the light the sheen doesn't reflect toward
+    // the viewer, so that the two together reflect no more than the base.
+    fn base_scale(&self, ray: &ray::Ray, inter: &scene::Intersection) -> linear::Vec3<f64> {
+        let cos = -(&ray.traj * &inter.norm);
+        if cos <= 0.0 {
+            return ray.reflectance(&scene::WHITE);
+        }
+        let albedo = sheen_albedo(self.roughness.at(inter), cos);
+        ray.reflectance(&(&scene::WHITE - &(self.color.at(inter) * albedo)))
+    }
+
+    // Probability of sampling the sheen rather than the base.
+    fn sheen_prob(&self, inter: &scene::Intersection) -> f64 {
+        let color = self.color.at(inter);
+        0.5 * color.x.max(color.y).max(color.z).clamp(0.0, 1.0)
+    }
+}
+
+impl<'a> Material for Sheen<'a> {
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let frame = linear::Frame::new(&inter.norm);
+        let sheen = Sheen::lobe(self.roughness.at(inter), &frame.to_local(&-&ray.traj), &frame.to_local(exitant));
+        &self.base.bsdf(ray, inter, exitant).color_prod(&self.base_scale(ray, inter)) + &(ray.reflectance(&self.color.at(inter)) * sheen)
+    }
+
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        let p = self.sheen_prob(inter);
+        let dir = if p > rand::random() {
+            let frame = linear::Frame::new(&inter.norm);
+            frame.to_world(&sampling::cosine_hemisphere(rand::random(), rand::random()))
+        } else {
+            let scatter = self.base.sample(ray, inter)?;
+            if scatter.pdf == 0.0 {
+                return Some(Scatter {weight: scatter.weight.color_prod(&self.base_scale(ray, inter)) / (1.0 - p), ..scatter});
+            }
+            scatter.dir
+        };
+        let pdf = self.pdf(ray, inter, &dir);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let cos = (&dir * &inter.norm).abs();
+        Some(Scatter {weight: self.bsdf(ray, inter, &dir) * (cos / pdf), dir, pdf})
     }
+
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        let p = self.sheen_prob(inter);
+        let cos = exitant * &inter.norm;
+        let sheen = if cos > 0.0 && &ray.traj * &inter.norm < 0.0 { cos * FRAC_1_PI } else { 0.0 };
+        self.base.pdf(ray, inter, exitant) * (1.0 - p) + sheen * p
+    }
+
+    forward_to_base!(emit, emissive, power, dispersive, transmissive, two_sided, medium, diffusion, shading_normal, opaque);
 }
 
-impl Dielectric {
-    fn schlick(cos: f64, ratio: f64) -> f64 {
-        let r0 = ((1.0 - ratio) / (1.0 + ratio)).powi(2);
-        r0 + (1.0 - r0)*(1.0 - cos).powi(5)
+impl<'a> Subsurface<'a> {
+    fn surface(&self) -> Dielectric<'a> {
+        Dielectric {color: Color::Constant(scene::WHITE), eta: spectrum::Ior::Constant(self.eta), roughness: self.roughness.copy(), thin: false, film: None, absorption: None}
+    }
+
+    fn walks(&self) -> bool {
+        matches!(self.method, SubsurfaceMethod::RandomWalk)
     }
 }
 
-impl Material for Emitter {
-    fn bsdf(&self, incident: f64, exitant: f64) -> linear::Vec3<f64> {
-        self.color.copy()
-        // if incident > 0.0 {
-        // } else {
-        //     scene::BLACK.copy()
-        // }
+impl<'a> Material for Subsurface<'a> {
+    // With a diffusion profile, only reflection off the surface remains here.
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        if !self.walks() && (exitant * &inter.norm) * (&ray.traj * &inter.norm) >= 0.0 {
+            return scene::BLACK.copy();
+        }
+        self.surface().bsdf(ray, inter, exitant)
+    }
+
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<Scatter> {
+        if self.walks() {
+            return self.surface().sample(ray, inter);
+        }
+        // Reflection alone, which the integrator picks with Fresnel probability.
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), 0.0);
+        let frame = linear::Frame::new(&inter.norm);
+        let wo = frame.to_local(&-&ray.traj);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let rel = ray.relative_ior(self.eta, inter, true);
+        if ggx.smooth() {
+            let wi = linear::Vec3 {x: -wo.x, y: -wo.y, z: wo.z};
+            let f = microfacet::fresnel_dielectric(wo.z, rel);
+            return Some(Scatter {dir: frame.to_world(&wi), weight: ray.reflectance(&scene::WHITE) * f, pdf: 0.0});
+        }
+        let wm = ggx.sample_visible(&wo, rand::random(), rand::random());
+        let wi = microfacet::reflect(&wo, &wm);
+        if wi.z <= 0.0 {
+            return None;
+        }
+        let f = microfacet::fresnel_dielectric(&wo * &wm, rel);
+        let pdf = ggx.pdf(&wo, &wm) / (4.0 * (&wo * &wm));
+        Some(Scatter {dir: frame.to_world(&wi), weight: ray.reflectance(&scene::WHITE) * (f * ggx.g(&wo, &wi) / ggx.g1(&wo)), pdf})
+    }
+
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        if self.walks() {
+            return self.surface().pdf(ray, inter, exitant);
+        }
+        let ggx = microfacet::Ggx::new(self.roughness.at(inter), 0.0);
+        let frame = linear::Frame::new(&inter.norm);
+        let (wo, wi) = (frame.to_local(&-&ray.traj), frame.to_local(exitant));
+        if ggx.smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let wm = (&wo + &wi).normalize();
+        ggx.pdf(&wo, &wm) / (4.0 * (&wo * &wm))
+    }
+
+    fn transmissive(&self) -> bool {
+        self.walks()
+    }
+
+    fn medium(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<medium::Medium> {
+        if !self.walks() {
+            return None;
+        }
+        Some(medium::Medium::from_albedo(self.eta, &ray.reflectance(&self.color.at(inter)), &ray.reflectance(&self.mfp)))
+    }
+
+    fn diffusion(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<medium::Diffusion> {
+        if self.walks() {
+            return None;
+        }
+        Some(medium::Diffusion {albedo: ray.reflectance(&self.color.at(inter)), mfp: ray.reflectance(&self.mfp), eta: self.eta})
+    }
+}
+
+impl<'a> Material for Bumped<'a> {
+    fn shading_normal(&self, inter: &scene::Intersection) -> Option<linear::Vec3<f64>> {
+        let norm = match &self.bump {
+            Bump::Normal(tex, strength) => {
+                let c = tex.value(inter);
+                let local = linear::Vec3 {x: (2.0 * c.x - 1.0) * strength, y: (2.0 * c.y - 1.0) * strength, z: (2.0 * c.z - 1.0).max(1e-3)};
+                inter.tangent_frame().to_world(&local)
+            },
+            // Differences the heights over about the ray's footprint, and
+            // bends the normal as if the surface were moved along it by them
+            // (Blinn 1978).
+            Bump::Height(tex, scale) => {
+                let d = (0.5 * inter.footprint).max(1e-4);
+                let h = tex.scalar(inter);
+                let dhdu = (tex.scalar(&inter.offset(d, 0.0)) - h) * scale / d;
+                let dhdv = (tex.scalar(&inter.offset(0.0, d)) - h) * scale / d;
+                let dpdu = &inter.tangent + &(&inter.norm * dhdu);
+                let dpdv = &inter.bitangent + &(&inter.norm * dhdv);
+                let n = &dpdu ^ &dpdv;
+                if &n * &inter.norm < 0.0 { -n } else { n }
+            },
+        };
+        if norm.norm() < 1e-12 {
+            return None;
+        }
+        Some(norm.normalize())
+    }
+
+    forward_to_base!(bsdf, sample, pdf, emit, emissive, power, dispersive, transmissive, two_sided, medium, diffusion, opaque);
+}
+
+impl<'a> Material for Cutout<'a> {
+    fn opaque(&self, inter: &scene::Intersection) -> bool {
+        self.mask.scalar(inter) >= 0.5 && self.base.opaque(inter)
+    }
+
+    forward_to_base!(bsdf, sample, pdf, emit, emissive, power, dispersive, transmissive, two_sided, medium, diffusion, shading_normal);
+}
+
+impl<'a> Emitter<'a> {
+    // Lambertian emitter of the given total power spread over a light of the
+    // given area, or an error for spectra that cannot be scaled to it.
+    pub fn new(spd: spectrum::Spd, power: spectrum::Power, area: f64) -> Result<Self, String> {
+        Emitter::directional(spd, power, area, 0.0)
+    }
+
+    // Emitter whose radiance falls off as cos^falloff away from the normal,
+    // brighter head-on for the same total power.
+    pub fn directional(spd: spectrum::Spd, power: spectrum::Power, area: f64, falloff: f64) -> Result<Self, String> {
+        let scale = spd.scale(&power, 2.0 * PI / (falloff + 2.0) * area)?;
+        Ok(Emitter {color: spd.rgb() * scale, spd, scale, two_sided: false, texture: None, falloff})
+    }
+
+    pub fn blackbody(kelvin: f64, power: spectrum::Power, area: f64) -> Result<Self, String> {
+        Emitter::new(spectrum::Spd::Blackbody(kelvin), power, area)
+    }
+
+    pub fn radiance(&self, ray: &ray::Ray) -> linear::Vec3<f64> {
+        match (&self.spd, &ray.lambda) {
+            (spectrum::Spd::Rgb, _) => ray.illuminant(&self.color),
+            (spd, Some(wl)) => spd.sample(wl) * self.scale,
+            (_, None) => self.color.copy(),
+        }
+    }
+}
+
+impl<'a> Material for Emitter<'a> {
+    fn bsdf(&self, _ray: &ray::Ray, _inter: &scene::Intersection, _exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        scene::BLACK.copy()
     }
     
-    fn sample(&self, incident: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> linear::Vec3<f64> {
-        linear::Vec3::new()
+    fn sample(&self, _ray: &ray::Ray, _inter: &scene::Intersection) -> Option<Scatter> {
+        None
     }
 
-    fn pdf(&self, exitant: &linear::Vec3<f64>, norm: &linear::Vec3<f64>) -> f64 {
+    fn pdf(&self, _ray: &ray::Ray, _inter: &scene::Intersection, _exitant: &linear::Vec3<f64>) -> f64 {
         0.0
     }
-}
\ No newline at end of file
+
+    fn emit(&self, ray: &ray::Ray, inter: &scene::Intersection) -> linear::Vec3<f64> {
+        let cos = -(&ray.traj * &inter.geom);
+        if cos <= 0.0 && !self.two_sided {
+            return scene::BLACK.copy();
+        }
+        let radiance = self.radiance(ray) * cos.abs().powf(self.falloff);
+        match self.texture {
+            Some(tex) => radiance.color_prod(&ray.reflectance(&tex.value(inter))),
+            None => radiance,
+        }
+    }
+
+    fn emissive(&self) -> bool {
+        true
+    }
+
+    // Radiance falling off as cos^n integrates to 2 pi / (n + 2) over the
+    // hemisphere, scaled by the mean of the texture.
+    fn power(&self) -> f64 {
+        let color = match self.texture {
+            Some(tex) => self.color.color_prod(&tex.average()),
+            None => self.color.copy(),
+        };
+        let sides = if self.two_sided { 2.0 } else { 1.0 };
+        spectrum::luminance(&color) * 2.0 * PI / (self.falloff + 2.0) * sides
+    }
+
+    fn two_sided(&self) -> bool {
+        self.two_sided
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inter(mat: &dyn Material) -> scene::Intersection<'_> {
+        scene::Intersection {t: 1.0, pos: linear::Vec3::new(), norm: linear::Z, geom: linear::Z, mat, uv: (0.5, 0.5), footprint: 0.0,
+                             tangent: linear::X, bitangent: linear::Y}
+    }
+
+    // Luminance leaving a unit area of the material, integrated numerically
+    // over both hemispheres.
+    fn integrate(mat: &dyn Material) -> f64 {
+        let steps = 2000;
+        let mut power = 0.0;
+        for i in 0..steps {
+            let theta = (i as f64 + 0.5) / steps as f64 * PI;
+            let dir = linear::Vec3 {x: theta.sin(), y: 0.0, z: theta.cos()};
+            let ray = ray::Ray {origin: dir.copy(), traj: -dir, lambda: None, pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0};
+            power += spectrum::luminance(&mat.emit(&ray, &inter(mat))) * theta.cos().abs() * theta.sin();
+        }
+        power * PI / steps as f64 * 2.0 * PI
+    }
+
+    #[test]
+    fn emitter_power_matches_its_radiance() {
+        let mut light = Emitter::blackbody(5000.0, spectrum::Power::Watts(10.0), 1.0).unwrap();
+        for (falloff, two_sided) in [(0.0, false), (3.0, false), (1.5, true)] {
+            light.falloff = falloff;
+            light.two_sided = two_sided;
+            let (power, numeric) = (light.power(), integrate(&light));
+            assert!((power - numeric).abs() < 1e-3 * numeric, "falloff {}: {} vs {}", falloff, power, numeric);
+        }
+    }
+
+    #[test]
+    fn watts_integrate_back_to_the_power() {
+        let (area, steps) = (2.0, 200);
+        for falloff in [0.0, 4.0] {
+            let light = Emitter::directional(spectrum::ILLUMINANT_D65, spectrum::Power::Watts(100.0), area, falloff).unwrap();
+            let mut power = 0.0;
+            for lambda in 380..=780 {
+                let wl = spectrum::Wavelengths {lambda: linear::Vec3 {x: lambda as f64, y: 0.0, z: 0.0}, secondary: false};
+                let trapezoid = if lambda == 380 || lambda == 780 { 0.5 } else { 1.0 };
+                for i in 0..steps {
+                    let theta = (i as f64 + 0.5) / steps as f64 * 0.5 * PI;
+                    let dir = linear::Vec3 {x: theta.sin(), y: 0.0, z: theta.cos()};
+                    let ray = ray::Ray {origin: dir.copy(), traj: -dir, lambda: Some(wl.copy()), pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0};
+                    power += trapezoid * light.emit(&ray, &inter(&light)).x * theta.cos() * theta.sin();
+                }
+            }
+            power *= 0.5 * PI / steps as f64 * 2.0 * PI * area;
+            assert!((power - 100.0).abs() < 0.1, "falloff {}: {} W", falloff, power);
+        }
+    }
+
+    #[test]
+    fn mix_forwards_the_material_its_weight_favors() {
+        let hole = texture::Checker {a: scene::BLACK.copy(), b: scene::BLACK.copy(), scale: 1.0, mapping: texture::Mapping::Uv};
+        let cut = Cutout {base: &BLANK, mask: &hole};
+        let mostly = |w: f64| Mix {a: &BLANK, b: &cut, weight: MixWeight::Constant(w)};
+        assert!(mostly(0.2).opaque(&inter(&BLANK)));
+        assert!(!mostly(0.8).opaque(&inter(&BLANK)));
+
+        // A Fresnel weight favors the other side at grazing angles, but light
+        // diffusing under the surface must leave by the side it entered.
+        let skin = Subsurface {color: Color::Constant(scene::WHITE.copy()), mfp: scene::WHITE.copy(), eta: 1.4,
+                               roughness: texture::Scalar::Constant(0.0), method: SubsurfaceMethod::Diffusion};
+        let coated = Mix {a: &skin, b: &BLANK, weight: MixWeight::Fresnel(1.5)};
+        let ray = |traj: linear::Vec3<f64>| ray::Ray {origin: linear::Vec3::new(), traj: traj.normalize(), lambda: None, pdf: 0.0,
+                                                      media: Vec::new(), width: 0.0, spread: 0.0};
+        for traj in [linear::Vec3 {x: 0.0, y: 0.0, z: -1.0}, linear::Vec3 {x: 1.0, y: 0.0, z: -0.01}, linear::Vec3 {x: 1.0, y: 0.0, z: 0.01}] {
+            assert!(coated.diffusion(&ray(traj), &inter(&coated)).is_some());
+        }
+    }
+
+    #[test]
+    fn sheen_over_white_reflects_no_more_than_white() {
+        let white = Lambert {color: Color::Constant(scene::WHITE)};
+        let steps = 400;
+        for roughness in [0.1, 0.3, 0.6, 1.0] {
+            let velvet = Sheen {base: &white, color: Color::Constant(scene::WHITE), roughness: Scalar::Constant(roughness)};
+            for deg in [0.0, 45.0, 80.0, 89.0] {
+                let theta: f64 = f64::to_radians(deg);
+                let dir = linear::Vec3 {x: theta.sin(), y: 0.0, z: theta.cos()};
+                let ray = ray::Ray {origin: dir.copy(), traj: -dir, lambda: None, pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0};
+                let mut albedo = 0.0;
+                for i in 0..steps {
+                    let mu = (i as f64 + 0.5) / steps as f64;
+                    let sin = (1.0 - mu * mu).sqrt();
+                    for j in 0..steps {
+                        let phi = (j as f64 + 0.5) / steps as f64 * 2.0 * PI;
+                        let wi = linear::Vec3 {x: sin * phi.cos(), y: sin * phi.sin(), z: mu};
+                        albedo += velvet.bsdf(&ray, &inter(&velvet), &wi).x * mu;
+                    }
+                }
+                albedo *= 2.0 * PI / (steps * steps) as f64;
+                assert!(albedo <= 1.0 + 1e-3, "albedo {} at roughness {} and {} degrees", albedo, roughness, deg);
+                // Nor much less, away from grazing where the table is coarse.
+                assert!(deg > 85.0 || albedo > 0.99, "albedo {} at roughness {} and {} degrees", albedo, roughness, deg);
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::f64::consts::PI;
+
+use crate::linear;
+use crate::microfacet;
+use crate::scene;
+
+// Homogeneous medium filling a closed surface, with absorption and
+// scattering coefficients per unit length in the color space of the ray that
+// entered it. Scattering is isotropic. Owner identifies the material whose
+// surface bounds the medium, to find it again when the ray leaves.
+pub struct Medium {
+    pub ior: f64,
+    pub owner: usize,
+    pub sigma_a: linear::Vec3<f64>,
+    pub sigma_s: linear::Vec3<f64>,
+}
+
+// Burley's normalized diffusion profile (Christensen and Burley 2015) for
+// light entering a surface and leaving at a distance r, from the surface
+// albedo and the mean free path per channel.
+pub struct Diffusion {
+    pub albedo: linear::Vec3<f64>,
+    pub mfp: linear::Vec3<f64>,
+    pub eta: f64,
+}
+
+fn channel(v: &linear::Vec3<f64>, c: usize) -> f64 {
+    match c {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+fn exp(v: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+    linear::Vec3 {x: v.x.exp(), y: v.y.exp(), z: v.z.exp()}
+}
+
+fn average(v: &linear::Vec3<f64>) -> f64 {
+    (v.x + v.y + v.z) / 3.0
+}
+
+impl Medium {
+    pub fn clear(ior: f64) -> Self {
+        Medium {ior, owner: 0, sigma_a: scene::BLACK.copy(), sigma_s: scene::BLACK.copy()}
+    }
+
+    // Scattering medium from the multiple scattering albedo seen on the
+    // surface, inverted to single scattering albedo (Chiang et al. 2016).
+    pub fn from_albedo(ior: f64, albedo: &linear::Vec3<f64>, mfp: &linear::Vec3<f64>) -> Self {
+        let single = |a: f64| {
+            let a = a.clamp(0.0, 0.999);
+            1.0 - (4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt()).powi(2)
+        };
+        let sigma_t = linear::Vec3 {x: 1.0 / mfp.x.max(1e-6), y: 1.0 / mfp.y.max(1e-6), z: 1.0 / mfp.z.max(1e-6)};
+        let sigma_s = linear::Vec3 {x: single(albedo.x) * sigma_t.x, y: single(albedo.y) * sigma_t.y, z: single(albedo.z) * sigma_t.z};
+        Medium {ior, owner: 0, sigma_a: &sigma_t - &sigma_s, sigma_s}
+    }
+
+    // Whether the medium absorbs or scatters at all.
+    pub fn interacts(&self) -> bool {
+        let t = &self.sigma_a + &self.sigma_s;
+        t.x > 0.0 || t.y > 0.0 || t.z > 0.0
+    }
+
+    // Samples how far light travels before scattering, for a surface at dist.
+    // Returns the throughput weight, with the distance if it scatters first.
+    // Distances are sampled in one channel chosen at random, weighted by the
+    // average density over all three.
+    pub fn sample(&self, dist: f64) -> (linear::Vec3<f64>, Option<f64>) {
+        let sigma_t = &self.sigma_a + &self.sigma_s;
+        if self.sigma_s.x <= 0.0 && self.sigma_s.y <= 0.0 && self.sigma_s.z <= 0.0 {
+            return (exp(&(&sigma_t * -dist)), None);
+        }
+        let c = ((rand::random::<f64>() * 3.0) as usize).min(2);
+        let st = channel(&sigma_t, c);
+        let d = if st > 0.0 { -(1.0 - rand::random::<f64>()).ln() / st } else { f64::INFINITY };
+        if d < dist {
+            let tr = exp(&(&sigma_t * -d));
+            let pdf = average(&sigma_t.color_prod(&tr));
+            (self.sigma_s.color_prod(&tr) / pdf, Some(d))
+        } else {
+            let tr = exp(&(&sigma_t * -dist));
+            let pdf = average(&tr);
+            (tr / pdf, None)
+        }
+    }
+}
+
+impl Diffusion {
+    // Scale of each channel's profile, s in the paper.
+    fn scale(&self) -> linear::Vec3<f64> {
+        let s = |a: f64| 1.9 - a + 3.5 * (a - 0.8).powi(2);
+        let d = |mfp: f64, a: f64| mfp.max(1e-6) / s(a);
+        linear::Vec3 {x: d(self.mfp.x, self.albedo.x), y: d(self.mfp.y, self.albedo.y), z: d(self.mfp.z, self.albedo.z)}
+    }
+
+    // Radius past which the profile is negligible.
+    pub fn max_radius(&self) -> f64 {
+        let d = self.scale();
+        3.0 * d.x.max(d.y).max(d.z) * 1000.0_f64.ln()
+    }
+
+    // Exitance per unit area at distance r, for unit flux entering.
+    pub fn eval(&self, r: f64) -> linear::Vec3<f64> {
+        let d = self.scale();
+        let r = r.max(1e-6);
+        let f = |d: f64, a: f64| a * ((-r / d).exp() + (-r / (3.0 * d)).exp()) / (8.0 * PI * d * r);
+        linear::Vec3 {x: f(d.x, self.albedo.x), y: f(d.y, self.albedo.y), z: f(d.z, self.albedo.z)}
+    }
+
+    // Samples a radius from one channel's profile, normalized to one.
+    pub fn sample_radius(&self, c: usize, u1: f64, u2: f64) -> f64 {
+        let d = channel(&self.scale(), c);
+        let d = if u1 < 0.25 { d } else { 3.0 * d };
+        -d * (1.0 - u2).ln()
+    }
+
+    // Area density of sample_radius() at r, averaged over the channels.
+    pub fn pdf_radius(&self, r: f64) -> f64 {
+        let d = self.scale();
+        let r = r.max(1e-6);
+        let f = |d: f64| ((-r / d).exp() + (-r / (3.0 * d)).exp()) / (4.0 * d) / (2.0 * PI * r);
+        (f(d.x) + f(d.y) + f(d.z)) / 3.0
+    }
+
+    // Directional weight of light leaving through the surface, normalized
+    // over the hemisphere (d'Eon and Irving 2011).
+    pub fn leaving(&self, cos: f64) -> f64 {
+        let eta = 1.0 / self.eta;
+        let moment = if eta < 1.0 {
+            0.45966 - 1.73965 * eta + 3.37668 * eta.powi(2) - 3.904945 * eta.powi(3) + 2.49277 * eta.powi(4) - 0.68441 * eta.powi(5)
+        } else {
+            -4.61686 + 11.1136 * eta - 10.4646 * eta.powi(2) + 5.11455 * eta.powi(3) - 1.27198 * eta.powi(4) + 0.12746 * eta.powi(5)
+        };
+        let c = 1.0 - 2.0 * moment;
+        (1.0 - microfacet::fresnel_dielectric(cos, self.eta)) / (c * PI)
+    }
+}
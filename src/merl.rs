@@ -0,0 +1,216 @@
+use std::convert::TryInto;
+use std::f64::consts::{FRAC_PI_2, PI};
+use std::fs;
+use std::io;
+
+use crate::linear;
+use crate::material;
+use crate::ray;
+use crate::sampling;
+use crate::scene;
+use crate::spectrum;
+
+// Resolution of the measurements over the half angle, the difference angle
+// and the difference azimuth, the last over half a turn by reciprocity.
+const THETA_H: usize = 90;
+const THETA_D: usize = 90;
+const PHI_D: usize = 180;
+// Channel scales the data was stored with.
+const SCALE: [f64; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+// Sampling tables: bands of outgoing elevation, and bins of incoming
+// elevation and of azimuth about the outgoing direction in each band.
+const BANDS: usize = 16;
+const THETA_I: usize = 32;
+const PHI_I: usize = 64;
+
+// Isotropic BRDF measured by Matusik et al. 2003, read from a MERL binary
+// file. Directions are sampled from tables of the measured reflectance times
+// the cosine, one per band of outgoing elevation.
+pub struct Measured {
+    // Reflectance per channel, indexed by half angle, then difference angle,
+    // then difference azimuth.
+    data: Vec<[f64; 3]>,
+    tables: Vec<sampling::Distribution2D>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Half angle, difference angle and difference azimuth between two directions
+// in the local frame (Rusinkiewicz 1998).
+fn half_diff(wi: &linear::Vec3<f64>, wo: &linear::Vec3<f64>) -> (f64, f64, f64) {
+    let h = (wi + wo).normalize();
+    let theta_h = h.z.clamp(-1.0, 1.0).acos();
+    let (sin_p, cos_p) = h.y.atan2(h.x).sin_cos();
+    let (sin_t, cos_t) = theta_h.sin_cos();
+    // Turns wi so that the half vector becomes the normal.
+    let x = wi.x * cos_p + wi.y * sin_p;
+    let y = -wi.x * sin_p + wi.y * cos_p;
+    let d = linear::Vec3 {x: x * cos_t - wi.z * sin_t, y, z: x * sin_t + wi.z * cos_t};
+    (theta_h, d.z.clamp(-1.0, 1.0).acos(), d.y.atan2(d.x))
+}
+
+impl Measured {
+    pub fn load(path: &str) -> io::Result<Self> {
+        Measured::parse(&fs::read(path)?)
+    }
+
+    // Three dimensions as 32 bit integers, then the red, green and blue
+    // tables of doubles, all little endian.
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < 12 {
+            return Err(invalid("missing header"));
+        }
+        let dim = |i: usize| i32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+        if (dim(0), dim(1), dim(2)) != (THETA_H as i32, THETA_D as i32, PHI_D as i32) {
+            return Err(invalid("unexpected table dimensions"));
+        }
+        let n = THETA_H * THETA_D * PHI_D;
+        if bytes.len() < 12 + 3 * n * 8 {
+            return Err(invalid("unexpected end of file"));
+        }
+        let value = |i: usize| f64::from_le_bytes(bytes[12 + 8 * i..20 + 8 * i].try_into().unwrap());
+        // Negative values mark angles that were not measured.
+        let data = (0..n).map(|i| [0, 1, 2].map(|c| (value(c * n + i) * SCALE[c]).max(0.0))).collect();
+        let mut measured = Measured {data, tables: Vec::new()};
+        measured.tables = (0..BANDS).map(|b| measured.table(b)).collect();
+        Ok(measured)
+    }
+
+    // Reflectance for directions in the local frame, in RGB.
+    fn lookup(&self, wi: &linear::Vec3<f64>, wo: &linear::Vec3<f64>) -> [f64; 3] {
+        let (theta_h, theta_d, mut phi_d) = half_diff(wi, wo);
+        if phi_d < 0.0 {
+            phi_d += PI;
+        }
+        let index = |x: f64, n: usize| (x.max(0.0) as usize).min(n - 1);
+        // Half angles are stored more densely near the normal.
+        let h = index((theta_h / FRAC_PI_2).max(0.0).sqrt() * THETA_H as f64, THETA_H);
+        let d = index(theta_d / FRAC_PI_2 * THETA_D as f64, THETA_D);
+        let p = index(phi_d / PI * PHI_D as f64, PHI_D);
+        self.data[(h * THETA_D + d) * PHI_D + p]
+    }
+
+    // Direction at elevation theta and azimuth phi in the local frame.
+    fn direction(theta: f64, phi: f64) -> linear::Vec3<f64> {
+        linear::Vec3 {x: theta.sin() * phi.cos(), y: theta.sin() * phi.sin(), z: theta.cos()}
+    }
+
+    // Reflectance times the cosine over incoming elevation and azimuth, for
+    // outgoing directions at the middle of a band and azimuth zero.
+    fn table(&self, band: usize) -> sampling::Distribution2D {
+        let wo = Measured::direction((band as f64 + 0.5) / BANDS as f64 * FRAC_PI_2, 0.0);
+        let mut values = Vec::with_capacity(THETA_I * PHI_I);
+        for t in 0..THETA_I {
+            let theta = (t as f64 + 0.5) / THETA_I as f64 * FRAC_PI_2;
+            for p in 0..PHI_I {
+                let wi = Measured::direction(theta, (p as f64 + 0.5) / PHI_I as f64 * 2.0 * PI);
+                let f = self.lookup(&wi, &wo);
+                values.push(spectrum::luminance(&linear::Vec3 {x: f[0], y: f[1], z: f[2]}) * theta.cos() * theta.sin());
+            }
+        }
+        // A floor keeps every direction reachable.
+        let floor = 1e-3 * values.iter().sum::<f64>() / values.len() as f64;
+        let values: Vec<f64> = values.iter().map(|v| v + floor).collect();
+        sampling::Distribution2D::new(&values, PHI_I, THETA_I)
+    }
+
+    // Table for the outgoing direction, and the azimuth it is measured from.
+    fn sampler(&self, wo: &linear::Vec3<f64>) -> (&sampling::Distribution2D, f64) {
+        let theta = wo.z.clamp(0.0, 1.0).acos();
+        let band = ((theta / FRAC_PI_2 * BANDS as f64) as usize).min(BANDS - 1);
+        (&self.tables[band], wo.y.atan2(wo.x))
+    }
+
+    fn density(&self, wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>) -> f64 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let (table, phi_o) = self.sampler(wo);
+        let theta = wi.z.clamp(0.0, 1.0).acos();
+        let sin = theta.sin();
+        if sin <= 0.0 {
+            return 0.0;
+        }
+        let phi = (wi.y.atan2(wi.x) - phi_o).rem_euclid(2.0 * PI);
+        table.pdf(phi / (2.0 * PI), theta / FRAC_PI_2) / (PI * PI * sin)
+    }
+}
+
+impl material::Material for Measured {
+    fn bsdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let frame = linear::Frame::new(&inter.norm);
+        let wo = frame.to_local(&-&ray.traj);
+        let wi = frame.to_local(exitant);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return scene::BLACK.copy();
+        }
+        let f = self.lookup(&wi, &wo);
+        ray.reflectance(&linear::Vec3 {x: f[0], y: f[1], z: f[2]})
+    }
+
+    fn sample(&self, ray: &ray::Ray, inter: &scene::Intersection) -> Option<material::Scatter> {
+        let frame = linear::Frame::new(&inter.norm);
+        let wo = frame.to_local(&-&ray.traj);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let (table, phi_o) = self.sampler(&wo);
+        let ((u, v), _) = table.sample(rand::random(), rand::random());
+        let wi = Measured::direction(v * FRAC_PI_2, phi_o + u * 2.0 * PI);
+        let pdf = self.density(&wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let dir = frame.to_world(&wi);
+        let weight = self.bsdf(ray, inter, &dir) * (wi.z / pdf);
+        Some(material::Scatter {dir, weight, pdf})
+    }
+
+    fn pdf(&self, ray: &ray::Ray, inter: &scene::Intersection, exitant: &linear::Vec3<f64>) -> f64 {
+        let frame = linear::Frame::new(&inter.norm);
+        self.density(&frame.to_local(&-&ray.traj), &frame.to_local(exitant))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Header and tables for a BRDF with the same stored value everywhere,
+    // except the red channel near the normal marked as unmeasured.
+    fn file(value: f64) -> Vec<u8> {
+        let n = THETA_H * THETA_D * PHI_D;
+        let mut bytes: Vec<u8> = [THETA_H, THETA_D, PHI_D].iter().flat_map(|&d| (d as i32).to_le_bytes()).collect();
+        bytes.extend_from_slice(&(-1.0f64).to_le_bytes());
+        for _ in 1..3 * n {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_tables_and_scales_channels() {
+        let brdf = Measured::parse(&file(300.0)).unwrap();
+        assert_eq!(brdf.data.len(), THETA_H * THETA_D * PHI_D);
+        assert_eq!(brdf.data[0], [0.0, 300.0 * SCALE[1], 300.0 * SCALE[2]]);
+        let wo = Measured::direction(0.7, 0.2);
+        let wi = Measured::direction(0.4, 2.5);
+        for (f, expect) in brdf.lookup(&wi, &wo).iter().zip([0.2, 0.23, 0.332]) {
+            assert!((f - expect).abs() < 1e-12);
+        }
+        assert!(brdf.density(&wo, &wi) > 0.0);
+    }
+
+    #[test]
+    fn rejects_bad_headers_and_short_payloads() {
+        let bytes = file(300.0);
+        assert!(Measured::parse(&bytes[..8]).is_err());
+        assert!(Measured::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Measured::parse(&bytes[..12]).is_err());
+        let mut wrong = bytes.clone();
+        wrong[0] = 45;
+        assert!(Measured::parse(&wrong).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use std::f64::consts::PI;
+
+use num::complex::Complex64;
+
+use crate::linear;
+
+// Trowbridge-Reitz (GGX) distribution of microfacet normals. Directions are
+// in the local shading frame, with the surface normal along z.
+pub struct Ggx {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+// Below this alpha the surface is treated as perfectly smooth.
+pub const SMOOTH_ALPHA: f64 = 1e-3;
+
+impl Ggx {
+    // Perceptual roughness is squared into alpha. Anisotropy in [0, 1)
+    // stretches highlights along the tangent (Burley 2012).
+    pub fn new(roughness: f64, anisotropy: f64) -> Self {
+        let aspect = (1.0 - 0.9 * anisotropy.clamp(0.0, 1.0)).sqrt();
+        let alpha = roughness * roughness;
+        Ggx {alpha_x: (alpha / aspect).max(1e-4), alpha_y: (alpha * aspect).max(1e-4)}
+    }
+
+    pub fn smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+
+    pub fn d(&self, wm: &linear::Vec3<f64>) -> f64 {
+        if wm.z <= 0.0 {
+            return 0.0;
+        }
+        let e = (wm.x / self.alpha_x).powi(2) + (wm.y / self.alpha_y).powi(2) + wm.z * wm.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    fn lambda(&self, w: &linear::Vec3<f64>) -> f64 {
+        if w.z == 0.0 {
+            return f64::INFINITY;
+        }
+        let t2 = ((self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2)) / (w.z * w.z);
+        0.5 * ((1.0 + t2).sqrt() - 1.0)
+    }
+
+    // Smith masking of one direction.
+    pub fn g1(&self, w: &linear::Vec3<f64>) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height correlated Smith shadowing-masking.
+    pub fn g(&self, wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Density of the normals visible from w, for sample_visible().
+    pub fn pdf(&self, w: &linear::Vec3<f64>, wm: &linear::Vec3<f64>) -> f64 {
+        if w.z == 0.0 {
+            return 0.0;
+        }
+        self.g1(w) * self.d(wm) * (w * wm).max(0.0) / w.z.abs()
+    }
+
+    // Samples a normal among those visible from w (Heitz 2018).
+    pub fn sample_visible(&self, w: &linear::Vec3<f64>, u1: f64, u2: f64) -> linear::Vec3<f64> {
+        let sign = if w.z < 0.0 { -1.0 } else { 1.0 };
+        let vh = linear::Vec3 {x: self.alpha_x * w.x * sign, y: self.alpha_y * w.y * sign, z: w.z * sign}.normalize();
+        let len2 = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if len2 > 0.0 { linear::Vec3 {x: -vh.y, y: vh.x, z: 0.0} / len2.sqrt() } else { linear::X };
+        let t2 = &vh ^ &t1;
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = &(&(&t1 * p1) + &(&t2 * p2)) + &(&vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt());
+        linear::Vec3 {x: self.alpha_x * nh.x, y: self.alpha_y * nh.y, z: nh.z.max(1e-6)}.normalize()
+    }
+}
+
+// Mirrors w about the normal m.
+pub fn reflect(w: &linear::Vec3<f64>, m: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+    &(m * (2.0 * (w * m))) - w
+}
+
+// Unpolarized Fresnel reflectance of a conductor with complex index of
+// refraction eta + ik, for the cosine of the incident angle.
+pub fn fresnel_conductor(cos: f64, eta: f64, k: f64) -> f64 {
+    let cos = cos.clamp(0.0, 1.0);
+    let cos2 = cos * cos;
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+    let t0 = eta2 - k2 - sin2;
+    let a2b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2b2 + cos2;
+    let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+// Refracts w through a surface with normal m on its side, for the relative
+// index eta (transmitted over incident). None on total internal reflection.
+pub fn refract(w: &linear::Vec3<f64>, m: &linear::Vec3<f64>, eta: f64) -> Option<linear::Vec3<f64>> {
+    let cos_i = w * m;
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(&(m * (cos_i / eta - cos_t)) - &(w / eta))
+}
+
+// Unpolarized Fresnel reflectance at a dielectric boundary, for the cosine of
+// the incident angle and the relative index eta (transmitted over incident).
+pub fn fresnel_dielectric(cos: f64, eta: f64) -> f64 {
+    let cos_i = cos.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parl = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_parl * r_parl + r_perp * r_perp)
+}
+
+// Reflectance of a thin film of index film over a substrate of complex index
+// eta + ik, both relative to the incident medium, from the interference of
+// the light bouncing inside the film (Airy summation). The cosine is that of
+// the incident angle, and the thickness and wavelength in the incident
+// medium are in the same units.
+pub fn fresnel_thin_film(cos: f64, film: f64, thickness: f64, eta: f64, k: f64, lambda: f64) -> f64 {
+    let cos0 = Complex64::new(cos.clamp(0.0, 1.0), 0.0);
+    let sin2 = 1.0 - cos0 * cos0;
+    let (n1, n2) = (Complex64::new(film, 0.0), Complex64::new(eta, k));
+    let cos1 = (1.0 - sin2 / (n1 * n1)).sqrt();
+    let cos2 = (1.0 - sin2 / (n2 * n2)).sqrt();
+    let phase = (Complex64::i() * (4.0 * PI * thickness / lambda) * n1 * cos1).exp();
+    let airy = |r01: Complex64, r12: Complex64| ((r01 + r12 * phase) / (1.0 + r01 * r12 * phase)).norm_sqr();
+    let rs = airy((cos0 - n1 * cos1) / (cos0 + n1 * cos1), (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2));
+    let rp = airy((n1 * cos0 - cos1) / (n1 * cos0 + cos1), (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2));
+    (0.5 * (rs + rp)).clamp(0.0, 1.0)
+}
+
+// Microfacet transmission from wo (above the surface) to wi (below it) for
+// the relative index rel (Walter et al. 2007). Returns the BSDF value, with
+// radiance scaled by the squared relative index, and the density of sampling
+// wi by refracting through a visible normal.
+pub fn transmission(ggx: &Ggx, wo: &linear::Vec3<f64>, wi: &linear::Vec3<f64>, rel: f64) -> (f64, f64) {
+    let mut wm = (&(wi * rel) + wo).normalize();
+    if wm.z < 0.0 {
+        wm = -wm;
+    }
+    let (cos_o, cos_i) = (wo * &wm, wi * &wm);
+    if cos_i >= 0.0 || cos_o <= 0.0 || wi.z >= 0.0 {
+        return (0.0, 0.0);
+    }
+    let t = 1.0 - fresnel_dielectric(cos_o, rel);
+    let denom = (cos_i + cos_o / rel).powi(2);
+    let f = ggx.d(&wm) * ggx.g(wo, wi) * t * (cos_i * cos_o).abs() / (wi.z.abs() * wo.z * denom * rel * rel);
+    (f, ggx.pdf(wo, &wm) * cos_i.abs() / denom)
+}
+
+// Berry (GTR1) distribution used for clear coats, normalized over the
+// hemisphere for alpha below one.
+pub fn gtr1(cos: f64, alpha: f64) -> f64 {
+    if cos <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * cos * cos))
+}
+
+// Samples a normal with density gtr1() * cos.
+pub fn sample_gtr1(alpha: f64, u1: f64, u2: f64) -> linear::Vec3<f64> {
+    let a2 = alpha * alpha;
+    let cos = ((1.0 - a2.powf(1.0 - u1)) / (1.0 - a2)).max(0.0).sqrt();
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    linear::Vec3 {x: sin * phi.cos(), y: sin * phi.sin(), z: cos}
+}
+
+// Charlie sheen distribution for cloth, of fibers standing up from the
+// surface (Estevez and Kulla 2017).
+pub fn charlie(cos: f64, alpha: f64) -> f64 {
+    let inv = 1.0 / alpha.max(1e-3);
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    (2.0 + inv) * sin.powf(inv) / (2.0 * PI)
+}
+
+// Weight of the grazing angle term in Schlick's Fresnel approximation.
+pub fn schlick_weight(cos: f64) -> f64 {
+    (1.0 - cos).clamp(0.0, 1.0).powi(5)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Midpoint sum of f over the upper hemisphere of directions.
+    fn hemisphere(f: impl Fn(&linear::Vec3<f64>) -> f64) -> f64 {
+        let (rows, cols) = (800, 800);
+        let mut total = 0.0;
+        for i in 0..rows {
+            let theta = 0.5 * PI * (i as f64 + 0.5) / rows as f64;
+            let dw = (0.5 * PI / rows as f64) * (2.0 * PI / cols as f64) * theta.sin();
+            for j in 0..cols {
+                let phi = 2.0 * PI * (j as f64 + 0.5) / cols as f64;
+                total += f(&linear::Vec3 {x: theta.sin() * phi.cos(), y: theta.sin() * phi.sin(), z: theta.cos()}) * dw;
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn visible_normal_densities_integrate_to_one() {
+        for ggx in [Ggx::new(0.5, 0.0), Ggx::new(0.7, 0.6)] {
+            assert!((hemisphere(|wm| ggx.d(wm) * wm.z) - 1.0).abs() < 1e-3);
+            for w in [linear::Vec3 {x: 0.0, y: 0.0, z: 1.0}, linear::Vec3 {x: 0.6, y: -0.3, z: 0.4}.normalize()] {
+                assert!((hemisphere(|wm| ggx.pdf(&w, wm)) - 1.0).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn sampled_normals_face_the_viewer() {
+        let ggx = Ggx::new(0.6, 0.3);
+        let w = linear::Vec3 {x: 0.8, y: 0.1, z: 0.2}.normalize();
+        for i in 0..32 {
+            for j in 0..32 {
+                let wm = ggx.sample_visible(&w, (i as f64 + 0.5) / 32.0, (j as f64 + 0.5) / 32.0);
+                assert!(wm.z > 0.0 && &w * &wm >= -1e-9 && ggx.pdf(&w, &wm) > 0.0);
+            }
+        }
+    }
+}
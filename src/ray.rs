@@ -1,71 +1,286 @@
 use crate::linear;
+use crate::material;
+use crate::medium;
+use crate::microfacet;
 use crate::scene;
-use crate::scene::Intersection;
+use crate::sampling;
+use crate::spectrum;
+
+// Most scattering events followed in a medium before giving up on the path.
+const MAX_WALK: usize = 256;
+
+// Identity of a material, for matching the media it encloses.
+pub fn owner(mat: &dyn material::Material) -> usize {
+    mat as *const dyn material::Material as *const u8 as usize
+}
 
 pub struct Ray {
     pub origin: linear::Vec3<f64>,
     pub traj: linear::Vec3<f64>,
+    pub lambda: Option<spectrum::Wavelengths>,
+    // Density the direction was sampled with, zero for camera rays and specular bounces.
+    pub pdf: f64,
+    // Media the ray is inside, innermost last.
+    pub media: Vec<medium::Medium>,
+    // Width of the area the ray stands for at its origin and its growth per
+    // unit distance, a cone around the ray for filtering textures.
+    pub width: f64,
+    pub spread: f64,
 }
 
 impl Ray {
+    // Width of the ray's cone at distance t.
+    pub fn footprint(&self, t: f64) -> f64 {
+        self.width + self.spread * t
+    }
+
+    // Converts an RGB albedo into the color space carried by this ray.
+    pub fn reflectance(&self, rgb: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        match &self.lambda {
+            Some(wl) => spectrum::reflectance(rgb, wl),
+            None => rgb.copy(),
+        }
+    }
+
+    // Reflectance given as a spectrum, in the color space carried by this ray.
+    pub fn spectrum(&self, f: impl Fn(f64) -> f64) -> linear::Vec3<f64> {
+        match &self.lambda {
+            Some(wl) => spectrum::sampled(f, wl),
+            None => spectrum::reflectance_rgb(f),
+        }
+    }
+
+    // Converts an RGB emission color into the color space carried by this ray.
+    pub fn illuminant(&self, rgb: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        match &self.lambda {
+            Some(wl) => spectrum::illuminant(rgb, wl),
+            None => rgb.copy(),
+        }
+    }
+
+    // Index of refraction on the other side of the boundary of the medium
+    // the owner encloses: the medium the ray is in when entering, or the one
+    // enclosing the owner's medium when leaving it.
+    pub fn outside_ior(&self, owner: usize, entering: bool) -> f64 {
+        let mut media = self.media.iter().rev();
+        if !entering {
+            media.position(|m| m.owner == owner);
+        }
+        media.next().map_or(1.0, |m| m.ior)
+    }
+
+    // Index on the far side of the boundary of a medium of index eta, enclosed
+    // by the material hit, over the index on the near side.
+    pub fn relative_ior(&self, eta: f64, inter: &scene::Intersection, entering: bool) -> f64 {
+        let outside = self.outside_ior(owner(inter.mat), entering);
+        if entering { eta / outside } else { outside / eta }
+    }
+
+    // Records crossing into or out of a medium.
+    fn cross(&mut self, medium: medium::Medium, entering: bool) {
+        if entering {
+            self.media.push(medium);
+        } else if let Some(i) = self.media.iter().rposition(|m| m.owner == medium.owner) {
+            self.media.remove(i);
+        }
+    }
+
+    // Carries the ray through the medium it is in up to the next surface,
+    // scattering on the way. Returns the throughput and the surface hit, or
+    // None if the walk ran too long.
+    fn propagate<'a>(&mut self, scene: &scene::Scene<'a>) -> Option<(linear::Vec3<f64>, Option<scene::Hit<'a>>)> {
+        let mut throughput = scene::WHITE.copy();
+        for _ in 0..MAX_WALK {
+            let hit = scene.hit(self);
+            let medium = match self.media.last() {
+                Some(m) if m.interacts() => m,
+                _ => return Some((throughput, hit)),
+            };
+            let dist = hit.as_ref().map_or(scene::T_MAX, |(_, inter)| inter.t);
+            let (weight, scattered) = medium.sample(dist);
+            throughput = throughput.color_prod(&weight);
+            match scattered {
+                Some(d) => {
+                    self.origin = &self.origin + &(&self.traj * d);
+                    self.traj = sampling::uniform_sphere(rand::random(), rand::random());
+                    // No light sampling inside media, so hits count in full.
+                    self.pdf = 0.0;
+                },
+                None => return Some((throughput, hit)),
+            }
+        }
+        None
+    }
+
+    // Light entering the surface at inter and diffusing out at a point found
+    // by probing the object along one of three axes (Christensen and Burley
+    // 2015), then reflected out from there.
+    fn subsurface(&mut self, scene: &scene::Scene, bg: &linear::Vec3<f64>, depth: u8,
+                  inter: &scene::Intersection, profile: &medium::Diffusion) -> linear::Vec3<f64> {
+        let frame = linear::Frame::new(&inter.norm);
+        let axes = [(&frame.n, &frame.s, &frame.t, 0.5), (&frame.s, &frame.t, &frame.n, 0.25), (&frame.t, &frame.n, &frame.s, 0.25)];
+        let u = rand::random::<f64>();
+        let (axis, v1, v2, _) = if u < 0.5 { axes[0] } else if u < 0.75 { axes[1] } else { axes[2] };
+        let c = ((rand::random::<f64>() * 3.0) as usize).min(2);
+        let r = profile.sample_radius(c, rand::random(), rand::random());
+        let r_max = profile.max_radius();
+        if r >= r_max {
+            return scene::BLACK.copy();
+        }
+        let phi = 2.0 * std::f64::consts::PI * rand::random::<f64>();
+        let h = (r_max * r_max - r * r).sqrt();
+        let start = &(&inter.pos + &(&(v1 * (r * phi.cos())) + &(v2 * (r * phi.sin())))) + &(axis * h);
+        let probe = Ray {origin: start, traj: -axis, lambda: None, pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0};
+        let hits = scene.probe(&probe, 2.0 * h, inter.mat);
+        if hits.is_empty() {
+            return scene::BLACK.copy();
+        }
+        let exit = &hits[((rand::random::<f64>() * hits.len() as f64) as usize).min(hits.len() - 1)];
+
+        // Density of reaching the exit over all axes and channels.
+        let offset = &exit.pos - &inter.pos;
+        let mut pdf = 0.0;
+        for (a, _, _, p) in axes {
+            let along = &offset * a;
+            let radial = ((&offset * &offset) - along * along).max(0.0).sqrt();
+            pdf += p * profile.pdf_radius(radial) * (&exit.norm * a).abs();
+        }
+        if pdf <= 0.0 {
+            return scene::BLACK.copy();
+        }
+        let throughput = profile.eval(offset.norm()) * (hits.len() as f64 / pdf);
+
+        // Light leaving the exit point, by light sampling and a cosine bounce.
+        let mut color = scene::BLACK.copy();
+        let exit_frame = linear::Frame::new(&exit.norm);
+        if let Some(ls) = scene.pick_light(&exit.pos).and_then(|(light, pmf)| {
+            light.sample(&exit.pos, &self.lambda).map(|mut ls| { ls.pdf *= pmf; ls })
+        }) {
+            let cos = &ls.ray.traj * &exit.norm;
+            if cos > 0.0 && !scene.occluded(&ls.ray, ls.dist) {
+                let weight = if ls.delta { 1.0 } else { sampling::power_heuristic(ls.pdf, cos * std::f64::consts::FRAC_1_PI) };
+                color = color + &ls.radiance * (profile.leaving(cos) * cos * weight / ls.pdf);
+            }
+        }
+        let local = sampling::cosine_hemisphere(rand::random(), rand::random());
+        self.origin = exit.pos.copy();
+        self.traj = exit_frame.to_world(&local);
+        self.pdf = local.z * std::f64::consts::FRAC_1_PI;
+        let weight = profile.leaving(local.z) / self.pdf * local.z;
+        color = color + self.trace(scene, bg, depth - 1) * weight;
+        color.color_prod(&throughput)
+    }
+
+    // Radiance of a ray leaving the scene: the background plus any lights at
+    // infinity, weighted against light sampling as for emissive hits.
+    fn escape(&self, scene: &scene::Scene, bg: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+        let mut color = self.illuminant(bg);
+        for light in &scene.lights {
+            let radiance = light.background(self);
+            if self.pdf > 0.0 {
+                let pdf = light.pdf(&self.origin, &self.traj) * scene.light_pmf(*light, &self.origin);
+                color = color + radiance * sampling::power_heuristic(self.pdf, pdf);
+            } else {
+                color = color + radiance;
+            }
+        }
+        color
+    }
+
+    // Path traces the ray, sampling one light picked by the scene at each
+    // bounce and combining with BSDF sampling through multiple importance
+    // sampling.
     pub fn trace(&mut self, scene: &scene::Scene, bg: &linear::Vec3<f64>, depth: u8) -> linear::Vec3<f64> {
         if depth == 0 {
             return scene::BLACK.copy();
         }
-        let mut color = bg.copy();
-        let mut min = 1000.0;
-        let mut finter: Option<Intersection> = Option::None;
+        let (throughput, hit) = match self.propagate(scene) {
+            Some(walk) => walk,
+            None => return scene::BLACK.copy(),
+        };
+        let (obj, inter) = match hit {
+            Some(hit) => hit,
+            None => return self.escape(scene, bg).color_prod(&throughput),
+        };
 
-        for obj in &scene.objects {
-            let inter = obj.intersect(&self);
-            if inter.t >= 0.001 && inter.t <= min && &self.traj * &inter.norm <= 0.0 {
-                min = inter.t;
-                finter.replace(inter);
+        let mut color = inter.mat.emit(self, &inter);
+        if self.pdf > 0.0 {
+            if let Some(light) = obj.light() {
+                let pdf = light.pdf(&self.origin, &self.traj) * scene.light_pmf(light, &self.origin);
+                color = color * sampling::power_heuristic(self.pdf, pdf);
             }
         }
-        
-        if finter.is_some() {
-            let inter = finter.unwrap();
-            let ncos = &-&self.traj * &inter.norm;
-            let mut lcolor: linear::Vec3<f64> = linear::Vec3::new();
-            for light in &scene.lights {
-                let mut hit = true;
-                let sray = Ray {
-                    origin: inter.pos.copy(),
-                    traj: light.sample(inter.pos.copy()).normalize()
-                };
-                let lscale = inter.mat.pdf(&sray.traj, &inter.norm);
-                if lscale <= 0.001 {
-                    continue;
-                }
-                let lcos = &sray.traj * &inter.norm;
-                if lcos < 0.0 {
-                    continue;
-                }
-                let linter = light.intersect(&sray);
-                for obj in &scene.objects {
-                    let sinter = obj.intersect(&sray);
-                    if sinter.t >= 0.001 && sinter.t < linter.t && &sray.traj * &sinter.norm <= 0.0 {
-                        hit = false;
-                        break;
-                    }
-                }
-                if hit {
-                    lcolor = lcolor + (lcos * lscale * linter.mat.bsdf(1.0, 0.0));
-                }
+
+        if inter.mat.dispersive() {
+            if let Some(wl) = self.lambda.as_mut() {
+                wl.terminate_secondary();
             }
-            color = inter.mat.bsdf(0.0, 0.0,).color_prod(&lcolor);
-            self.traj = inter.mat.sample(&self.traj, &inter.norm);
-            let tmp = self.traj.norm();
-            if tmp != 0.0 {
-                self.traj = &self.traj / tmp;
-                self.origin = inter.pos;
-                color = color + inter.mat.bsdf(0.0, 0.0).color_prod(&self.trace(scene, bg, depth-1));
-            } else {
-                color = color + inter.mat.bsdf(0.0, 0.0);
+        }
+
+        if let Some(ls) = scene.pick_light(&inter.pos).and_then(|(light, pmf)| {
+            light.sample(&inter.pos, &self.lambda).map(|mut ls| { ls.pdf *= pmf; ls })
+        }) {
+            let f = inter.mat.bsdf(self, &inter, &ls.ray.traj);
+            if (f.x > 0.0 || f.y > 0.0 || f.z > 0.0) && !scene.occluded(&ls.ray, ls.dist) {
+                let cos = (&ls.ray.traj * &inter.norm).abs();
+                let weight = if ls.delta { 1.0 } else { sampling::power_heuristic(ls.pdf, inter.mat.pdf(self, &inter, &ls.ray.traj)) };
+                color = color + f.color_prod(&ls.radiance) * (cos * weight / ls.pdf);
             }
         }
 
-        color
+        // Diffusing materials send light under the surface unless it reflects.
+        let mut choice = 1.0;
+        if let Some(profile) = inter.mat.diffusion(self, &inter) {
+            let cos = (&self.traj * &inter.norm).abs();
+            let reflect = microfacet::fresnel_dielectric(cos, self.relative_ior(profile.eta, &inter, true));
+            if reflect <= rand::random() {
+                let light = self.subsurface(scene, bg, depth, &inter, &profile);
+                return (color + light).color_prod(&throughput);
+            }
+            choice = reflect;
+        }
+
+        if let Some(scatter) = inter.mat.sample(self, &inter) {
+            let entering = &self.traj * &inter.geom < 0.0;
+            if entering == (&scatter.dir * &inter.geom < 0.0) {
+                if let Some(medium) = inter.mat.medium(self, &inter) {
+                    self.cross(medium::Medium {owner: owner(inter.mat), ..medium}, entering);
+                }
+            }
+            self.width = self.footprint(inter.t);
+            self.origin = inter.pos;
+            self.traj = scatter.dir;
+            self.pdf = scatter.pdf * choice;
+            color = color + scatter.weight.color_prod(&self.trace(scene, bg, depth-1)) / choice;
+        }
+
+        color.color_prod(&throughput)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn medium(ior: f64, owner: usize) -> medium::Medium {
+        medium::Medium {owner, ..medium::Medium::clear(ior)}
+    }
+
+    fn ray(media: Vec<medium::Medium>) -> Ray {
+        let zero = linear::Vec3 {x: 0.0, y: 0.0, z: 0.0};
+        Ray {origin: zero.copy(), traj: zero, lambda: None, pdf: 0.0, media, width: 0.0, spread: 0.0}
+    }
+
+    #[test]
+    fn leaving_matches_the_owner_not_the_index() {
+        // Two touching glass blocks of the same index inside water.
+        let mut r = ray(vec![medium(1.33, 1), medium(1.5, 2), medium(1.5, 3)]);
+        assert_eq!(r.outside_ior(2, false), 1.33);
+        assert_eq!(r.outside_ior(3, false), 1.5);
+        assert_eq!(r.outside_ior(4, true), 1.5);
+
+        r.cross(medium(1.5, 2), false);
+        let owners: Vec<usize> = r.media.iter().map(|m| m.owner).collect();
+        assert_eq!(owners, vec![1, 3]);
+    }
+}
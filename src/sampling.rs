@@ -0,0 +1,209 @@
+use std::f64::consts::PI;
+
+use crate::linear;
+
+// Cosine-weighted direction about +z, with density cos(theta) / pi.
+pub fn cosine_hemisphere(u1: f64, u2: f64) -> linear::Vec3<f64> {
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    linear::Vec3 {x: r * phi.cos(), y: r * phi.sin(), z: (1.0 - u1).max(0.0).sqrt()}
+}
+
+// Uniform direction inside the cone about +z with the given cosine of its
+// half angle, with density 1 / cone_solid_angle(cos_max).
+pub fn uniform_cone(u1: f64, u2: f64, cos_max: f64) -> linear::Vec3<f64> {
+    let cos = 1.0 - u1 * (1.0 - cos_max);
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    linear::Vec3 {x: sin * phi.cos(), y: sin * phi.sin(), z: cos}
+}
+
+pub fn cone_solid_angle(cos_max: f64) -> f64 {
+    2.0 * PI * (1.0 - cos_max)
+}
+
+// Uniform point on the unit sphere, with density 1 / (4 pi).
+pub fn uniform_sphere(u1: f64, u2: f64) -> linear::Vec3<f64> {
+    let z = 1.0 - 2.0 * u1;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    linear::Vec3 {x: r * phi.cos(), y: r * phi.sin(), z}
+}
+
+// Uniform barycentric coordinates over a triangle.
+pub fn uniform_triangle(u1: f64, u2: f64) -> (f64, f64) {
+    let su = u1.sqrt();
+    (1.0 - su, u2 * su)
+}
+
+// Multiple importance sampling weight of a strategy with density f against
+// one with density g (Veach's power heuristic, beta = 2).
+pub fn power_heuristic(f: f64, g: f64) -> f64 {
+    let ff = f * f;
+    let gg = g * g;
+    if ff + gg == 0.0 { 0.0 } else { ff / (ff + gg) }
+}
+
+// Piecewise constant distribution over [0, 1) proportional to a function
+// tabulated at equal intervals.
+pub struct Distribution1D {
+    pub func: Vec<f64>,
+    pub cdf: Vec<f64>,
+    pub integral: f64,
+}
+
+// Piecewise constant 2D distribution over [0, 1)^2, sampled through the
+// marginal over rows and then the conditional within the chosen row.
+pub struct Distribution2D {
+    pub conditional: Vec<Distribution1D>,
+    pub marginal: Distribution1D,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i].abs() / n as f64;
+        }
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = if integral > 0.0 { *c / integral } else { i as f64 / n as f64 };
+        }
+        Distribution1D {func, cdf, integral}
+    }
+
+    // Returns the sampled point, its density, and the bin it fell in.
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        let n = self.func.len();
+        let i = (self.cdf.partition_point(|&c| c <= u) - 1).min(n - 1);
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let du = if width > 0.0 { (u - self.cdf[i]) / width } else { 0.0 };
+        ((i as f64 + du) / n as f64, self.pdf_bin(i), i)
+    }
+
+    pub fn pdf(&self, x: f64) -> f64 {
+        let n = self.func.len();
+        self.pdf_bin(((x * n as f64) as usize).min(n - 1))
+    }
+
+    fn pdf_bin(&self, i: usize) -> f64 {
+        if self.integral > 0.0 { self.func[i].abs() / self.integral } else { 1.0 }
+    }
+}
+
+impl Distribution2D {
+    // Builds the distribution from row major values, height rows of width each.
+    pub fn new(values: &[f64], width: usize, height: usize) -> Self {
+        let conditional: Vec<Distribution1D> = (0..height)
+            .map(|y| Distribution1D::new(values[y * width..(y + 1) * width].to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral).collect());
+        Distribution2D {conditional, marginal}
+    }
+
+    // Returns the sampled (u, v) point and its density.
+    pub fn sample(&self, u1: f64, u2: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, row) = self.marginal.sample(u2);
+        let (u, pdf_u, _) = self.conditional[row].sample(u1);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let rows = self.conditional.len();
+        let row = ((v * rows as f64) as usize).min(rows - 1);
+        self.conditional[row].pdf(u) * self.marginal.pdf(v)
+    }
+}
+
+// Constant time sampling of a discrete distribution (Vose's alias method).
+pub struct AliasTable {
+    pub pmf: Vec<f64>,
+    prob: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    pub fn new(weights: &[f64]) -> Self {
+        let n = weights.len();
+        let total: f64 = weights.iter().sum();
+        let pmf: Vec<f64> = if total > 0.0 {
+            weights.iter().map(|w| w / total).collect()
+        } else {
+            vec![1.0 / n as f64; n]
+        };
+        let mut prob = vec![1.0; n];
+        let mut alias: Vec<usize> = (0..n).collect();
+        let mut scaled: Vec<f64> = pmf.iter().map(|p| p * n as f64).collect();
+        let mut small: Vec<usize> = (0..n).filter(|&i| scaled[i] < 1.0).collect();
+        let mut large: Vec<usize> = (0..n).filter(|&i| scaled[i] >= 1.0).collect();
+        while let (Some(s), Some(&l)) = (small.pop(), large.last()) {
+            prob[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] -= 1.0 - scaled[s];
+            if scaled[l] < 1.0 {
+                large.pop();
+                small.push(l);
+            }
+        }
+        AliasTable {pmf, prob, alias}
+    }
+
+    pub fn sample(&self, u: f64) -> (usize, f64) {
+        let n = self.prob.len();
+        let f = u * n as f64;
+        let i = (f as usize).min(n - 1);
+        let j = if f - (i as f64) < self.prob[i] { i } else { self.alias[i] };
+        (j, self.pmf[j])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEPS: usize = 1200;
+
+    #[test]
+    fn distribution_1d_densities_integrate_to_one() {
+        let d = Distribution1D::new(vec![0.0, 3.0, 1.0, 0.5, 0.0, 2.0]);
+        let total: f64 = (0..STEPS).map(|i| d.pdf((i as f64 + 0.5) / STEPS as f64) / STEPS as f64).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        for i in 0..STEPS {
+            let (x, pdf, bin) = d.sample((i as f64 + 0.5) / STEPS as f64);
+            assert!(pdf > 0.0 && d.func[bin] > 0.0);
+            assert!((pdf - d.pdf(x)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn distribution_2d_densities_integrate_to_one() {
+        let values = [1.0, 0.0, 4.0, 2.0, 0.0, 0.0, 0.5, 0.5, 3.0, 1.0, 1.0, 6.0];
+        let d = Distribution2D::new(&values, 4, 3);
+        let n = 120;
+        let mut total = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                total += d.pdf((i as f64 + 0.5) / n as f64, (j as f64 + 0.5) / n as f64) / (n * n) as f64;
+            }
+        }
+        assert!((total - 1.0).abs() < 1e-9);
+        let ((u, v), pdf) = d.sample(0.3, 0.8);
+        assert!((pdf - d.pdf(u, v)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn alias_table_picks_by_its_pmf() {
+        let table = AliasTable::new(&[1.0, 0.0, 5.0, 2.0, 2.0]);
+        assert!((table.pmf.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        let mut counts = [0usize; 5];
+        for i in 0..STEPS * 10 {
+            let (j, pmf) = table.sample((i as f64 + 0.5) / (STEPS * 10) as f64);
+            assert_eq!(pmf, table.pmf[j]);
+            counts[j] += 1;
+        }
+        for (count, pmf) in counts.iter().zip(&table.pmf) {
+            assert!((*count as f64 / (STEPS * 10) as f64 - pmf).abs() < 1e-3);
+        }
+    }
+}
@@ -1,16 +1,23 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
 use crate::linear;
 use crate::ray;
 use crate::material;
+use crate::sampling;
+use crate::spectrum;
+use crate::lightbvh;
 
 pub const WHITE: linear::Vec3<f64> = linear::Vec3{x: 1.0, y: 1.0, z: 1.0};
 pub const BLACK: linear::Vec3<f64> = linear::Vec3{x: 0.0, y: 0.0, z: 0.0};
-pub const RED: linear::Vec3<f64> = linear::Vec3{x: 1.0, y: 0.0, z: 0.0};
-pub const PURPLE: linear::Vec3<f64> = linear::Vec3{x: 1.0, y: 0.0, z: 1.0};
-pub const BLUE: linear::Vec3<f64> = linear::Vec3{x: 0.0, y: 0.0, z: 1.0};
-pub const GREEN: linear::Vec3<f64> = linear::Vec3{x: 0.0, y: 1.0, z: 0.0};
-pub const SKY: linear::Vec3<f64> = linear::Vec3{x: 0.2, y: 0.4, z: 0.9};
 pub const SKY_DARK: linear::Vec3<f64> = linear::Vec3{x: 0.1, y: 0.1, z: 0.1};
 
+// Closest and farthest hit distances accepted along a ray.
+pub const T_MIN: f64 = 0.001;
+pub const T_MAX: f64 = 1000.0;
+// Most cut out hits on one object a ray passes before giving up.
+const MAX_CUTOUTS: usize = 64;
+
 pub enum FaceAxis {
     FaceX,
     FaceY,
@@ -18,22 +25,102 @@ pub enum FaceAxis {
 }
 
 pub trait SceneObject {
-    fn intersect(&self, r: &ray::Ray) -> Intersection;
+    fn intersect(&self, r: &ray::Ray) -> Intersection<'_>;
+
+    // The object as a light, if its material emits.
+    fn light(&self) -> Option<&dyn LightSource> {
+        None
+    }
 }
 
 pub trait LightSource {
-    fn sample(&self, from: linear::Vec3<f64>) -> linear::Vec3<f64>;
+    // Samples a direction from a point towards the light. The pdf is with
+    // respect to solid angle at the point.
+    fn sample(&self, from: &linear::Vec3<f64>, lambda: &Option<spectrum::Wavelengths>) -> Option<LightSample>;
+    // Solid angle density with which sample() would produce the direction.
+    fn pdf(&self, from: &linear::Vec3<f64>, dir: &linear::Vec3<f64>) -> f64;
+
+    // Radiance reaching a ray that escapes the scene, for lights at infinity.
+    fn background(&self, _ray: &ray::Ray) -> linear::Vec3<f64> {
+        BLACK.copy()
+    }
+
+    // Extent, emission cone and power of the light, used to choose between
+    // lights. None for lights at infinity.
+    fn bounds(&self) -> Option<lightbvh::LightBounds> {
+        None
+    }
 }
 
-pub trait LightObject: SceneObject + LightSource {}
+pub struct LightSample {
+    pub ray: ray::Ray,
+    pub dist: f64,
+    pub pdf: f64,
+    pub radiance: linear::Vec3<f64>,
+    // Delta lights cannot be hit by rays, so are not weighted against BSDF sampling.
+    pub delta: bool,
+}
 
 pub struct Intersection<'a> {
     pub t: f64,
     pub pos: linear::Vec3<f64>,
+    // Normal for shading, which normal and bump maps bend, and the normal of
+    // the surface itself, which decides the side a ray is on.
     pub norm: linear::Vec3<f64>,
+    pub geom: linear::Vec3<f64>,
     pub mat: &'a dyn material::Material,
+    // Texture coordinates, and the width in them of the area the ray stands
+    // for at the hit, for filtering textures.
+    pub uv: (f64, f64),
+    pub footprint: f64,
+    // Change in position per unit of u and of v, zero where the surface has
+    // no texture coordinates.
+    pub tangent: linear::Vec3<f64>,
+    pub bitangent: linear::Vec3<f64>,
+}
+
+impl<'a> Intersection<'a> {
+    // Frame around the shading normal with s following u and t following v,
+    // or any frame if the texture coordinates do not vary.
+    pub fn tangent_frame(&self) -> linear::Frame {
+        let s = &self.tangent - &(&self.norm * (&self.tangent * &self.norm));
+        if s.norm() < 1e-9 {
+            return linear::Frame::new(&self.norm);
+        }
+        let s = s.normalize();
+        let t = &self.norm ^ &s;
+        let t = if &t * &self.bitangent < 0.0 { -t } else { t };
+        linear::Frame {s, t, n: self.norm.copy()}
+    }
+
+    // The hit moved by du and dv in texture coordinates, to difference
+    // textures across the surface.
+    pub fn offset(&self, du: f64, dv: f64) -> Intersection<'a> {
+        Intersection {
+            t: self.t,
+            pos: &self.pos + &(&(&self.tangent * du) + &(&self.bitangent * dv)),
+            norm: self.norm.copy(),
+            geom: self.geom.copy(),
+            mat: self.mat,
+            uv: (self.uv.0 + du, self.uv.1 + dv),
+            footprint: self.footprint,
+            tangent: self.tangent.copy(),
+            bitangent: self.bitangent.copy(),
+        }
+    }
+
+    // Bends the shading normal as the material asks.
+    fn shade(mut self) -> Self {
+        if let Some(norm) = self.mat.shading_normal(&self) {
+            self.norm = norm;
+        }
+        self
+    }
 }
 
+// An object hit by a ray, and where.
+pub type Hit<'a> = (&'a dyn SceneObject, Intersection<'a>);
+
 pub struct Sphere<'a> {
     pub pos: linear::Vec3<f64>,
     pub rad: f64,
@@ -50,22 +137,248 @@ pub struct Face<'a> {
     pub mat: &'a dyn material::Material,
 }
 
+// Single sided triangle, front facing when a, b, c wind counter-clockwise.
+// Texture coordinates are given at each corner.
+pub struct Triangle<'a> {
+    pub a: linear::Vec3<f64>,
+    pub b: linear::Vec3<f64>,
+    pub c: linear::Vec3<f64>,
+    pub uv: [(f64, f64); 3],
+    pub mat: &'a dyn material::Material,
+}
+
+// How a light is picked for each shadow ray. Lights at infinity are always
+// picked uniformly, sharing the choice equally with the bounded lights.
+pub enum LightStrategy {
+    Uniform,
+    // In proportion to emitted power.
+    Power,
+    // By estimated contribution to the shaded point, through a light BVH.
+    Bvh,
+}
+
+impl LightStrategy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "uniform" => Some(LightStrategy::Uniform),
+            "power" => Some(LightStrategy::Power),
+            "bvh" => Some(LightStrategy::Bvh),
+            _ => None,
+        }
+    }
+
+    pub fn copy(&self) -> Self {
+        match self {
+            LightStrategy::Uniform => LightStrategy::Uniform,
+            LightStrategy::Power => LightStrategy::Power,
+            LightStrategy::Bvh => LightStrategy::Bvh,
+        }
+    }
+}
+
+enum Selection {
+    Table(sampling::AliasTable),
+    Bvh(lightbvh::LightBvh),
+}
+
+struct LightSelector {
+    infinite: Vec<usize>,
+    bounded: Vec<usize>,
+    selection: Selection,
+    // Light address to whether it is bounded and its index in that group.
+    index: HashMap<usize, (bool, usize)>,
+}
+
 pub struct Scene<'a> {
     pub objects: Vec<&'a dyn SceneObject>,
-    pub lights: Vec<&'a dyn LightObject>,
+    pub lights: Vec<&'a dyn LightSource>,
+    pub strategy: LightStrategy,
+    selector: Option<LightSelector>,
 }
 
-impl<'a> SceneObject for Face<'a> {
-    fn intersect(&self, r: &ray::Ray) -> Intersection {
-        let mut result = Intersection {t: -1.0, pos: linear::Vec3::new(), norm: linear::Vec3::new(), mat: self.mat};
-        result.norm = match self.facing {
+impl<'a> Default for Scene<'a> {
+    fn default() -> Self {
+        Scene::new()
+    }
+}
+
+fn address(light: &dyn LightSource) -> usize {
+    light as *const dyn LightSource as *const u8 as usize
+}
+
+impl<'a> Scene<'a> {
+    pub fn new() -> Self {
+        Scene {objects: Vec::new(), lights: Vec::new(), strategy: LightStrategy::Bvh, selector: None}
+    }
+
+    // Adds every emissive object to the lights, unless it is already there,
+    // and builds the structure lights are picked from.
+    pub fn prepare(&mut self) {
+        for obj in &self.objects {
+            if let Some(light) = obj.light() {
+                if !self.lights.iter().any(|l| address(*l) == address(light)) {
+                    self.lights.push(light);
+                }
+            }
+        }
+
+        let mut infinite = Vec::new();
+        let mut bounded = Vec::new();
+        let mut bounds = Vec::new();
+        let mut index = HashMap::new();
+        for (i, light) in self.lights.iter().enumerate() {
+            match light.bounds() {
+                Some(b) => {
+                    index.insert(address(*light), (true, bounded.len()));
+                    bounded.push(i);
+                    bounds.push(b);
+                },
+                None => {
+                    index.insert(address(*light), (false, infinite.len()));
+                    infinite.push(i);
+                },
+            }
+        }
+        let selection = match self.strategy {
+            LightStrategy::Uniform => Selection::Table(sampling::AliasTable::new(&vec![1.0; bounds.len()])),
+            LightStrategy::Power => Selection::Table(sampling::AliasTable::new(&bounds.iter().map(|b| b.power).collect::<Vec<_>>())),
+            LightStrategy::Bvh => Selection::Bvh(lightbvh::LightBvh::new(bounds)),
+        };
+        self.selector = Some(LightSelector {infinite, bounded, selection, index});
+    }
+
+    // Number of equally likely choices between the lights at infinity and
+    // the bounded lights as a whole.
+    fn choices(selector: &LightSelector) -> usize {
+        selector.infinite.len() + if selector.bounded.is_empty() { 0 } else { 1 }
+    }
+
+    // Picks one light to sample from a point, with the probability of picking it.
+    pub fn pick_light(&self, from: &linear::Vec3<f64>) -> Option<(&'a dyn LightSource, f64)> {
+        let selector = self.selector.as_ref()?;
+        let n = Scene::choices(selector);
+        if n == 0 {
+            return None;
+        }
+        let f = rand::random::<f64>() * n as f64;
+        let k = (f as usize).min(n - 1);
+        if k < selector.infinite.len() {
+            return Some((self.lights[selector.infinite[k]], 1.0 / n as f64));
+        }
+        let u = f - k as f64;
+        let (i, pmf) = match &selector.selection {
+            Selection::Table(table) => table.sample(u),
+            Selection::Bvh(bvh) => bvh.sample(from, u)?,
+        };
+        Some((self.lights[selector.bounded[i]], pmf / n as f64))
+    }
+
+    // Probability that pick_light() chooses the light from a point.
+    pub fn light_pmf(&self, light: &dyn LightSource, from: &linear::Vec3<f64>) -> f64 {
+        let selector = match self.selector.as_ref() {
+            Some(selector) => selector,
+            None => return 0.0,
+        };
+        let n = Scene::choices(selector) as f64;
+        match selector.index.get(&address(light)) {
+            Some((false, _)) => 1.0 / n,
+            Some((true, i)) => match &selector.selection {
+                Selection::Table(table) => table.pmf[*i] / n,
+                Selection::Bvh(bvh) => bvh.pmf(from, *i) / n,
+            },
+            None => 0.0,
+        }
+    }
+
+    // Closest hit along the ray. Back faces are culled by the geometric
+    // normal, except on two sided materials.
+    pub fn hit(&self, r: &ray::Ray) -> Option<Hit<'a>> {
+        let mut min = T_MAX;
+        let mut result = None;
+        for obj in &self.objects {
+            let obj: &'a dyn SceneObject = *obj;
+            let inter = surface(obj, r);
+            if inter.t >= T_MIN && inter.t <= min && (&r.traj * &inter.geom <= 0.0 || inter.mat.two_sided()) {
+                min = inter.t;
+                result = Some((obj, inter));
+            }
+        }
+        result.map(|(obj, inter)| (obj, inter.shade()))
+    }
+
+    // Every hit on surfaces of the given material along the ray up to dist,
+    // from either side.
+    pub fn probe(&self, r: &ray::Ray, dist: f64, mat: &dyn material::Material) -> Vec<Intersection<'a>> {
+        let addr = mat as *const dyn material::Material as *const u8;
+        let mut hits = Vec::new();
+        for obj in &self.objects {
+            let obj: &'a dyn SceneObject = *obj;
+            let mut ray = ray::Ray {origin: r.origin.copy(), traj: r.traj.copy(), lambda: None, pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0};
+            let mut travelled = 0.0;
+            loop {
+                let inter = obj.intersect(&ray);
+                if inter.t < T_MIN || travelled + inter.t > dist || !std::ptr::eq(inter.mat as *const dyn material::Material as *const u8, addr) {
+                    break;
+                }
+                travelled += inter.t;
+                ray.origin = inter.pos.copy();
+                hits.push(Intersection {t: travelled, ..inter});
+            }
+        }
+        hits
+    }
+
+    // Whether anything blocks the ray before the given distance.
+    pub fn occluded(&self, r: &ray::Ray, dist: f64) -> bool {
+        self.objects.iter().any(|obj| {
+            let inter = surface(*obj, r);
+            inter.t >= T_MIN && inter.t < dist - T_MIN && (&r.traj * &inter.geom <= 0.0 || inter.mat.two_sided())
+        })
+    }
+}
+
+impl<'a> Face<'a> {
+    fn normal(&self) -> linear::Vec3<f64> {
+        let norm = match self.facing {
             FaceAxis::FaceX => linear::X,
             FaceAxis::FaceY => linear::Y,
             FaceAxis::FaceZ => linear::Z,
         };
-        if self.d >= 0.0 {
-            result.norm = -result.norm;
+        if self.d >= 0.0 { -norm } else { norm }
+    }
+
+    fn area(&self) -> f64 {
+        (self.w2 - self.w1) * (self.h2 - self.h1)
+    }
+
+    fn point(&self, w: f64, h: f64) -> linear::Vec3<f64> {
+        match self.facing {
+            FaceAxis::FaceX => linear::Vec3 {x: self.d, y: w, z: h},
+            FaceAxis::FaceY => linear::Vec3 {x: h, y: self.d, z: w},
+            FaceAxis::FaceZ => linear::Vec3 {x: w, y: h, z: self.d},
         }
+    }
+
+    // Texture coordinates run from w1 to w2 and from h1 to h2.
+    fn uv(&self, p: &linear::Vec3<f64>) -> (f64, f64) {
+        let (w, h) = match self.facing {
+            FaceAxis::FaceX => (p.y, p.z),
+            FaceAxis::FaceY => (p.z, p.x),
+            FaceAxis::FaceZ => (p.x, p.y),
+        };
+        ((w - self.w1) / (self.w2 - self.w1), (h - self.h1) / (self.h2 - self.h1))
+    }
+
+    fn tangents(&self) -> (linear::Vec3<f64>, linear::Vec3<f64>) {
+        (self.point(self.w2, 0.0) - self.point(self.w1, 0.0), self.point(0.0, self.h2) - self.point(0.0, self.h1))
+    }
+}
+
+impl<'a> SceneObject for Face<'a> {
+    fn intersect(&self, r: &ray::Ray) -> Intersection<'_> {
+        let (tangent, bitangent) = self.tangents();
+        let mut result = Intersection {t: -1.0, pos: linear::Vec3::new(), norm: self.normal(), geom: self.normal(), mat: self.mat,
+                                       uv: (0.0, 0.0), footprint: 0.0, tangent, bitangent};
         let t = match self.facing {
             FaceAxis::FaceX => (self.d - r.origin.x) / r.traj.x,
             FaceAxis::FaceY => (self.d - r.origin.y) / r.traj.y,
@@ -83,42 +396,136 @@ impl<'a> SceneObject for Face<'a> {
                 if self.w1 <= p.x && p.x <= self.w2 && self.h1 <= p.y && p.y <= self.h2 { result.t = t; }
             },
         };
+        result.uv = self.uv(&p);
+        result.footprint = r.footprint(t) / self.area().sqrt();
         result.pos = p;
         result
     }
+
+    fn light(&self) -> Option<&dyn LightSource> {
+        if self.mat.emissive() { Some(self) } else { None }
+    }
+}
+
+impl<'a> LightSource for Face<'a> {
+    fn sample(&self, from: &linear::Vec3<f64>, lambda: &Option<spectrum::Wavelengths>) -> Option<LightSample> {
+        let w = self.w1 + rand::random::<f64>() * (self.w2 - self.w1);
+        let h = self.h1 + rand::random::<f64>() * (self.h2 - self.h1);
+        let pos = self.point(w, h);
+        let uv = self.uv(&pos);
+        sample_area(from, pos, self.normal(), uv, self.area(), self.mat, lambda)
+    }
+
+    fn pdf(&self, from: &linear::Vec3<f64>, dir: &linear::Vec3<f64>) -> f64 {
+        pdf_area(self, from, dir, self.area())
+    }
+
+    fn bounds(&self) -> Option<lightbvh::LightBounds> {
+        let norm = self.normal();
+        let power = self.mat.power() * self.area();
+        Some(lightbvh::LightBounds {min: self.point(self.w1, self.h1), max: self.point(self.w2, self.h2), axis: norm, cos_o: if self.mat.two_sided() { -1.0 } else { 1.0 }, cos_e: 0.0, power})
+    }
 }
 
 impl<'a> SceneObject for Sphere<'a> {
-    fn intersect(&self, r: &ray::Ray) -> Intersection {
-        let mut result = Intersection {t: -1.0, pos: linear::Vec3::new(), norm: linear::Vec3::new(), mat: &material::BLANK};
+    fn intersect(&self, r: &ray::Ray) -> Intersection<'_> {
+        let mut result = Intersection {t: -1.0, pos: linear::Vec3::new(), norm: linear::Vec3::new(), geom: linear::Vec3::new(), mat: &material::BLANK,
+                                       uv: (0.0, 0.0), footprint: 0.0, tangent: linear::Vec3::new(), bitangent: linear::Vec3::new()};
         let pc = &r.origin - &self.pos;
         let b = &r.traj * &pc;  
         let c = &pc*&pc - self.rad.powi(2);
         let disc = b.powi(2) - c;
         if disc >= 0.0 {
             let sdisc = (disc).sqrt();
-            if sdisc >= 0.0 {
+            // Near side, or the far side when starting inside the sphere.
+            if -b - sdisc >= T_MIN {
                 result.t = -b - sdisc;
                 result.pos = &r.origin + &(result.t * &r.traj);
             } else {
                 result.t = -b + sdisc;
                 result.pos = &r.origin + &(result.t * &r.traj);
             }
-    
+
             result.norm = (&result.pos - &self.pos).normalize();
+            result.geom = result.norm.copy();
             result.mat = self.mat;
+            result.uv = Sphere::uv(&result.norm);
+            (result.tangent, result.bitangent) = self.tangents(&result.norm);
+            result.footprint = r.footprint(result.t) / (2.0 * self.rad * PI.sqrt());
         }
         result
     }
+
+    fn light(&self) -> Option<&dyn LightSource> {
+        if self.mat.emissive() { Some(self) } else { None }
+    }
+}
+
+impl<'a> Sphere<'a> {
+    // Longitude and latitude of a point with the given normal, with v going
+    // up from the bottom pole.
+    fn uv(norm: &linear::Vec3<f64>) -> (f64, f64) {
+        (0.5 + norm.x.atan2(norm.z) / (2.0 * PI), 0.5 + norm.y.clamp(-1.0, 1.0).asin() / PI)
+    }
+
+    // Derivatives of position along the longitude and latitude, the latter
+    // left zero at the poles.
+    fn tangents(&self, norm: &linear::Vec3<f64>) -> (linear::Vec3<f64>, linear::Vec3<f64>) {
+        let ring = (norm.x * norm.x + norm.z * norm.z).sqrt();
+        let du = linear::Vec3 {x: norm.z, y: 0.0, z: -norm.x} * (2.0 * PI * self.rad);
+        if ring < 1e-9 {
+            return (du, linear::Vec3::new());
+        }
+        let dv = linear::Vec3 {x: -norm.y * norm.x / ring, y: ring, z: -norm.y * norm.z / ring} * (PI * self.rad);
+        (du, dv)
+    }
+
+    // Cosine of the half angle of the cone the sphere subtends from a point,
+    // or None from inside the sphere.
+    fn cone(&self, from: &linear::Vec3<f64>) -> Option<(linear::Vec3<f64>, f64)> {
+        let axis = &self.pos - from;
+        let d2 = &axis * &axis;
+        let r2 = self.rad * self.rad;
+        if d2 <= r2 {
+            return None;
+        }
+        let sin2 = r2 / d2;
+        let cos = (1.0 - sin2).sqrt();
+        Some((axis.normalize(), cos))
+    }
 }
 
 impl<'a> LightSource for Sphere<'a> {
-    fn sample(&self, from: linear::Vec3<f64>) -> linear::Vec3<f64> {
-        let mut vec = linear::Vec3::<f64>::rand(self.rad);
-        while vec.norm() > self.rad {
-            vec = linear::Vec3::<f64>::rand(self.rad);
+    // Samples the cone of directions subtended by the sphere.
+    fn sample(&self, from: &linear::Vec3<f64>, lambda: &Option<spectrum::Wavelengths>) -> Option<LightSample> {
+        let (axis, cos_max) = self.cone(from)?;
+        let frame = linear::Frame::new(&axis);
+        let dir = frame.to_world(&sampling::uniform_cone(rand::random(), rand::random(), cos_max));
+        let pc = from - &self.pos;
+        let b = &dir * &pc;
+        let disc = (b * b - (&pc * &pc - self.rad * self.rad)).max(0.0);
+        let dist = -b - disc.sqrt();
+        let pos = from + &(&dir * dist);
+        let norm = (&pos - &self.pos).normalize();
+        let ray = ray::Ray {origin: from.copy(), traj: dir, lambda: lambda.as_ref().map(|wl| wl.copy()), pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0};
+        let uv = Sphere::uv(&norm);
+        let (tangent, bitangent) = self.tangents(&norm);
+        let inter = Intersection {t: dist, pos, geom: norm.copy(), norm, mat: self.mat, uv, footprint: 0.0, tangent, bitangent};
+        let radiance = self.mat.emit(&ray, &inter);
+        Some(LightSample {ray, dist, pdf: sampling::cone_solid_angle(cos_max).recip(), radiance, delta: false})
+    }
+
+    fn pdf(&self, from: &linear::Vec3<f64>, dir: &linear::Vec3<f64>) -> f64 {
+        match self.cone(from) {
+            Some((axis, cos_max)) if dir * &axis >= cos_max => sampling::cone_solid_angle(cos_max).recip(),
+            _ => 0.0,
         }
-        &self.pos - &from + vec 
+    }
+
+    fn bounds(&self) -> Option<lightbvh::LightBounds> {
+        let r = linear::Vec3 {x: self.rad, y: self.rad, z: self.rad};
+        let power = self.mat.power() * 4.0 * PI * self.rad * self.rad;
+        Some(lightbvh::LightBounds {min: &self.pos - &r, max: &self.pos + &r, axis: linear::Y, cos_o: -1.0, cos_e: 0.0, power})
     }
 }
 
@@ -128,4 +535,147 @@ impl<'a> Clone for Sphere<'a> {
     }
 }
 
-impl<'a> LightObject for Sphere<'a> {}
\ No newline at end of file
+impl<'a> Triangle<'a> {
+    fn normal(&self) -> linear::Vec3<f64> {
+        ((&self.b - &self.a) ^ (&self.c - &self.a)).normalize()
+    }
+
+    fn area(&self) -> f64 {
+        ((&self.b - &self.a) ^ (&self.c - &self.a)).norm() * 0.5
+    }
+
+    // Texture coordinates at barycentric coordinates of b and c.
+    fn uv_at(&self, u: f64, v: f64) -> (f64, f64) {
+        let [a, b, c] = self.uv;
+        let w = 1.0 - u - v;
+        (a.0 * w + b.0 * u + c.0 * v, a.1 * w + b.1 * u + c.1 * v)
+    }
+
+    // Derivatives of position along u and v, zero if the texture coordinates
+    // are degenerate.
+    fn tangents(&self) -> (linear::Vec3<f64>, linear::Vec3<f64>) {
+        let [a, b, c] = self.uv;
+        let (du1, dv1, du2, dv2) = (b.0 - a.0, b.1 - a.1, c.0 - a.0, c.1 - a.1);
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() < 1e-12 {
+            return (linear::Vec3::new(), linear::Vec3::new());
+        }
+        let e1 = &self.b - &self.a;
+        let e2 = &self.c - &self.a;
+        ((&e1 * dv2 - &e2 * dv1) / det, (&e2 * du1 - &e1 * du2) / det)
+    }
+
+    // Texture coordinates per unit length on the triangle.
+    fn uv_density(&self) -> f64 {
+        let [a, b, c] = self.uv;
+        let uv_area = 0.5 * ((b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1)).abs();
+        (uv_area / self.area()).sqrt()
+    }
+}
+
+impl<'a> SceneObject for Triangle<'a> {
+    // Moller-Trumbore intersection.
+    fn intersect(&self, r: &ray::Ray) -> Intersection<'_> {
+        let (tangent, bitangent) = self.tangents();
+        let mut result = Intersection {t: -1.0, pos: linear::Vec3::new(), norm: self.normal(), geom: self.normal(), mat: self.mat,
+                                       uv: (0.0, 0.0), footprint: 0.0, tangent, bitangent};
+        let e1 = &self.b - &self.a;
+        let e2 = &self.c - &self.a;
+        let p = &r.traj ^ &e2;
+        let det = &e1 * &p;
+        if det.abs() < 1e-12 {
+            return result;
+        }
+        let inv = det.recip();
+        let s = &r.origin - &self.a;
+        let u = (&s * &p) * inv;
+        if !(0.0..=1.0).contains(&u) {
+            return result;
+        }
+        let q = &s ^ &e1;
+        let v = (&r.traj * &q) * inv;
+        if v < 0.0 || u + v > 1.0 {
+            return result;
+        }
+        result.t = (&e2 * &q) * inv;
+        result.pos = &r.origin + &(result.t * &r.traj);
+        result.uv = self.uv_at(u, v);
+        result.footprint = r.footprint(result.t) * self.uv_density();
+        result
+    }
+
+    fn light(&self) -> Option<&dyn LightSource> {
+        if self.mat.emissive() { Some(self) } else { None }
+    }
+}
+
+impl<'a> LightSource for Triangle<'a> {
+    fn sample(&self, from: &linear::Vec3<f64>, lambda: &Option<spectrum::Wavelengths>) -> Option<LightSample> {
+        let (b0, b1) = sampling::uniform_triangle(rand::random(), rand::random());
+        let pos = &(&(&self.a * b0) + &(&self.b * b1)) + &(&self.c * (1.0 - b0 - b1));
+        sample_area(from, pos, self.normal(), self.uv_at(b1, 1.0 - b0 - b1), self.area(), self.mat, lambda)
+    }
+
+    fn pdf(&self, from: &linear::Vec3<f64>, dir: &linear::Vec3<f64>) -> f64 {
+        pdf_area(self, from, dir, self.area())
+    }
+
+    fn bounds(&self) -> Option<lightbvh::LightBounds> {
+        let min = linear::Vec3 {x: self.a.x.min(self.b.x).min(self.c.x), y: self.a.y.min(self.b.y).min(self.c.y), z: self.a.z.min(self.b.z).min(self.c.z)};
+        let max = linear::Vec3 {x: self.a.x.max(self.b.x).max(self.c.x), y: self.a.y.max(self.b.y).max(self.c.y), z: self.a.z.max(self.b.z).max(self.c.z)};
+        let norm = self.normal();
+        let power = self.mat.power() * self.area();
+        Some(lightbvh::LightBounds {min, max, axis: norm, cos_o: if self.mat.two_sided() { -1.0 } else { 1.0 }, cos_e: 0.0, power})
+    }
+}
+
+// Closest hit on the object that its material does not cut out, found by
+// following the ray on through the holes.
+fn surface<'b>(obj: &'b dyn SceneObject, r: &ray::Ray) -> Intersection<'b> {
+    let mut inter = obj.intersect(r);
+    let mut travelled = 0.0;
+    for _ in 0..MAX_CUTOUTS {
+        if inter.t < T_MIN {
+            return Intersection {t: -1.0, ..inter};
+        }
+        if inter.mat.opaque(&inter) {
+            return Intersection {t: travelled + inter.t, ..inter};
+        }
+        travelled += inter.t;
+        let ray = ray::Ray {origin: inter.pos.copy(), traj: r.traj.copy(), lambda: None, pdf: 0.0, media: Vec::new(),
+                            width: r.footprint(travelled), spread: r.spread};
+        inter = obj.intersect(&ray);
+    }
+    Intersection {t: -1.0, ..inter}
+}
+
+// Converts a point sampled uniformly by area into a solid angle light sample.
+// Only the front of a surface emits unless it is two sided, matching the
+// culling of back faces.
+fn sample_area(from: &linear::Vec3<f64>, pos: linear::Vec3<f64>, norm: linear::Vec3<f64>, uv: (f64, f64), area: f64,
+               mat: &dyn material::Material, lambda: &Option<spectrum::Wavelengths>) -> Option<LightSample> {
+    let to = &pos - from;
+    let dist = to.norm();
+    let dir = &to / dist;
+    let cos = -(&dir * &norm);
+    let cos = if mat.two_sided() { cos.abs() } else { cos };
+    if cos <= 0.0 {
+        return None;
+    }
+    let ray = ray::Ray {origin: from.copy(), traj: dir, lambda: lambda.as_ref().map(|wl| wl.copy()), pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0};
+    let inter = Intersection {t: dist, pos, geom: norm.copy(), norm, mat, uv, footprint: 0.0,
+                              tangent: linear::Vec3::new(), bitangent: linear::Vec3::new()};
+    let radiance = mat.emit(&ray, &inter);
+    Some(LightSample {ray, dist, pdf: dist * dist / (cos * area), radiance, delta: false})
+}
+
+fn pdf_area(obj: &dyn SceneObject, from: &linear::Vec3<f64>, dir: &linear::Vec3<f64>, area: f64) -> f64 {
+    let ray = ray::Ray {origin: from.copy(), traj: dir.copy(), lambda: None, pdf: 0.0, media: Vec::new(), width: 0.0, spread: 0.0};
+    let inter = obj.intersect(&ray);
+    let cos = -(dir * &inter.geom);
+    let cos = if inter.mat.two_sided() { cos.abs() } else { cos };
+    if inter.t < T_MIN || cos <= 0.0 {
+        return 0.0;
+    }
+    inter.t * inter.t / (cos * area)
+}
@@ -0,0 +1,344 @@
+use std::f64::consts::PI;
+use std::io;
+
+use crate::camera;
+use crate::displace;
+use crate::envmap;
+use crate::ies;
+use crate::library;
+use crate::light;
+use crate::linear;
+use crate::material;
+use crate::merl;
+use crate::scene;
+use crate::sky;
+use crate::spectrum;
+use crate::texture;
+
+// Scenes that can be rendered, picked with --scene.
+pub enum Showcase {
+    // Spheres of many materials lit by a sphere light, the default.
+    Studio,
+    // Dispersive glasses splitting white light.
+    Spectral,
+    // Balls on open ground under an analytic sky and sun.
+    Outdoor,
+    // Balls lit by point, spot and directional lights.
+    Stage,
+    // Textured, layered and blended materials in a row.
+    Gallery,
+}
+
+// Render settings from the command line. Paths name files to load into
+// the scenes that use them.
+pub struct Options {
+    pub width: u32,
+    pub height: u32,
+    pub spectral: bool,
+    pub scene: Showcase,
+    pub spd: Option<String>,
+    pub lights: scene::LightStrategy,
+    // Width of the view of an orthographic camera, if one is asked for.
+    pub ortho: Option<f64>,
+    // Lat-long map, or six cube faces +x, -x, +y, -y, +z, -z, to light the
+    // scene with, turned by env_rotate degrees about the vertical.
+    pub env: Option<Vec<String>>,
+    pub env_rotate: f64,
+    pub env_intensity: f64,
+    // IES photometry to shape the stage's bulb with.
+    pub ies: Option<String>,
+    // MERL measured BRDF for the stage's middle ball.
+    pub brdf: Option<String>,
+    // Material library overriding the studio's materials by name.
+    pub library: Option<String>,
+    // Image for the gallery's back wall, tiled three by two and looked up
+    // with texture_wrap and texture_filter, and tangent space normals for
+    // its bumped ball.
+    pub texture: Option<String>,
+    pub texture_wrap: texture::Wrap,
+    pub texture_filter: texture::Filter,
+    pub normal_map: Option<String>,
+}
+
+// Files named by the options, loaded once and shared by the render threads.
+pub struct Assets {
+    pub spd: Option<spectrum::Spd>,
+    pub env: Option<envmap::Environment>,
+    pub ies: Option<ies::Profile>,
+    pub brdf: Option<merl::Measured>,
+    pub library: Option<library::Library>,
+    pub texture: Option<texture::ImageTexture>,
+    pub normal_map: Option<texture::ImageTexture>,
+}
+
+impl Showcase {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "studio" => Some(Showcase::Studio),
+            "spectral" => Some(Showcase::Spectral),
+            "outdoor" => Some(Showcase::Outdoor),
+            "stage" => Some(Showcase::Stage),
+            "gallery" => Some(Showcase::Gallery),
+            _ => None,
+        }
+    }
+}
+
+impl Assets {
+    pub fn load(options: &Options) -> io::Result<Self> {
+        let spd = options.spd.as_deref().map(spectrum::Spd::load).transpose()?;
+        let (rotation, intensity) = (options.env_rotate.to_radians(), options.env_intensity);
+        let env = match options.env.as_deref() {
+            Some([path]) => Some(envmap::Environment::load(path, rotation, intensity)?),
+            Some([px, nx, py, ny, pz, nz]) => Some(envmap::Environment::load_cube([px, nx, py, ny, pz, nz], rotation, intensity)?),
+            Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "an environment is one lat-long map or six cube faces")),
+            None => None,
+        };
+        let ies = options.ies.as_deref().map(ies::Profile::load).transpose()?;
+        let brdf = options.brdf.as_deref().map(merl::Measured::load).transpose()?;
+        let library = options.library.as_deref().map(library::Library::load).transpose()?;
+        let texture = match options.texture.as_deref() {
+            Some(path) => {
+                let mut image = texture::ImageTexture::load(path, options.texture_wrap.copy(), options.texture_filter.copy())?;
+                image.tiles = (3.0, 2.0);
+                Some(image)
+            },
+            None => None,
+        };
+        let normal_map = options.normal_map.as_deref().map(|path| texture::ImageTexture::load_linear(path, texture::Wrap::Repeat, texture::Filter::Bilinear)).transpose()?;
+        Ok(Assets {spd, env, ies, brdf, library, texture, normal_map})
+    }
+
+    // The named material from the library, or the fallback without one or
+    // when the library lacks it.
+    pub fn material<'a>(&'a self, name: &str, fallback: &'a dyn material::Material) -> &'a dyn material::Material {
+        self.library.as_ref().and_then(|library| library.get(name)).unwrap_or(fallback)
+    }
+}
+
+// Lights the scene with the loaded environment, if any, in place of the
+// flat background.
+pub fn surround<'a>(cam: &mut camera::Camera<'a>, assets: &'a Assets) {
+    if let Some(env) = &assets.env {
+        cam.bg = scene::BLACK;
+        cam.scene.lights.push(env);
+    }
+}
+
+// Camera at the origin looking down -z, set up as the options ask.
+pub fn camera<'a>(options: &Options) -> camera::Camera<'a> {
+    let mut scene = scene::Scene::new();
+    scene.strategy = options.lights.copy();
+    camera::Camera {
+        fov: std::f64::consts::PI * 0.5,
+        look: linear::Vec3{x: 0.0, y: 0.0, z: -1.0},
+        pos: linear::Vec3 {x: 0.0, y: 0.0, z: 0.0},
+        ratio: 0.0,
+        scene,
+        up: linear::Vec3{x: 0.0, y: 1.0, z: 0.0},
+        lens_rad: 0.05,
+        projection: match options.ortho {
+            Some(view) => camera::Projection::Orthographic(view),
+            None => camera::Projection::Perspective,
+        },
+        spectral: options.spectral,
+        bg: scene::SKY_DARK,
+        film: Vec::new()
+    }
+}
+
+// Crown glass, fused silica and flint balls in a row under a daylight lamp,
+// dispersing it into caustics of increasing spread, between brushed
+// aluminium and gold balls whose color comes from their complex index of
+// refraction, before a row of lamps
+// showing incandescent, candle and fluorescent light and any loaded SPD.
+pub fn spectral(options: &Options, assets: &Assets, samples: u16) -> Result<Vec<linear::Vec3<f64>>, String> {
+    let lamp_area = 4.0 * PI * 0.3 * 0.3;
+    let daylight = material::Emitter::new(spectrum::ILLUMINANT_D65, spectrum::Power::Lumens(550000.0), 4.0 * PI * 1.5 * 1.5)?;
+    let mut lamps = vec![
+        material::Emitter::new(spectrum::ILLUMINANT_A, spectrum::Power::Lumens(12000.0), lamp_area)?,
+        material::Emitter::blackbody(1850.0, spectrum::Power::Lumens(12000.0), lamp_area)?,
+        material::Emitter::new(spectrum::ILLUMINANT_F2, spectrum::Power::Lumens(12000.0), lamp_area)?,
+        material::Emitter::new(spectrum::ILLUMINANT_F11, spectrum::Power::Lumens(12000.0), lamp_area)?,
+    ];
+    if let Some(spd) = &assets.spd {
+        lamps.push(material::Emitter::new(spd.copy(), spectrum::Power::Lumens(12000.0), lamp_area)?);
+    }
+    let bulbs: Vec<scene::Sphere> = lamps.iter().enumerate().map(|(i, lamp)| {
+        let x = (i as f64 - (lamps.len() - 1) as f64 * 0.5) * 1.6;
+        scene::Sphere{mat: lamp, pos: linear::Vec3{x, y: 3.2, z: -9.0}, rad: 0.3}
+    }).collect();
+
+    let crown = scene::Sphere{mat: &material::CROWN_TESTING, pos: linear::Vec3{x: -2.4, y: 1.0, z: -5.0}, rad: 1.0};
+    let silica = scene::Sphere{mat: &material::SILICA_TESTING, pos: linear::Vec3{x: 0.0, y: 1.0, z: -5.0}, rad: 1.0};
+    let flint = scene::Sphere{mat: &material::PRISM_TESTING, pos: linear::Vec3{x: 2.4, y: 1.0, z: -5.0}, rad: 1.0};
+    let brushed = scene::Sphere{mat: &material::BRUSHED_TESTING, pos: linear::Vec3{x: -5.0, y: 1.0, z: -7.0}, rad: 1.0};
+    let gilt = scene::Sphere{mat: &material::GILT_TESTING, pos: linear::Vec3{x: 5.0, y: 1.0, z: -7.0}, rad: 1.0};
+    let light = scene::Sphere{mat: &daylight, pos: linear::Vec3{x: 0.0, y: 9.0, z: -3.0}, rad: 1.5};
+    let floor = scene::Face{d: -0.0001, facing: scene::FaceAxis::FaceY, mat: &material::WHITE_TESTING, w1: -12.0, w2: 4.0, h1: -8.0, h2: 8.0};
+    let back = scene::Face{d: -12.0, facing: scene::FaceAxis::FaceZ, mat: &material::WHITE_TESTING, w1: -8.0, w2: 8.0, h1: 0.0, h2: 10.0};
+
+    let mut cam = camera(options);
+    cam.bg = scene::BLACK;
+    cam.translate(&linear::Vec3 {x: 0.0, y: 2.5, z: 2.0});
+    cam.look_at(&linear::Vec3{x: 0.0, y: 0.8, z: -5.0});
+    cam.scene.objects.push(&crown);
+    cam.scene.objects.push(&silica);
+    cam.scene.objects.push(&flint);
+    cam.scene.objects.push(&brushed);
+    cam.scene.objects.push(&gilt);
+    cam.scene.objects.push(&light);
+    cam.scene.objects.push(&floor);
+    cam.scene.objects.push(&back);
+    surround(&mut cam, assets);
+    for bulb in &bulbs {
+        cam.scene.objects.push(bulb);
+    }
+    cam.shoot(options.width, options.height, samples);
+    Ok(cam.film)
+}
+
+// Grey, gold and glass balls on a wide plain in mid-morning sun, lit only by
+// the baked sky and the sun's disk, scaled down to the film's range.
+pub fn outdoor(options: &Options, samples: u16) -> Result<Vec<linear::Vec3<f64>>, String> {
+    let daylight = sky::Sky::new(35f64.to_radians(), 60f64.to_radians(), 3.0, &linear::Vec3 {x: 0.3, y: 0.3, z: 0.3}, 0.03);
+    let sun = daylight.sun();
+
+    let stone = scene::Sphere{mat: &material::WHITE_TESTING, pos: linear::Vec3{x: -2.4, y: 1.0, z: -6.0}, rad: 1.0};
+    let gold = scene::Sphere{mat: &material::GILT_TESTING, pos: linear::Vec3{x: 0.0, y: 1.0, z: -6.0}, rad: 1.0};
+    let glass = scene::Sphere{mat: &material::GLASS_TESTING, pos: linear::Vec3{x: 2.4, y: 1.0, z: -6.0}, rad: 1.0};
+    let ground = scene::Face{d: -0.0001, facing: scene::FaceAxis::FaceY, mat: &material::WHITE_TESTING, w1: -60.0, w2: 60.0, h1: -60.0, h2: 60.0};
+
+    let mut cam = camera(options);
+    cam.bg = scene::BLACK;
+    cam.translate(&linear::Vec3 {x: 0.0, y: 2.0, z: 2.0});
+    cam.look_at(&linear::Vec3{x: 0.0, y: 0.8, z: -6.0});
+    cam.scene.objects.push(&stone);
+    cam.scene.objects.push(&gold);
+    cam.scene.objects.push(&glass);
+    cam.scene.objects.push(&ground);
+    cam.scene.lights.push(&daylight.env);
+    cam.scene.lights.push(&sun);
+    cam.shoot(options.width, options.height, samples);
+    Ok(cam.film)
+}
+
+// Three balls before a wall in the dark, under a warm bare bulb, a spot light
+// picking out the right ball, and cold moonlight raking in from the left. A
+// loaded IES profile hangs the bulb in its fixture, pointing down, with the
+// bulb's intensity as the peak, and a loaded measured BRDF covers the
+// middle ball in place of its gold.
+pub fn stage(options: &Options, assets: &Assets, samples: u16) -> Result<Vec<linear::Vec3<f64>>, String> {
+    let rgb = |x, y, z| material::Emitter {color: linear::Vec3 {x, y, z}, spd: spectrum::Spd::Rgb, scale: 1.0, two_sided: false, texture: None, falloff: 0.0};
+    let (warm, white, cold) = (rgb(48.0, 36.0, 24.0), rgb(240.0, 240.0, 240.0), rgb(1.5, 1.8, 2.7));
+    let bulb = light::PointLight {pos: linear::Vec3 {x: -2.0, y: 4.0, z: -4.0}, dir: -&linear::Y, emitter: &warm,
+                                  fixture: assets.ies.as_ref().map(|profile| ies::Fixture {profile, zero: linear::Z})};
+    let spot = light::SpotLight {pos: linear::Vec3 {x: 3.0, y: 6.0, z: -3.0}, dir: linear::Vec3 {x: -0.6, y: -5.0, z: -3.0},
+                                 inner: 8f64.to_radians(), outer: 14f64.to_radians(), emitter: &white, fixture: None};
+    let moon = light::DirectionalLight {dir: linear::Vec3 {x: -1.0, y: 0.6, z: 0.3}, angle: 0.0, emitter: &cold};
+
+    let left = scene::Sphere{mat: &material::WHITE_TESTING, pos: linear::Vec3{x: -2.4, y: 1.0, z: -6.0}, rad: 1.0};
+    let measured: &dyn material::Material = match &assets.brdf {
+        Some(brdf) => brdf,
+        None => &material::GILT_TESTING,
+    };
+    let middle = scene::Sphere{mat: measured, pos: linear::Vec3{x: 0.0, y: 1.0, z: -6.0}, rad: 1.0};
+    let right = scene::Sphere{mat: &material::RED_TESTING, pos: linear::Vec3{x: 2.4, y: 1.0, z: -6.0}, rad: 1.0};
+    let floor = scene::Face{d: -0.0001, facing: scene::FaceAxis::FaceY, mat: &material::WHITE_TESTING, w1: -12.0, w2: 4.0, h1: -8.0, h2: 8.0};
+    let back = scene::Face{d: -10.0, facing: scene::FaceAxis::FaceZ, mat: &material::WHITE_TESTING, w1: -8.0, w2: 8.0, h1: 0.0, h2: 10.0};
+
+    let mut cam = camera(options);
+    cam.bg = scene::BLACK;
+    cam.translate(&linear::Vec3 {x: 0.0, y: 2.5, z: 2.0});
+    cam.look_at(&linear::Vec3{x: 0.0, y: 0.8, z: -6.0});
+    cam.scene.objects.push(&left);
+    cam.scene.objects.push(&middle);
+    cam.scene.objects.push(&right);
+    cam.scene.objects.push(&floor);
+    cam.scene.objects.push(&back);
+    cam.scene.lights.push(&bulb);
+    cam.scene.lights.push(&spot);
+    cam.scene.lights.push(&moon);
+    surround(&mut cam, assets);
+    cam.shoot(options.width, options.height, samples);
+    Ok(cam.film)
+}
+
+// A row of balls showing off materials: polished marble veined by cellular
+// noise, red under a clear coat roughened by noise, gold rusting in cells,
+// striped velvet and bumped steel, beside a card cut out in checks. They
+// stand on polished checkered ground before hills displaced by noise and
+// coloured by height, tessellated to a few pixels an edge, under a daylight
+// lamp rated in watts. A loaded texture covers the back wall, else it fades towards the top, and
+// a loaded normal map replaces the steel's noise.
+pub fn gallery(options: &Options, assets: &Assets, samples: u16) -> Result<Vec<linear::Vec3<f64>>, String> {
+    let lamp = material::Emitter::new(spectrum::ILLUMINANT_D65, spectrum::Power::Watts(4000.0), 4.0 * PI * 1.5 * 1.5)?;
+    let grey = |v| linear::Vec3 {x: v, y: v, z: v};
+
+    let grain = texture::Perlin {low: grey(0.0), high: grey(0.4), scale: 6.0, octaves: 4, mapping: texture::Mapping::Position, seed: 7};
+    let veins = texture::Worley {low: grey(0.3), high: grey(0.8), scale: 3.0, mapping: texture::Mapping::Position, seed: 5};
+    let cells = texture::Worley {low: grey(1.0), high: grey(0.0), scale: 5.0, mapping: texture::Mapping::Position, seed: 3};
+    let stripes = texture::Gradient {stops: vec![(0.2, linear::Vec3 {x: 0.9, y: 0.3, z: 0.6}), (0.8, linear::Vec3 {x: 0.3, y: 0.5, z: 0.9})], along: texture::Along::U};
+    let checks = texture::Checker {a: grey(0.7), b: grey(0.2), scale: 1.0, mapping: texture::Mapping::Position};
+    let holes = texture::Checker {a: grey(1.0), b: grey(0.0), scale: 4.0, mapping: texture::Mapping::Uv};
+    let heights = texture::Perlin {low: grey(0.0), high: grey(1.0), scale: 8.0, octaves: 5, mapping: texture::Mapping::Uv, seed: 11};
+    let strata = texture::Gradient {stops: vec![(0.8, linear::Vec3 {x: 0.2, y: 0.35, z: 0.1}), (1.6, linear::Vec3 {x: 0.45, y: 0.35, z: 0.25}), (2.4, grey(0.8))],
+                                    along: texture::Along::Direction(linear::Y)};
+    let fade = texture::Gradient {stops: vec![(0.0, grey(0.6)), (1.0, grey(0.2))], along: texture::Along::V};
+
+    let stone = material::Lambert {color: texture::Color::Texture(&veins)};
+    let marble = material::Mix {a: &stone, b: &material::MIRROR_TESTING, weight: material::MixWeight::Constant(0.1)};
+    let rust = material::Lambert {color: texture::Color::Constant(linear::Vec3 {x: 0.35, y: 0.12, z: 0.05})};
+    let lacquer = material::Coated {base: &material::RED_TESTING, eta: 1.5, roughness: texture::Scalar::Texture(&grain), tint: texture::Color::Constant(scene::WHITE)};
+    let rusting = material::Mix {a: &material::GOLD_TESTING, b: &rust, weight: material::MixWeight::Texture(&cells)};
+    let velvet = material::Sheen {base: &material::PURPLE_TESTING, color: texture::Color::Texture(&stripes), roughness: texture::Scalar::Constant(0.4)};
+    let steel = material::Bumped {base: &material::METAL_TESTING, bump: match &assets.normal_map {
+        Some(map) => material::Bump::Normal(map, 1.0),
+        None => material::Bump::Height(&grain, 0.02),
+    }};
+    let card = material::Cutout {base: &material::GREEN_TESTING, mask: &holes};
+    let tiles = material::Lambert {color: texture::Color::Texture(&checks)};
+    let ground = material::Mix {a: &tiles, b: &material::MIRROR_TESTING, weight: material::MixWeight::Fresnel(1.5)};
+    let wall = material::Lambert {color: match &assets.texture {
+        Some(image) => texture::Color::Texture(image),
+        None => texture::Color::Texture(&fade),
+    }};
+
+    let hillside = material::Lambert {color: texture::Color::Texture(&strata)};
+
+    let mut cam = camera(options);
+    cam.translate(&linear::Vec3 {x: 0.0, y: 2.5, z: 2.0});
+    cam.look_at(&linear::Vec3{x: 0.0, y: 0.8, z: -5.0});
+    // Edges of about four pixels, which an orthographic view spreads evenly
+    // and a perspective one over the distance from the eye.
+    let edge = match options.ortho {
+        Some(view) => displace::EdgeLength::World(view / options.width as f64 * 4.0),
+        None => displace::EdgeLength::Screen {eye: cam.pos.copy(), spread: cam.spread(options.width), pixels: 4.0},
+    };
+    let mesh = displace::Mesh::grid(&linear::Vec3 {x: -8.0, y: 0.0, z: -8.0}, &linear::Vec3 {x: 16.0, y: 0.0, z: 0.0}, &linear::Vec3 {x: 0.0, y: 0.0, z: -3.0}, 8);
+    let hills = displace::Displaced::new(mesh, &heights, 3.0, edge, &hillside);
+
+    let balls: Vec<scene::Sphere> = [&marble as &dyn material::Material, &lacquer, &rusting, &velvet, &steel].iter().enumerate().map(|(i, mat)| {
+        scene::Sphere{mat: *mat, pos: linear::Vec3{x: i as f64 * 2.1 - 5.2, y: 0.9, z: -5.0}, rad: 0.9}
+    }).collect();
+    let (bl, br, tl, tr) = (linear::Vec3 {x: 4.3, y: 0.0, z: -5.4}, linear::Vec3 {x: 5.5, y: 0.0, z: -4.6},
+                            linear::Vec3 {x: 4.3, y: 1.8, z: -5.4}, linear::Vec3 {x: 5.5, y: 1.8, z: -4.6});
+    let lower = scene::Triangle {a: bl.copy(), b: br.copy(), c: tr.copy(), uv: [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)], mat: &card};
+    let upper = scene::Triangle {a: bl, b: tr, c: tl, uv: [(0.0, 0.0), (1.0, 1.0), (0.0, 1.0)], mat: &card};
+    let light = scene::Sphere{mat: &lamp, pos: linear::Vec3{x: 0.0, y: 9.0, z: -3.0}, rad: 1.5};
+    let floor = scene::Face{d: -0.0001, facing: scene::FaceAxis::FaceY, mat: &ground, w1: -12.0, w2: 4.0, h1: -8.0, h2: 8.0};
+    let back = scene::Face{d: -12.0, facing: scene::FaceAxis::FaceZ, mat: &wall, w1: -8.0, w2: 8.0, h1: 0.0, h2: 10.0};
+
+    for ball in &balls {
+        cam.scene.objects.push(ball);
+    }
+    cam.scene.objects.push(&lower);
+    cam.scene.objects.push(&upper);
+    cam.scene.objects.push(&hills);
+    cam.scene.objects.push(&light);
+    cam.scene.objects.push(&floor);
+    cam.scene.objects.push(&back);
+    surround(&mut cam, assets);
+    cam.shoot(options.width, options.height, samples);
+    Ok(cam.film)
+}
@@ -0,0 +1,119 @@
+use std::f64::consts::PI;
+
+use crate::envmap;
+use crate::image;
+use crate::light;
+use crate::linear;
+use crate::material;
+use crate::spectrum;
+
+// Angular diameter of the sun as seen from the earth, radians.
+pub const SUN_ANGLE: f64 = 0.00931;
+
+// Resolution of the lat-long map the sky is baked into for sampling.
+const BAKE_WIDTH: usize = 256;
+const BAKE_HEIGHT: usize = 128;
+
+// Analytic daylight (Preetham et al. 1999). The sky is baked into an
+// environment map so it is importance sampled like any other, and the sun
+// is a directional light whose spectrum is attenuated by the atmosphere.
+// Luminances are in kcd/m^2 before being scaled by intensity.
+pub struct Sky {
+    pub dir: linear::Vec3<f64>,
+    pub env: envmap::Environment,
+    pub sun: material::Emitter<'static>,
+}
+
+// Perez distribution coefficients A to E.
+struct Perez([f64; 5]);
+
+impl Perez {
+    fn eval(&self, cos_theta: f64, gamma: f64) -> f64 {
+        let [a, b, c, d, e] = self.0;
+        (1.0 + a * (b / cos_theta.max(0.01)).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+    }
+}
+
+impl Sky {
+    // Sun elevation above the horizon and azimuth from -z towards +x, in
+    // radians. Turbidity ranges from about 2 (clear) to 10 (hazy).
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64, albedo: &linear::Vec3<f64>, intensity: f64) -> Sky {
+        let dir = linear::Vec3 {x: elevation.cos() * azimuth.sin(), y: elevation.sin(), z: -elevation.cos() * azimuth.cos()};
+        let t = turbidity;
+        let theta_s = (PI * 0.5 - elevation).clamp(0.0, PI * 0.5 - 0.01);
+
+        let perez_y = Perez([0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703]);
+        let perez_x = Perez([-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452]);
+        let perez_yc = Perez([-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529]);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (s1, s2, s3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x = t * t * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s1)
+            + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s1 + 0.00394)
+            + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s1 + 0.25886);
+        let zenith_yc = t * t * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s1)
+            + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s1 + 0.00516)
+            + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s1 + 0.26688);
+        let sun = linear::Vec3 {x: dir.x, y: theta_s.cos(), z: dir.z}.normalize();
+
+        let sky = |d: &linear::Vec3<f64>| {
+            let gamma = (d * &sun).clamp(-1.0, 1.0).acos();
+            let lum = zenith_y * perez_y.eval(d.y, gamma) / perez_y.eval(1.0, theta_s);
+            let x = zenith_x * perez_x.eval(d.y, gamma) / perez_x.eval(1.0, theta_s);
+            let y = zenith_yc * perez_yc.eval(d.y, gamma) / perez_yc.eval(1.0, theta_s);
+            spectrum::xyz_to_rgb(&linear::Vec3 {x: x / y * lum, y: lum, z: (1.0 - x - y) / y * lum})
+        };
+
+        // Sun spectrum outside the atmosphere, attenuated by Rayleigh and
+        // aerosol scattering along the air mass towards the sun.
+        let zenith = theta_s.to_degrees();
+        let mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - zenith).powf(-1.253));
+        let beta = 0.04608365 * t - 0.04586025;
+        let solid_angle = 2.0 * PI * (1.0 - (SUN_ANGLE * 0.5).cos());
+        let mut lambda = Vec::new();
+        let mut values = Vec::new();
+        let mut l = spectrum::LAMBDA_MIN;
+        while l <= spectrum::LAMBDA_MAX {
+            let um = l * 0.001;
+            let tau = (-mass * (0.008735 * um.powf(-4.08) + beta * um.powf(-1.3))).exp();
+            lambda.push(l);
+            values.push(if elevation > 0.0 { spectrum::planck(l, 5778.0) * 1e-9 * tau * solid_angle } else { 0.0 });
+            l += 5.0;
+        }
+        let spd = spectrum::Spd::Tabulated(lambda, values);
+        let scale = intensity * 0.683;
+        let sun_color = spd.rgb() * scale;
+
+        // Bake the sky, then light the ground below the horizon by the sky
+        // and sun falling on it.
+        let mut pixels = Vec::with_capacity(BAKE_WIDTH * BAKE_HEIGHT);
+        let mut irradiance = &sun_color * elevation.sin().max(0.0);
+        for y in 0..BAKE_HEIGHT {
+            let theta = PI * (y as f64 + 0.5) / BAKE_HEIGHT as f64;
+            for x in 0..BAKE_WIDTH {
+                let phi = 2.0 * PI * (x as f64 + 0.5) / BAKE_WIDTH as f64;
+                let d = linear::Vec3 {x: theta.sin() * phi.cos(), y: theta.cos(), z: theta.sin() * phi.sin()};
+                if d.y > 0.0 {
+                    let c = sky(&d) * intensity;
+                    let dw = (2.0 * PI / BAKE_WIDTH as f64) * (PI / BAKE_HEIGHT as f64) * theta.sin();
+                    irradiance = irradiance + &c * (d.y * dw);
+                    pixels.push(c);
+                } else {
+                    pixels.push(linear::Vec3::new());
+                }
+            }
+        }
+        let ground = albedo.color_prod(&irradiance) * (1.0 / PI);
+        for px in pixels.iter_mut().skip(BAKE_WIDTH * BAKE_HEIGHT / 2) {
+            *px = ground.copy();
+        }
+        // The bake has a fixed, non-zero size, so it can't be rejected.
+        let env = envmap::Environment::new(image::Image {width: BAKE_WIDTH, height: BAKE_HEIGHT, pixels}, 0.0, 1.0).expect("empty sky bake");
+        Sky {dir, env, sun: material::Emitter {color: sun_color, spd, scale, two_sided: false, texture: None, falloff: 0.0}}
+    }
+
+    pub fn sun(&self) -> light::DirectionalLight<'_> {
+        light::DirectionalLight {dir: self.dir.copy(), angle: SUN_ANGLE, emitter: &self.sun}
+    }
+}
@@ -0,0 +1,458 @@
+use std::{fs, io, sync::OnceLock};
+
+use crate::linear;
+use crate::scene;
+
+// Visible range traced in spectral mode, in nanometers.
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 720.0;
+// Fraunhofer d line, used as the representative wavelength outside spectral mode.
+pub const LAMBDA_D: f64 = 587.56;
+
+// Schott catalogue Sellmeier coefficients (C terms in square micrometers).
+pub const BK7: Ior = Ior::Sellmeier([1.03961212, 0.231792344, 1.01046945], [0.00600069867, 0.0200179144, 103.560653]);
+pub const SF11: Ior = Ior::Sellmeier([1.73759695, 0.313747346, 1.89878101], [0.013188707, 0.0623068142, 155.23629]);
+pub const FUSED_SILICA: Ior = Ior::Cauchy(1.4580, 0.00354);
+
+// Smits' basis spectra, 10 equal bins spanning LAMBDA_MIN..LAMBDA_MAX.
+const SMITS_WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// CIE standard illuminant D65, 380nm to 780nm in 10nm steps.
+const D65: [f64; 41] = [
+    49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008, 117.812, 114.861,
+    115.923, 108.811, 109.354, 107.802, 104.79, 107.689, 104.405, 104.046, 100.0, 96.3342,
+    95.788, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268, 80.2146, 82.2778,
+    78.2842, 69.7213, 71.6091, 74.349, 61.604, 69.8856, 75.087, 63.5927, 46.4182, 66.8054,
+    63.3828,
+];
+
+// CIE fluorescent illuminants F2 (cool white) and F11 (narrow triband),
+// 380nm to 780nm in 5nm steps.
+const F2: [f64; 81] = [
+    1.18, 1.48, 1.84, 2.15, 3.44, 15.69, 3.85, 3.74, 4.19, 4.62,
+    5.06, 34.98, 11.81, 6.27, 6.63, 6.93, 7.19, 7.40, 7.54, 7.62,
+    7.65, 7.62, 7.62, 7.45, 7.28, 7.15, 7.05, 7.04, 7.16, 7.47,
+    8.04, 8.88, 10.01, 24.88, 16.64, 14.59, 16.16, 17.56, 18.62, 21.47,
+    22.79, 19.29, 18.66, 17.73, 16.54, 15.21, 13.80, 12.36, 10.95, 9.65,
+    8.40, 7.32, 6.31, 5.43, 4.68, 4.02, 3.45, 2.96, 2.55, 2.19,
+    1.89, 1.64, 1.53, 1.27, 1.10, 0.99, 0.88, 0.76, 0.68, 0.61,
+    0.56, 0.54, 0.51, 0.47, 0.47, 0.43, 0.46, 0.47, 0.40, 0.33,
+    0.27,
+];
+const F11: [f64; 81] = [
+    0.91, 0.63, 0.46, 0.37, 1.29, 12.68, 1.59, 1.79, 2.46, 3.33,
+    4.49, 33.94, 12.13, 6.95, 7.19, 7.12, 6.72, 6.13, 5.46, 4.79,
+    5.66, 14.29, 14.96, 8.97, 4.72, 2.33, 1.47, 1.10, 0.89, 0.83,
+    1.18, 4.90, 39.59, 72.84, 32.61, 7.52, 2.83, 1.96, 1.67, 4.43,
+    11.28, 14.76, 12.73, 9.74, 7.33, 9.72, 55.27, 42.58, 13.18, 13.16,
+    12.26, 5.11, 2.07, 2.34, 3.58, 3.01, 2.48, 2.14, 1.54, 1.33,
+    1.46, 1.94, 2.00, 1.20, 1.35, 4.10, 5.58, 2.51, 0.57, 0.27,
+    0.23, 0.21, 0.24, 0.24, 0.20, 0.24, 0.32, 0.26, 0.16, 0.12,
+    0.09,
+];
+
+pub const ILLUMINANT_D65: Spd = Spd::Table(380.0, 10.0, &D65);
+pub const ILLUMINANT_A: Spd = Spd::Blackbody(2856.0);
+pub const ILLUMINANT_F2: Spd = Spd::Table(380.0, 5.0, &F2);
+pub const ILLUMINANT_F11: Spd = Spd::Table(380.0, 5.0, &F11);
+
+// Peak luminous efficacy of radiation, lumens per watt.
+const EFFICACY: f64 = 683.0;
+
+// XYZ of the D65 white point, which linear sRGB (1, 1, 1) maps to.
+const WHITE_XYZ: linear::Vec3<f64> = linear::Vec3 {x: 0.95047, y: 1.0, z: 1.08883};
+
+// Wavelength-dependent index of refraction. Wavelengths are in nanometers.
+pub enum Ior {
+    Constant(f64),
+    Cauchy(f64, f64),
+    Sellmeier([f64; 3], [f64; 3]),
+}
+
+// Relative spectral power distribution of an emitter. Blackbodies are
+// normalized to 1 at their peak, tables are sampled at regular intervals
+// from a start wavelength, and tabulated spectra are (wavelength, value)
+// pairs as read from an SPD file.
+pub enum Spd {
+    Rgb,
+    Blackbody(f64),
+    Table(f64, f64, &'static [f64]),
+    Tabulated(Vec<f64>, Vec<f64>),
+}
+
+// Total output of a light in physical units.
+pub enum Power {
+    Watts(f64),
+    Lumens(f64),
+}
+
+// Hero wavelength sample. The three wavelengths are evenly rotated through the
+// visible range and map onto the x, y and z channels of a color.
+pub struct Wavelengths {
+    pub lambda: linear::Vec3<f64>,
+    pub secondary: bool,
+}
+
+impl Ior {
+    pub fn at(&self, lambda: f64) -> f64 {
+        let um = lambda * 0.001;
+        match self {
+            Ior::Constant(eta) => *eta,
+            Ior::Cauchy(a, b) => a + b / (um * um),
+            Ior::Sellmeier(b, c) => {
+                let l2 = um * um;
+                let mut n2 = 1.0;
+                for i in 0..3 {
+                    n2 += b[i] * l2 / (l2 - c[i]);
+                }
+                n2.sqrt()
+            }
+        }
+    }
+
+    pub fn dispersive(&self) -> bool {
+        !matches!(self, Ior::Constant(_))
+    }
+}
+
+impl Spd {
+    // Reads a tabulated SPD from a text file of "wavelength value" lines.
+    pub fn load(path: &str) -> io::Result<Spd> {
+        Spd::parse(&fs::read_to_string(path)?).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+    }
+
+    // Blank lines and anything after a '#' are ignored, and columns may be
+    // separated by commas as well as spaces.
+    pub fn parse(text: &str) -> io::Result<Spd> {
+        let mut lambda = Vec::new();
+        let mut values = Vec::new();
+        for (number, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line)) {
+            let line = line.split('#').next().unwrap_or("");
+            let nums: Vec<&str> = line.split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty()).collect();
+            if nums.is_empty() {
+                continue;
+            }
+            let parse = |s: &str| s.parse::<f64>().map_err(|e| invalid(&format!("line {}: \"{}\": {}", number, s, e)));
+            if nums.len() != 2 {
+                return Err(invalid(&format!("line {}: expected two columns in \"{}\"", number, line.trim())));
+            }
+            let l = parse(nums[0])?;
+            if lambda.last().is_some_and(|&last| l <= last) {
+                return Err(invalid(&format!("line {}: wavelengths must increase", number)));
+            }
+            // Negative power would make lights draw negative probabilities.
+            let value = parse(nums[1])?;
+            if !value.is_finite() || value < 0.0 {
+                return Err(invalid(&format!("line {}: {} is not a power", number, nums[1])));
+            }
+            lambda.push(l);
+            values.push(value);
+        }
+        if lambda.len() < 2 {
+            return Err(invalid("too few samples"));
+        }
+        Ok(Spd::Tabulated(lambda, values))
+    }
+
+    // Relative power at a wavelength in nanometers. Rgb spectra have no
+    // distribution of their own and evaluate to zero.
+    pub fn eval(&self, lambda: f64) -> f64 {
+        match self {
+            Spd::Rgb => 0.0,
+            Spd::Blackbody(kelvin) => {
+                let peak = 2.8977721e6 / kelvin;
+                planck(lambda, *kelvin) / planck(peak, *kelvin)
+            }
+            Spd::Table(start, step, values) => {
+                let f = (lambda - start) / step;
+                if f < 0.0 || f > (values.len() - 1) as f64 {
+                    return 0.0;
+                }
+                let i = (f as usize).min(values.len() - 2);
+                let t = f - i as f64;
+                values[i] * (1.0 - t) + values[i + 1] * t
+            }
+            Spd::Tabulated(lambda_tab, values) => {
+                if lambda < lambda_tab[0] || lambda > lambda_tab[lambda_tab.len() - 1] {
+                    return 0.0;
+                }
+                let i = lambda_tab.partition_point(|&l| l <= lambda).clamp(1, lambda_tab.len() - 1);
+                let t = (lambda - lambda_tab[i - 1]) / (lambda_tab[i] - lambda_tab[i - 1]);
+                values[i - 1] * (1.0 - t) + values[i] * t
+            }
+        }
+    }
+
+    pub fn sample(&self, wl: &Wavelengths) -> linear::Vec3<f64> {
+        wl.map(|l| self.eval(l))
+    }
+
+    pub fn copy(&self) -> Self {
+        match self {
+            Spd::Rgb => Spd::Rgb,
+            Spd::Blackbody(kelvin) => Spd::Blackbody(*kelvin),
+            Spd::Table(start, step, values) => Spd::Table(*start, *step, values),
+            Spd::Tabulated(lambda, values) => Spd::Tabulated(lambda.clone(), values.clone()),
+        }
+    }
+
+    // Linear sRGB color of the distribution, on the same scale as spectral renders.
+    pub fn rgb(&self) -> linear::Vec3<f64> {
+        xyz_to_rgb(&integrate(|l| self.eval(l)).color_prod(white_balance()))
+    }
+
+    // Integral over every wavelength the distribution covers, in nanometers:
+    // the tabulated range, or in closed form by Stefan-Boltzmann for a black body.
+    pub fn radiant(&self) -> f64 {
+        match self {
+            Spd::Rgb => 0.0,
+            Spd::Blackbody(kelvin) => {
+                const SIGMA: f64 = 5.670374419e-8;
+                let peak = 2.8977721e6 / kelvin;
+                SIGMA * kelvin.powi(4) / std::f64::consts::PI / planck(peak, *kelvin) * 1e9
+            }
+            Spd::Table(_, step, values) => values.windows(2).map(|v| 0.5 * (v[0] + v[1]) * step).sum(),
+            Spd::Tabulated(lambda, values) => {
+                (1..lambda.len()).map(|i| 0.5 * (values[i - 1] + values[i]) * (lambda[i] - lambda[i - 1])).sum()
+            }
+        }
+    }
+
+    // Scale that gives an emitter this total power, where projected is its
+    // area times the integral of its angular falloff and the cosine over the
+    // directions it emits in, pi times the area for a Lambertian one. Rgb
+    // spectra carry no power of their own to scale.
+    pub fn scale(&self, power: &Power, projected: f64) -> Result<f64, String> {
+        if let Spd::Rgb = self {
+            return Err("an RGB emitter has no spectrum to scale".to_string());
+        }
+        let (amount, total) = match power {
+            Power::Watts(w) => (w, self.radiant()),
+            Power::Lumens(lm) => (lm, EFFICACY * integrate(|l| self.eval(l)).y),
+        };
+        if total <= 0.0 {
+            return Err("the spectrum emits no power".to_string());
+        }
+        if projected <= 0.0 {
+            return Err("the light has no area to emit from".to_string());
+        }
+        Ok(amount / (projected * total))
+    }
+}
+
+impl Wavelengths {
+    pub fn sample(u: f64) -> Self {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let hero = LAMBDA_MIN + u * range;
+        let rotate = |i: f64| {
+            let lambda = hero + i * range / 3.0;
+            if lambda > LAMBDA_MAX { lambda - range } else { lambda }
+        };
+        Wavelengths {lambda: linear::Vec3 {x: hero, y: rotate(1.0), z: rotate(2.0)}, secondary: true}
+    }
+
+    // Drops the secondary wavelengths once the path has become wavelength
+    // dependent, e.g. after refracting through a dispersive medium.
+    pub fn terminate_secondary(&mut self) {
+        self.secondary = false;
+    }
+
+    pub fn copy(&self) -> Self {
+        Wavelengths {lambda: self.lambda.copy(), secondary: self.secondary}
+    }
+
+    fn map(&self, f: impl Fn(f64) -> f64) -> linear::Vec3<f64> {
+        linear::Vec3 {x: f(self.lambda.x), y: f(self.lambda.y), z: f(self.lambda.z)}
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Spectral radiance of a blackbody (Planck's law), wavelength in nanometers.
+pub fn planck(lambda: f64, kelvin: f64) -> f64 {
+    const C: f64 = 299792458.0;
+    const H: f64 = 6.62607015e-34;
+    const KB: f64 = 1.380649e-23;
+    let l = lambda * 1e-9;
+    2.0 * H * C * C / (l.powi(5) * ((H * C / (l * KB * kelvin)).exp() - 1.0))
+}
+
+fn gaussian(x: f64, mu: f64, s1: f64, s2: f64) -> f64 {
+    let t = (x - mu) / if x < mu { s1 } else { s2 };
+    (-0.5 * t * t).exp()
+}
+
+// Multi-lobe fit of the CIE 1931 color matching functions (Wyman et al. 2013).
+pub fn cmf(lambda: f64) -> linear::Vec3<f64> {
+    linear::Vec3 {
+        x: 1.056 * gaussian(lambda, 599.8, 37.9, 31.0) + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * gaussian(lambda, 501.1, 20.4, 26.2),
+        y: 0.821 * gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1),
+        z: 1.217 * gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8),
+    }
+}
+
+pub fn d65(lambda: f64) -> f64 {
+    let f = ((lambda - 380.0) / 10.0).clamp(0.0, 40.0);
+    let i = (f as usize).min(39);
+    let t = f - i as f64;
+    D65[i] * (1.0 - t) + D65[i + 1] * t
+}
+
+// Integrates a spectral distribution against the color matching functions
+// over the traced range.
+pub fn integrate(f: impl Fn(f64) -> f64) -> linear::Vec3<f64> {
+    let mut xyz = linear::Vec3::new();
+    let mut lambda = LAMBDA_MIN + 0.5;
+    while lambda < LAMBDA_MAX {
+        xyz = xyz + cmf(lambda) * f(lambda);
+        lambda += 1.0;
+    }
+    xyz
+}
+
+// Luminance of unnormalized D65 over the traced range, so that an RGB
+// illuminant of (1, 1, 1) has Y = 1.
+fn d65_scale() -> f64 {
+    static SCALE: OnceLock<f64> = OnceLock::new();
+    *SCALE.get_or_init(|| integrate(d65).y.recip())
+}
+
+// Per-channel correction mapping the traced D65 white onto the reference
+// white point, absorbing the error of the fitted CMFs and truncated range.
+fn white_balance() -> &'static linear::Vec3<f64> {
+    static BALANCE: OnceLock<linear::Vec3<f64>> = OnceLock::new();
+    BALANCE.get_or_init(|| {
+        let white = integrate(|l| smits(&scene::WHITE, l) * d65(l)) * d65_scale();
+        linear::Vec3 {x: WHITE_XYZ.x / white.x, y: WHITE_XYZ.y / white.y, z: WHITE_XYZ.z / white.z}
+    })
+}
+
+// RGB to reflectance spectrum conversion (Smits 1999).
+fn smits(rgb: &linear::Vec3<f64>, lambda: f64) -> f64 {
+    let bin = (((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN) * 10.0) as usize).min(9);
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    if r <= g && r <= b {
+        let base = r * SMITS_WHITE[bin];
+        if g <= b {
+            base + (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+        } else {
+            base + (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+        }
+    } else if g <= r && g <= b {
+        let base = g * SMITS_WHITE[bin];
+        if r <= b {
+            base + (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+        } else {
+            base + (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+        }
+    } else {
+        let base = b * SMITS_WHITE[bin];
+        if r <= g {
+            base + (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+        } else {
+            base + (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+        }
+    }
+}
+
+// Upsamples an RGB albedo to reflectance at the sampled wavelengths.
+pub fn reflectance(rgb: &linear::Vec3<f64>, wl: &Wavelengths) -> linear::Vec3<f64> {
+    wl.map(|l| smits(rgb, l))
+}
+
+// Reflectance spectrum given as a function of wavelength, at the sampled
+// wavelengths.
+pub fn sampled(f: impl Fn(f64) -> f64, wl: &Wavelengths) -> linear::Vec3<f64> {
+    wl.map(f)
+}
+
+// Linear sRGB albedo of a reflectance spectrum seen under D65, so that a
+// constant spectrum gives a grey of the same value.
+pub fn reflectance_rgb(f: impl Fn(f64) -> f64) -> linear::Vec3<f64> {
+    let mut xyz = linear::Vec3::new();
+    let mut white = linear::Vec3::new();
+    let mut lambda = LAMBDA_MIN + 5.0;
+    while lambda < LAMBDA_MAX {
+        let w = cmf(lambda) * d65(lambda);
+        xyz = &xyz + &(&w * f(lambda));
+        white = white + w;
+        lambda += 10.0;
+    }
+    let (rgb, white) = (xyz_to_rgb(&xyz), xyz_to_rgb(&white));
+    linear::Vec3 {x: rgb.x / white.x, y: rgb.y / white.y, z: rgb.z / white.z}
+}
+
+// Upsamples an RGB emission color to spectral radiance under a D65 whitepoint.
+pub fn illuminant(rgb: &linear::Vec3<f64>, wl: &Wavelengths) -> linear::Vec3<f64> {
+    let scale = d65_scale();
+    wl.map(|l| smits(rgb, l) * d65(l) * scale)
+}
+
+// Monte Carlo estimate of XYZ from radiance carried at the sampled wavelengths.
+pub fn to_xyz(radiance: &linear::Vec3<f64>, wl: &Wavelengths) -> linear::Vec3<f64> {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let xyz = if wl.secondary {
+        (cmf(wl.lambda.x) * radiance.x + cmf(wl.lambda.y) * radiance.y + cmf(wl.lambda.z) * radiance.z) * (range / 3.0)
+    } else {
+        cmf(wl.lambda.x) * (radiance.x * range)
+    };
+    xyz.color_prod(white_balance())
+}
+
+// Relative luminance of a linear sRGB color.
+pub fn luminance(rgb: &linear::Vec3<f64>) -> f64 {
+    0.2126 * rgb.x + 0.7152 * rgb.y + 0.0722 * rgb.z
+}
+
+pub fn xyz_to_rgb(xyz: &linear::Vec3<f64>) -> linear::Vec3<f64> {
+    linear::Vec3 {
+        x: 3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+        y: -0.9692660 * xyz.x + 1.8760108 * xyz.y + 0.0415560 * xyz.z,
+        z: 0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z,
+    }
+}
+
+pub fn to_rgb(radiance: &linear::Vec3<f64>, wl: &Wavelengths) -> linear::Vec3<f64> {
+    xyz_to_rgb(&to_xyz(radiance, wl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_parse_with_comments_and_reject_bad_lines() {
+        let spd = Spd::parse("# lamp\n400, 1.5\n\n500 2 # peak\n600\t0\n").unwrap();
+        assert!(matches!(&spd, Spd::Tabulated(lambda, values) if lambda == &[400.0, 500.0, 600.0] && values == &[1.5, 2.0, 0.0]));
+        let error = |text: &str| Spd::parse(text).err().unwrap().to_string();
+        assert!(error("400 1\n# gap\n500 -0.2\n").ends_with("line 3: -0.2 is not a power"));
+        assert!(error("400 1\n500 inf\n").ends_with("line 2: inf is not a power"));
+        assert!(error("400 1\n400 2\n").ends_with("line 2: wavelengths must increase"));
+        assert!(error("400 1\n500 x\n").contains("line 2: \"x\""));
+        assert!(error("400 1 2\n").contains("line 1: expected two columns"));
+    }
+
+    #[test]
+    fn black_body_integrates_past_the_visible_range() {
+        let spd = Spd::Blackbody(3000.0);
+        let numeric: f64 = (1..400000).map(|i| spd.eval(i as f64 * 0.25) * 0.25).sum();
+        assert!((spd.radiant() - numeric).abs() < 1e-4 * numeric, "{} vs {}", spd.radiant(), numeric);
+        let visible: f64 = (380..720).map(|l| spd.eval(l as f64 + 0.5)).sum();
+        assert!(spd.radiant() > 2.0 * visible);
+    }
+
+    #[test]
+    fn tables_integrate_over_their_own_range() {
+        assert_eq!(Spd::Table(400.0, 100.0, &[1.0, 1.0, 1.0]).radiant(), 200.0);
+        assert_eq!(Spd::Tabulated(vec![300.0, 900.0], vec![0.0, 2.0]).radiant(), 600.0);
+    }
+}
@@ -0,0 +1,474 @@
+use std::io;
+
+use crate::image;
+use crate::linear;
+use crate::material;
+use crate::scene;
+use crate::spectrum;
+
+// Lookups per side of the grid averaging a texture without a cheaper way.
+const AVERAGE_STEPS: usize = 16;
+
+// A color varying over surfaces, looked up at each hit. Scalar parameters
+// read the luminance, so grey images work as masks. Sync, like materials.
+pub trait Texture: Sync {
+    fn value(&self, inter: &scene::Intersection) -> linear::Vec3<f64>;
+
+    fn scalar(&self, inter: &scene::Intersection) -> f64 {
+        spectrum::luminance(&self.value(inter))
+    }
+
+    // Mean value over the unit square of texture coordinates, here from a
+    // grid of lookups.
+    fn average(&self) -> linear::Vec3<f64> {
+        let mut inter = scene::Intersection {t: 1.0, pos: linear::Vec3::new(), norm: linear::Z, geom: linear::Z, mat: &material::BLANK,
+                                             uv: (0.0, 0.0), footprint: 0.0, tangent: linear::X, bitangent: linear::Y};
+        let mut sum = linear::Vec3::new();
+        for i in 0..AVERAGE_STEPS * AVERAGE_STEPS {
+            inter.uv = (((i % AVERAGE_STEPS) as f64 + 0.5) / AVERAGE_STEPS as f64, ((i / AVERAGE_STEPS) as f64 + 0.5) / AVERAGE_STEPS as f64);
+            inter.pos = linear::Vec3 {x: inter.uv.0, y: inter.uv.1, z: 0.0};
+            sum = &sum + &self.value(&inter);
+        }
+        sum / (AVERAGE_STEPS * AVERAGE_STEPS) as f64
+    }
+}
+
+// A color parameter of a material, constant or from a texture.
+pub enum Color<'a> {
+    Constant(linear::Vec3<f64>),
+    Texture(&'a dyn Texture),
+}
+
+// A scalar parameter of a material, constant or from a texture.
+pub enum Scalar<'a> {
+    Constant(f64),
+    Texture(&'a dyn Texture),
+}
+
+impl<'a> Color<'a> {
+    pub fn at(&self, inter: &scene::Intersection) -> linear::Vec3<f64> {
+        match self {
+            Color::Constant(c) => c.copy(),
+            Color::Texture(tex) => tex.value(inter),
+        }
+    }
+}
+
+impl<'a> Scalar<'a> {
+    pub fn at(&self, inter: &scene::Intersection) -> f64 {
+        match self {
+            Scalar::Constant(s) => *s,
+            Scalar::Texture(tex) => tex.scalar(inter),
+        }
+    }
+
+    pub fn copy(&self) -> Self {
+        match self {
+            Scalar::Constant(s) => Scalar::Constant(*s),
+            Scalar::Texture(tex) => Scalar::Texture(*tex),
+        }
+    }
+}
+
+// How texture coordinates outside [0, 1] land on an image.
+pub enum Wrap {
+    Repeat,
+    Mirror,
+    Clamp,
+    // Black outside the image, for decals.
+    Border,
+}
+
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    // Bilinear between the two mip levels nearest the size of the hit's
+    // footprint, blurring distant detail instead of letting it alias.
+    Mipmap,
+}
+
+// An image mapped over surfaces by their texture coordinates, with v going
+// up from the bottom row. Tiles repeats it that many times along u and v.
+pub struct ImageTexture {
+    levels: Vec<image::Image>,
+    pub wrap: Wrap,
+    pub filter: Filter,
+    pub tiles: (f64, f64),
+}
+
+// Whether the texture is laid out by texture coordinates or across space, as
+// if carved from a solid block.
+pub enum Mapping {
+    Uv,
+    Position,
+}
+
+// Alternating squares of two colors, scale of them per unit of the mapping.
+pub struct Checker {
+    pub a: linear::Vec3<f64>,
+    pub b: linear::Vec3<f64>,
+    pub scale: f64,
+    pub mapping: Mapping,
+}
+
+pub enum Along {
+    U,
+    V,
+    // Distance along a direction in space, one unit of it per unit of the
+    // gradient.
+    Direction(linear::Vec3<f64>),
+}
+
+// Colors blended between stops at increasing positions, holding the end
+// colors past the first and last.
+pub struct Gradient {
+    pub stops: Vec<(f64, linear::Vec3<f64>)>,
+    pub along: Along,
+}
+
+// Perlin gradient noise blending from low to high, summing octaves of
+// doubling frequency and halving amplitude for fractal detail. Scale is the
+// frequency of the first octave per unit of the mapping.
+pub struct Perlin {
+    pub low: linear::Vec3<f64>,
+    pub high: linear::Vec3<f64>,
+    pub scale: f64,
+    pub octaves: u32,
+    pub mapping: Mapping,
+    pub seed: u64,
+}
+
+// Worley cellular noise, blending from low at scattered feature points to
+// high a cell's width away, like stone, scales or foam.
+pub struct Worley {
+    pub low: linear::Vec3<f64>,
+    pub high: linear::Vec3<f64>,
+    pub scale: f64,
+    pub mapping: Mapping,
+    pub seed: u64,
+}
+
+impl Wrap {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "repeat" => Some(Wrap::Repeat),
+            "mirror" => Some(Wrap::Mirror),
+            "clamp" => Some(Wrap::Clamp),
+            "border" => Some(Wrap::Border),
+            _ => None,
+        }
+    }
+
+    pub fn copy(&self) -> Self {
+        match self {
+            Wrap::Repeat => Wrap::Repeat,
+            Wrap::Mirror => Wrap::Mirror,
+            Wrap::Clamp => Wrap::Clamp,
+            Wrap::Border => Wrap::Border,
+        }
+    }
+}
+
+impl Filter {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "nearest" => Some(Filter::Nearest),
+            "bilinear" => Some(Filter::Bilinear),
+            "mipmap" => Some(Filter::Mipmap),
+            _ => None,
+        }
+    }
+
+    pub fn copy(&self) -> Self {
+        match self {
+            Filter::Nearest => Filter::Nearest,
+            Filter::Bilinear => Filter::Bilinear,
+            Filter::Mipmap => Filter::Mipmap,
+        }
+    }
+}
+
+impl ImageTexture {
+    // Errors on an image with no pixels, which has nothing to look up.
+    pub fn new(image: image::Image, wrap: Wrap, filter: Filter) -> io::Result<Self> {
+        if image.width == 0 || image.height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty image"));
+        }
+        let mut levels = vec![image];
+        loop {
+            let last = &levels[levels.len() - 1];
+            if last.width == 1 && last.height == 1 {
+                break;
+            }
+            // Box filter down to half size, edges clamped for odd sizes.
+            let (width, height) = (last.width.div_ceil(2), last.height.div_ceil(2));
+            let mut pixels = Vec::with_capacity(width * height);
+            for y in 0..height {
+                for x in 0..width {
+                    let (x0, y0) = (2 * x, 2 * y);
+                    let (x1, y1) = ((x0 + 1).min(last.width - 1), (y0 + 1).min(last.height - 1));
+                    let sum = &(last.get(x0, y0) + last.get(x1, y0)) + &(last.get(x0, y1) + last.get(x1, y1));
+                    pixels.push(sum * 0.25);
+                }
+            }
+            levels.push(image::Image {width, height, pixels});
+        }
+        Ok(ImageTexture {levels, wrap, filter, tiles: (1.0, 1.0)})
+    }
+
+    pub fn load(path: &str, wrap: Wrap, filter: Filter) -> io::Result<Self> {
+        ImageTexture::new(image::Image::load(path)?, wrap, filter)
+    }
+
+    // Loads an image holding data rather than colors, like a normal map,
+    // without decoding sRGB.
+    pub fn load_linear(path: &str, wrap: Wrap, filter: Filter) -> io::Result<Self> {
+        ImageTexture::new(image::Image::load_linear(path)?, wrap, filter)
+    }
+
+    fn texel(&self, level: usize, x: i64, y: i64) -> linear::Vec3<f64> {
+        let img = &self.levels[level];
+        let fold = |i: i64, n: usize| {
+            let n = n as i64;
+            match self.wrap {
+                Wrap::Repeat => Some(i.rem_euclid(n)),
+                Wrap::Mirror => {
+                    let i = i.rem_euclid(2 * n);
+                    Some(if i < n { i } else { 2 * n - 1 - i })
+                },
+                Wrap::Clamp => Some(i.clamp(0, n - 1)),
+                Wrap::Border => if i < 0 || i >= n { None } else { Some(i) },
+            }
+        };
+        match (fold(x, img.width), fold(y, img.height)) {
+            (Some(x), Some(y)) => img.get(x as usize, y as usize).copy(),
+            _ => scene::BLACK.copy(),
+        }
+    }
+
+    fn nearest(&self, level: usize, u: f64, v: f64) -> linear::Vec3<f64> {
+        let img = &self.levels[level];
+        self.texel(level, (u * img.width as f64).floor() as i64, ((1.0 - v) * img.height as f64).floor() as i64)
+    }
+
+    fn bilinear(&self, level: usize, u: f64, v: f64) -> linear::Vec3<f64> {
+        let img = &self.levels[level];
+        let x = u * img.width as f64 - 0.5;
+        let y = (1.0 - v) * img.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(level, x0, y0) * (1.0 - tx) + self.texel(level, x0 + 1, y0) * tx;
+        let bot = self.texel(level, x0, y0 + 1) * (1.0 - tx) + self.texel(level, x0 + 1, y0 + 1) * tx;
+        top * (1.0 - ty) + bot * ty
+    }
+}
+
+impl Texture for ImageTexture {
+    // The top of the mip chain, one texel averaging the whole image.
+    fn average(&self) -> linear::Vec3<f64> {
+        self.levels[self.levels.len() - 1].get(0, 0).copy()
+    }
+
+    fn value(&self, inter: &scene::Intersection) -> linear::Vec3<f64> {
+        let (u, v) = (inter.uv.0 * self.tiles.0, inter.uv.1 * self.tiles.1);
+        match self.filter {
+            Filter::Nearest => self.nearest(0, u, v),
+            Filter::Bilinear => self.bilinear(0, u, v),
+            Filter::Mipmap => {
+                let base = &self.levels[0];
+                let texels = inter.footprint * self.tiles.0.max(self.tiles.1) * base.width.max(base.height) as f64;
+                let level = texels.max(1.0).log2().min((self.levels.len() - 1) as f64);
+                let (l0, t) = (level.floor() as usize, level.fract());
+                if l0 + 1 >= self.levels.len() || t == 0.0 {
+                    return self.bilinear(l0, u, v);
+                }
+                self.bilinear(l0, u, v) * (1.0 - t) + self.bilinear(l0 + 1, u, v) * t
+            },
+        }
+    }
+}
+
+// Point a texture of the given mapping is laid out by.
+fn coords(mapping: &Mapping, inter: &scene::Intersection) -> linear::Vec3<f64> {
+    match mapping {
+        Mapping::Uv => linear::Vec3 {x: inter.uv.0, y: inter.uv.1, z: 0.0},
+        Mapping::Position => inter.pos.copy(),
+    }
+}
+
+impl Texture for Checker {
+    fn value(&self, inter: &scene::Intersection) -> linear::Vec3<f64> {
+        let p = coords(&self.mapping, inter) * self.scale;
+        let parity = (p.x.floor() + p.y.floor() + p.z.floor()) as i64;
+        if parity.rem_euclid(2) == 0 { self.a.copy() } else { self.b.copy() }
+    }
+}
+
+impl Texture for Gradient {
+    fn value(&self, inter: &scene::Intersection) -> linear::Vec3<f64> {
+        let t = match &self.along {
+            Along::U => inter.uv.0,
+            Along::V => inter.uv.1,
+            Along::Direction(dir) => &inter.pos * dir,
+        };
+        let i = self.stops.partition_point(|(at, _)| *at <= t);
+        if i == 0 {
+            return self.stops.first().map_or(scene::BLACK.copy(), |(_, c)| c.copy());
+        }
+        if i == self.stops.len() {
+            return self.stops[i - 1].1.copy();
+        }
+        let ((t0, c0), (t1, c1)) = (&self.stops[i - 1], &self.stops[i]);
+        let f = (t - t0) / (t1 - t0);
+        c0 * (1.0 - f) + c1 * f
+    }
+}
+
+// Hash of a lattice point, uniform over u64.
+fn hash(x: i64, y: i64, z: i64, seed: u64) -> u64 {
+    let mut h = seed ^ (x as u64).wrapping_mul(0x9E3779B97F4A7C15)
+        ^ (y as u64).wrapping_mul(0xC2B2AE3D27D4EB4F)
+        ^ (z as u64).wrapping_mul(0x165667B19E3779F9);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xFF51AFD7ED558CCD);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xC4CEB9FE1A85EC53);
+    h ^ (h >> 33)
+}
+
+fn blend(low: &linear::Vec3<f64>, high: &linear::Vec3<f64>, t: f64) -> linear::Vec3<f64> {
+    let t = t.clamp(0.0, 1.0);
+    low * (1.0 - t) + high * t
+}
+
+impl Perlin {
+    // Improved noise (Perlin 2002) in about [-1, 1].
+    fn noise(&self, p: &linear::Vec3<f64>) -> f64 {
+        let (xi, yi, zi) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (x, y, z) = (p.x - xi, p.y - yi, p.z - zi);
+        let (xi, yi, zi) = (xi as i64, yi as i64, zi as i64);
+        let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let grad = |i: i64, j: i64, k: i64, x: f64, y: f64, z: f64| {
+            // One of the twelve edge directions of a cube.
+            let h = hash(xi + i, yi + j, zi + k, self.seed) % 12;
+            let (a, b) = if h < 4 { (x, y) } else if h < 8 { (x, z) } else { (y, z) };
+            (if h & 1 == 0 { a } else { -a }) + (if h & 2 == 0 { b } else { -b })
+        };
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        let lerp = |t: f64, a: f64, b: f64| a + t * (b - a);
+        lerp(w,
+            lerp(v, lerp(u, grad(0, 0, 0, x, y, z), grad(1, 0, 0, x - 1.0, y, z)),
+                    lerp(u, grad(0, 1, 0, x, y - 1.0, z), grad(1, 1, 0, x - 1.0, y - 1.0, z))),
+            lerp(v, lerp(u, grad(0, 0, 1, x, y, z - 1.0), grad(1, 0, 1, x - 1.0, y, z - 1.0)),
+                    lerp(u, grad(0, 1, 1, x, y - 1.0, z - 1.0), grad(1, 1, 1, x - 1.0, y - 1.0, z - 1.0))))
+    }
+}
+
+impl Texture for Perlin {
+    fn value(&self, inter: &scene::Intersection) -> linear::Vec3<f64> {
+        let mut p = coords(&self.mapping, inter) * self.scale;
+        let (mut sum, mut amplitude, mut total) = (0.0, 1.0, 0.0);
+        for _ in 0..self.octaves.max(1) {
+            sum += amplitude * self.noise(&p);
+            total += amplitude;
+            amplitude *= 0.5;
+            p = p * 2.0;
+        }
+        blend(&self.low, &self.high, 0.5 + 0.5 * sum / total)
+    }
+}
+
+impl Texture for Worley {
+    fn value(&self, inter: &scene::Intersection) -> linear::Vec3<f64> {
+        let p = coords(&self.mapping, inter) * self.scale;
+        let (xi, yi, zi) = (p.x.floor() as i64, p.y.floor() as i64, p.z.floor() as i64);
+        let unit = |h: u64, shift: u32| ((h >> shift) & 0xFFFF) as f64 / 65536.0;
+        let mut nearest = f64::INFINITY;
+        for i in -1..=1 {
+            for j in -1..=1 {
+                for k in -1..=1 {
+                    // One feature point per cell.
+                    let h = hash(xi + i, yi + j, zi + k, self.seed);
+                    let feature = linear::Vec3 {
+                        x: (xi + i) as f64 + unit(h, 0),
+                        y: (yi + j) as f64 + unit(h, 16),
+                        z: (zi + k) as f64 + unit(h, 32),
+                    };
+                    nearest = nearest.min((&feature - &p).norm());
+                }
+            }
+        }
+        // Distances run from zero at a feature point to about a cell.
+        blend(&self.low, &self.high, nearest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: usize, height: usize) -> image::Image {
+        let pixels = (0..width * height).map(|i| linear::Vec3 {x: i as f64, y: 0.0, z: 1.0}).collect();
+        image::Image {width, height, pixels}
+    }
+
+    #[test]
+    fn empty_images_are_rejected() {
+        assert!(ImageTexture::new(image(0, 0), Wrap::Repeat, Filter::Mipmap).is_err());
+        assert!(ImageTexture::new(image(4, 0), Wrap::Repeat, Filter::Mipmap).is_err());
+        assert!(ImageTexture::new(image(0, 4), Wrap::Repeat, Filter::Mipmap).is_err());
+    }
+
+    #[test]
+    fn mip_chain_ends_at_one_texel() {
+        let tex = ImageTexture::new(image(5, 3), Wrap::Clamp, Filter::Mipmap).unwrap();
+        let sizes: Vec<(usize, usize)> = tex.levels.iter().map(|l| (l.width, l.height)).collect();
+        assert_eq!(sizes, vec![(5, 3), (3, 2), (2, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn average_is_the_top_mip_level() {
+        let tex = ImageTexture::new(image(4, 2), Wrap::Repeat, Filter::Nearest).unwrap();
+        assert_eq!(tex.average().x, 3.5);
+        let flat = Checker {a: scene::WHITE.copy(), b: scene::BLACK.copy(), scale: 2.0, mapping: Mapping::Uv};
+        assert!((flat.average().x - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn texels_outside_fold_back_by_the_wrap() {
+        // Texel x of the first row of a 4 by 2 image, or -1 for black.
+        let row = |wrap| {
+            let tex = ImageTexture::new(image(4, 2), wrap, Filter::Nearest).unwrap();
+            (-5..9).map(|x| if tex.texel(0, x, 0).z == 0.0 { -1.0 } else { tex.texel(0, x, 0).x }).collect::<Vec<f64>>()
+        };
+        assert_eq!(row(Wrap::Repeat), vec![3.0, 0.0, 1.0, 2.0, 3.0, 0.0, 1.0, 2.0, 3.0, 0.0, 1.0, 2.0, 3.0, 0.0]);
+        assert_eq!(row(Wrap::Mirror), vec![3.0, 3.0, 2.0, 1.0, 0.0, 0.0, 1.0, 2.0, 3.0, 3.0, 2.0, 1.0, 0.0, 0.0]);
+        assert_eq!(row(Wrap::Clamp), vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 3.0, 3.0, 3.0, 3.0, 3.0]);
+        assert_eq!(row(Wrap::Border), vec![-1.0, -1.0, -1.0, -1.0, -1.0, 0.0, 1.0, 2.0, 3.0, -1.0, -1.0, -1.0, -1.0, -1.0]);
+    }
+
+    #[test]
+    fn nearest_picks_and_bilinear_blends() {
+        let mut inter = scene::Intersection {t: 1.0, pos: linear::Vec3::new(), norm: linear::Z, geom: linear::Z, mat: &material::BLANK,
+                                             uv: (0.0, 0.0), footprint: 0.0, tangent: linear::X, bitangent: linear::Y};
+        let nearest = ImageTexture::new(image(4, 2), Wrap::Clamp, Filter::Nearest).unwrap();
+        let bilinear = ImageTexture::new(image(4, 2), Wrap::Clamp, Filter::Bilinear).unwrap();
+        // Halfway between the centers of the second and third texels of the
+        // top row, v running up from the bottom.
+        inter.uv = (0.5, 0.75);
+        assert_eq!(nearest.value(&inter).x, 2.0);
+        assert!((bilinear.value(&inter).x - 1.5).abs() < 1e-12);
+        // On a texel's center both agree.
+        inter.uv = (0.125, 0.25);
+        assert_eq!(nearest.value(&inter).x, 4.0);
+        assert!((bilinear.value(&inter).x - 4.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn wraps_and_filters_parse_by_name() {
+        assert!(matches!(Wrap::parse("mirror"), Some(Wrap::Mirror)));
+        assert!(matches!(Filter::parse("nearest"), Some(Filter::Nearest)));
+        assert!(Wrap::parse("tile").is_none() && Filter::parse("trilinear").is_none());
+    }
+}